@@ -12,7 +12,7 @@ use stm32h7xx_hal::gpio::Speed;
 use stm32h7xx_hal::hal::digital::v2::ToggleableOutputPin;
 use stm32h7xx_hal::{pac, prelude::*};
 
-use stm32h7_sdmmc::SdmmcExt;
+use stm32h7_sdmmc::{CardDetectPolarity, SdmmcExt, SlotState};
 
 use cortex_m_log::println;
 use cortex_m_log::{
@@ -85,28 +85,32 @@ fn main() -> ! {
         .internal_pull_up(true)
         .set_speed(Speed::VeryHigh);
 
-    // Card detect pin
-    let _cd = gpioi.pi8.into_pull_up_input();
+    // Card detect pin. Low when a card is present
+    let cd = gpioi.pi8.into_pull_up_input();
 
     // Create SDMMC
-    let mut sdmmc = dp.SDMMC1.sdmmc(
-        (clk, cmd, d0, d1, d2, d3),
-        ccdr.peripheral.SDMMC1,
-        &ccdr.clocks,
-    );
+    let mut sdmmc = dp
+        .SDMMC1
+        .sdmmc(
+            (clk, cmd, d0, d1, d2, d3),
+            ccdr.peripheral.SDMMC1,
+            &ccdr.clocks,
+        )
+        .with_card_detect(cd, CardDetectPolarity::ActiveLow, 10);
 
     // Loop until we have a card
     loop {
-        match sdmmc.init_card(50.mhz()) {
-            Ok(_) => break,
-            Err(err) => {
-                println!(log, "Init err: {:?}", err);
+        match sdmmc.poll_card_detect() {
+            SlotState::Initialized => break,
+            SlotState::Inserted => {
+                if let Err(err) = sdmmc.init_card(50.mhz()) {
+                    println!(log, "Init err: {:?}", err);
+                }
             }
+            _ => println!(log, "Waiting for card..."),
         }
 
-        println!(log, "Waiting for card...");
-
-        delay.delay_ms(1000u32);
+        delay.delay_ms(100u32);
         led.toggle().ok();
     }
 
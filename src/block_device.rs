@@ -0,0 +1,308 @@
+//! [`BlockDevice`] implementation for
+//! [embedded-sdmmc](https://crates.io/crates/embedded-sdmmc)
+//!
+//! [`BlockDevice`]: https://docs.rs/embedded-sdmmc/0.3.0/embedded_sdmmc/trait.BlockDevice.html
+
+use core::cell::{RefCell, RefMut};
+use core::convert::TryFrom;
+use core::fmt;
+
+use embedded_sdmmc::{Block, BlockCount, BlockDevice, BlockIdx};
+
+use crate::registers::SdmmcRegisters;
+use crate::sdmmc::{CardDetect, Error, NoCardDetect, Sdmmc};
+#[cfg(feature = "std")]
+use crate::virtual_card::VirtualCard;
+#[cfg(feature = "std")]
+use std::io::{Read, Seek, Write};
+
+/// Blocks moved by each multiple block transfer of a
+/// [`SdmmcBlockDevice`]
+const RUN_BLOCKS: usize = 8;
+
+/// An SDMMC peripheral used as an embedded-sdmmc `BlockDevice`.
+///
+/// The `BlockDevice` methods take `&self`, so the `Sdmmc` is kept in a
+/// `RefCell`. The card must already be initialised.
+///
+/// Runs of blocks are copied through a buffer of 8 blocks, and
+/// transferred with a single multiple block read or write.
+pub struct SdmmcBlockDevice<SDMMC, CD = NoCardDetect> {
+    sdmmc: RefCell<Sdmmc<SDMMC, CD>>,
+    /// Buffer for multiple block transfers
+    buffer: RefCell<[u8; RUN_BLOCKS * 512]>,
+}
+
+impl<SDMMC, CD> SdmmcBlockDevice<SDMMC, CD> {
+    /// Create a new block device from an SDMMC peripheral
+    pub fn new(sdmmc: Sdmmc<SDMMC, CD>) -> Self {
+        SdmmcBlockDevice {
+            sdmmc: RefCell::new(sdmmc),
+            buffer: RefCell::new([0; RUN_BLOCKS * 512]),
+        }
+    }
+
+    /// Borrow the SDMMC peripheral
+    ///
+    /// # Panics
+    ///
+    /// Panics if the peripheral is already borrowed
+    pub fn sdmmc(&self) -> RefMut<'_, Sdmmc<SDMMC, CD>> {
+        self.sdmmc.borrow_mut()
+    }
+
+    /// Releases the SDMMC peripheral
+    pub fn free(self) -> Sdmmc<SDMMC, CD> {
+        self.sdmmc.into_inner()
+    }
+}
+
+impl<SDMMC, CD> fmt::Debug for SdmmcBlockDevice<SDMMC, CD> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SDMMC Block Device")
+            .field("SDMMC", &self.sdmmc)
+            .finish()
+    }
+}
+
+impl<S: SdmmcRegisters, CD: CardDetect> BlockDevice
+    for SdmmcBlockDevice<S, CD>
+{
+    type Error = Error;
+
+    fn read(
+        &self,
+        blocks: &mut [Block],
+        start_block_idx: BlockIdx,
+        _reason: &str,
+    ) -> Result<(), Self::Error> {
+        let mut sdmmc = self.sdmmc.borrow_mut();
+        let mut buffer = self.buffer.borrow_mut();
+        for (i, run) in blocks.chunks_mut(RUN_BLOCKS).enumerate() {
+            let address = start_block_idx.0 + (i * RUN_BLOCKS) as u32;
+            if let [block] = run {
+                sdmmc.read_block(address, &mut block.contents)?;
+                continue;
+            }
+
+            let buffer = &mut buffer[..run.len() * 512];
+            sdmmc.read_blocks(address, buffer)?;
+            for (block, data) in run.iter_mut().zip(buffer.chunks(512)) {
+                block.contents.copy_from_slice(data);
+            }
+        }
+        Ok(())
+    }
+
+    fn write(
+        &self,
+        blocks: &[Block],
+        start_block_idx: BlockIdx,
+    ) -> Result<(), Self::Error> {
+        let mut sdmmc = self.sdmmc.borrow_mut();
+        let mut buffer = self.buffer.borrow_mut();
+        for (i, run) in blocks.chunks(RUN_BLOCKS).enumerate() {
+            let address = start_block_idx.0 + (i * RUN_BLOCKS) as u32;
+            if let [block] = run {
+                sdmmc.write_block(address, &block.contents)?;
+                continue;
+            }
+
+            let buffer = &mut buffer[..run.len() * 512];
+            for (block, data) in run.iter().zip(buffer.chunks_mut(512)) {
+                data.copy_from_slice(&block.contents);
+            }
+            sdmmc.write_blocks(address, buffer)?;
+        }
+        Ok(())
+    }
+
+    fn num_blocks(&self) -> Result<BlockCount, Self::Error> {
+        let sdmmc = self.sdmmc.borrow();
+        let size = sdmmc.card()?.size();
+
+        // Only 2^32 blocks can be addressed
+        let blocks = u32::try_from(size / 512).unwrap_or(core::u32::MAX);
+        Ok(BlockCount(blocks))
+    }
+}
+
+/// A [`VirtualCard`] used as an embedded-sdmmc `BlockDevice`, so that code
+/// built on [`SdmmcBlockDevice`] can be tested on the host. Only available
+/// with the `std` feature.
+///
+/// The `BlockDevice` methods take `&self`, so the `VirtualCard` is kept in
+/// a `RefCell`.
+#[cfg(feature = "std")]
+pub struct VirtualBlockDevice<B> {
+    card: RefCell<VirtualCard<B>>,
+}
+
+#[cfg(feature = "std")]
+impl<B> VirtualBlockDevice<B> {
+    /// Create a new block device from a virtual card
+    pub fn new(card: VirtualCard<B>) -> Self {
+        VirtualBlockDevice {
+            card: RefCell::new(card),
+        }
+    }
+
+    /// Borrow the virtual card
+    ///
+    /// # Panics
+    ///
+    /// Panics if the card is already borrowed
+    pub fn card(&self) -> RefMut<'_, VirtualCard<B>> {
+        self.card.borrow_mut()
+    }
+
+    /// Releases the virtual card
+    pub fn free(self) -> VirtualCard<B> {
+        self.card.into_inner()
+    }
+}
+
+#[cfg(feature = "std")]
+impl<B: fmt::Debug> fmt::Debug for VirtualBlockDevice<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Virtual Block Device")
+            .field("Card", &self.card)
+            .finish()
+    }
+}
+
+#[cfg(feature = "std")]
+impl<B: Read + Write + Seek> BlockDevice for VirtualBlockDevice<B> {
+    type Error = Error;
+
+    fn read(
+        &self,
+        blocks: &mut [Block],
+        start_block_idx: BlockIdx,
+        _reason: &str,
+    ) -> Result<(), Self::Error> {
+        let mut card = self.card.borrow_mut();
+        for (i, block) in blocks.iter_mut().enumerate() {
+            card.read_block(start_block_idx.0 + i as u32, &mut block.contents)?;
+        }
+        Ok(())
+    }
+
+    fn write(
+        &self,
+        blocks: &[Block],
+        start_block_idx: BlockIdx,
+    ) -> Result<(), Self::Error> {
+        let mut card = self.card.borrow_mut();
+        for (i, block) in blocks.iter().enumerate() {
+            card.write_block(start_block_idx.0 + i as u32, &block.contents)?;
+        }
+        Ok(())
+    }
+
+    fn num_blocks(&self) -> Result<BlockCount, Self::Error> {
+        let card = self.card.borrow();
+        let size = card.card()?.size();
+
+        // Only 2^32 blocks can be addressed
+        let blocks = u32::try_from(size / 512).unwrap_or(core::u32::MAX);
+        Ok(BlockCount(blocks))
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::sdmmc::{ErrorKind, Hertz, Phase};
+    use crate::sim::SimCard;
+    use crate::virtual_card::Fault;
+    use std::vec::Vec;
+
+    const SIZE: usize = 2 * 1024 * 1024;
+
+    /// Write two blocks and read them back through `device`
+    fn read_write<D: BlockDevice>(device: &D)
+    where
+        D::Error: fmt::Debug,
+    {
+        let count = device.num_blocks().unwrap();
+        assert_eq!(count.0, (SIZE / 512) as u32);
+
+        let mut blocks = [Block::new(), Block::new()];
+        blocks[0].contents = [0x12; 512];
+        blocks[1].contents = [0x34; 512];
+        device.write(&blocks, BlockIdx(9)).unwrap();
+
+        let mut read = [Block::new(), Block::new(), Block::new()];
+        device.read(&mut read, BlockIdx(8), "test").unwrap();
+        assert!(read[0].contents.iter().all(|&b| b == 0));
+        assert!(read[1].contents.iter().all(|&b| b == 0x12));
+        assert!(read[2].contents.iter().all(|&b| b == 0x34));
+    }
+
+    #[test]
+    fn sdmmc_block_device() {
+        let card = SimCard::new(SIZE);
+        let mut sdmmc = card.sdmmc();
+        sdmmc.init_card(Hertz(25_000_000)).unwrap();
+
+        read_write(&SdmmcBlockDevice::new(sdmmc));
+    }
+
+    #[test]
+    fn sdmmc_block_device_runs() {
+        let card = SimCard::new(SIZE);
+        let mut sdmmc = card.sdmmc();
+        sdmmc.init_card(Hertz(25_000_000)).unwrap();
+        let device = SdmmcBlockDevice::new(sdmmc);
+
+        // Runs of up to 8 blocks are moved by each transfer
+        let mut blocks: Vec<Block> = (0..9).map(|_| Block::new()).collect();
+        for (i, block) in blocks.iter_mut().enumerate() {
+            block.contents = [i as u8; 512];
+        }
+        let _ = card.commands();
+        device.write(&blocks, BlockIdx(4)).unwrap();
+        let mut read: Vec<Block> = (0..9).map(|_| Block::new()).collect();
+        device.read(&mut read, BlockIdx(4), "test").unwrap();
+
+        let data_commands: Vec<(u32, u32)> = card
+            .commands()
+            .into_iter()
+            .filter(|&(index, _)| match index {
+                17 | 18 | 24 | 25 => true,
+                _ => false,
+            })
+            .collect();
+        assert_eq!(data_commands, vec![(25, 4), (24, 12), (18, 4), (17, 12)]);
+        for (i, block) in read.iter().enumerate() {
+            assert!(block.contents.iter().all(|&b| b == i as u8));
+        }
+    }
+
+    #[test]
+    fn virtual_block_device() {
+        let card = VirtualCard::from_vec(vec![0; SIZE]).unwrap();
+
+        read_write(&VirtualBlockDevice::new(card));
+    }
+
+    #[test]
+    fn virtual_block_device_removal() {
+        let device = VirtualBlockDevice::new(
+            VirtualCard::from_vec(vec![0; SIZE]).unwrap(),
+        );
+        device.card().inject_fault(3, Fault::Removal);
+
+        let mut blocks = [Block::new(), Block::new()];
+        let err = device.read(&mut blocks, BlockIdx(2), "test").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NoCard);
+        assert_eq!(err.cmd(), Some(17));
+        assert_eq!(err.phase(), Some(Phase::Data));
+        assert_eq!(err.address(), Some(3));
+        match device.num_blocks().map_err(|e| e.kind()) {
+            Err(ErrorKind::NoCard) => (),
+            r => panic!("{:?}", r),
+        }
+    }
+}
@@ -28,11 +28,49 @@
 #[macro_use(trace)]
 extern crate log;
 
+#[cfg(any(test, feature = "std"))]
+#[macro_use]
+extern crate std;
+
 #[macro_use]
 mod macros;
 
 mod sd_registers;
-pub use sd_registers::{SDStatus, CID, CSD, OCR, SCR};
+pub use sd_registers::{
+    CardState, CardVersion, ExtCSD, FunctionGroup, LifeTimeEstimate,
+    PartitionAccess, PreEolInfo, R1Status, SDStatus, SwitchStatus, CID, CSD,
+    OCR, SCR,
+};
+
+mod registers;
+pub use registers::{Register, SdmmcRegisters};
 
 mod sdmmc;
-pub use sdmmc::{BusWidth, Card, CardType, Error, Sdmmc, SdmmcExt, Signalling};
+#[cfg(target_arch = "arm")]
+pub use sdmmc::SdmmcExt;
+pub use sdmmc::{
+    BusWidth, Card, CardDetect, CardDetectPolarity, CardType, CurrentLimit,
+    DriverStrength, EraseMode, Error, ErrorKind, NoCardDetect, Phase,
+    RetryPolicy, RetryStats, SampleDelay, Sdmmc, Signalling, SignallingVoltage,
+    SlotState, Transfer,
+};
+
+#[cfg(test)]
+mod sim;
+
+#[cfg(feature = "std")]
+mod virtual_card;
+#[cfg(feature = "std")]
+pub use virtual_card::{Fault, VirtualCard};
+
+#[cfg(feature = "embedded-sdmmc")]
+mod block_device;
+#[cfg(feature = "embedded-sdmmc")]
+pub use block_device::SdmmcBlockDevice;
+#[cfg(all(feature = "embedded-sdmmc", feature = "std"))]
+pub use block_device::VirtualBlockDevice;
+
+#[cfg(feature = "embedded-storage")]
+mod storage;
+#[cfg(feature = "embedded-storage")]
+pub use storage::SdmmcStorage;
@@ -0,0 +1,185 @@
+//! Access to the registers of a SDMMC peripheral
+//!
+//! The driver accesses the peripheral only through the [`SdmmcRegisters`]
+//! trait, so that it can run against something other than the hardware,
+//! for example a simulated card on the host.
+//!
+//! See RM0433 Rev 7 Section 55.10 for the register map.
+
+/// A register of a SDMMC peripheral, or of the DLYB delay block attached
+/// to it
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[allow(missing_docs)]
+pub enum Register {
+    Power,
+    Clkcr,
+    Argr,
+    Cmdr,
+    Respcmdr,
+    Resp1r,
+    Resp2r,
+    Resp3r,
+    Resp4r,
+    Dtimer,
+    Dlenr,
+    Dctrl,
+    Dcntr,
+    Star,
+    Icr,
+    Maskr,
+    Idmactrlr,
+    Idmabsizer,
+    Idmabase0r,
+    Idmabase1r,
+    Fifor,
+    /// DLYB control register
+    DlybCr,
+    /// DLYB configuration register
+    DlybCfgr,
+}
+impl Register {
+    /// Offset of this register from the base address of its peripheral
+    pub fn offset(self) -> usize {
+        match self {
+            Register::Power => 0x00,
+            Register::Clkcr => 0x04,
+            Register::Argr => 0x08,
+            Register::Cmdr => 0x0C,
+            Register::Respcmdr => 0x10,
+            Register::Resp1r => 0x14,
+            Register::Resp2r => 0x18,
+            Register::Resp3r => 0x1C,
+            Register::Resp4r => 0x20,
+            Register::Dtimer => 0x24,
+            Register::Dlenr => 0x28,
+            Register::Dctrl => 0x2C,
+            Register::Dcntr => 0x30,
+            Register::Star => 0x34,
+            Register::Icr => 0x38,
+            Register::Maskr => 0x3C,
+            Register::Idmactrlr => 0x50,
+            Register::Idmabsizer => 0x54,
+            Register::Idmabase0r => 0x58,
+            Register::Idmabase1r => 0x5C,
+            Register::Fifor => 0x80,
+            Register::DlybCr => 0x00,
+            Register::DlybCfgr => 0x04,
+        }
+    }
+}
+
+/// Word access to the registers of a SDMMC peripheral.
+///
+/// Implemented for the `SDMMC1` and `SDMMC2` peripherals, where
+/// [`Register::DlybCr`] and [`Register::DlybCfgr`] access the delay block
+/// `DELAY_BLOCK_SDMMC1` or `DELAY_BLOCK_SDMMC2`.
+pub trait SdmmcRegisters {
+    /// Memory that can be accessed by the internal DMA (IDMA), as
+    /// inclusive address ranges
+    const IDMA_REGIONS: &'static [(u32, u32)];
+
+    /// Read a register
+    fn read(&self, reg: Register) -> u32;
+
+    /// Write a register
+    fn write(&self, reg: Register, value: u32);
+
+    /// Read a register, and write back the value returned by `f`
+    fn modify<F>(&self, reg: Register, f: F)
+    where
+        F: FnOnce(u32) -> u32,
+    {
+        self.write(reg, f(self.read(reg)));
+    }
+}
+
+/// SDMMC_POWER fields
+pub(crate) mod power {
+    pub const PWRCTRL: u32 = 0b11;
+    pub const VSWITCH: u32 = 1 << 2;
+    pub const VSWITCHEN: u32 = 1 << 3;
+}
+
+/// SDMMC_CLKCR fields
+pub(crate) mod clkcr {
+    pub const CLKDIV: u32 = 0x3FF;
+    pub const WIDBUS_SHIFT: u32 = 14;
+    pub const WIDBUS: u32 = 0b11 << WIDBUS_SHIFT;
+    pub const HWFC_EN: u32 = 1 << 17;
+    pub const DDR: u32 = 1 << 18;
+    pub const BUSSPEED: u32 = 1 << 19;
+    pub const SELCLKRX_SHIFT: u32 = 20;
+    pub const SELCLKRX: u32 = 0b11 << SELCLKRX_SHIFT;
+}
+
+/// SDMMC_CMDR fields
+pub(crate) mod cmdr {
+    pub const CMDSTOP: u32 = 1 << 7;
+    pub const WAITRESP_SHIFT: u32 = 8;
+    pub const CPSMEN: u32 = 1 << 12;
+}
+
+/// SDMMC_DCTRL fields
+pub(crate) mod dctrl {
+    pub const DTEN: u32 = 1 << 0;
+    pub const DTDIR: u32 = 1 << 1;
+    pub const DBLOCKSIZE_SHIFT: u32 = 4;
+    pub const FIFORST: u32 = 1 << 13;
+}
+
+/// SDMMC_STAR flags. SDMMC_ICR and SDMMC_MASKR use the same bit positions
+/// for the flags they clear or enable
+pub(crate) mod star {
+    pub const CCRCFAIL: u32 = 1 << 0;
+    pub const DCRCFAIL: u32 = 1 << 1;
+    pub const CTIMEOUT: u32 = 1 << 2;
+    pub const DTIMEOUT: u32 = 1 << 3;
+    pub const TXUNDERR: u32 = 1 << 4;
+    pub const RXOVERR: u32 = 1 << 5;
+    pub const CMDREND: u32 = 1 << 6;
+    pub const CMDSENT: u32 = 1 << 7;
+    pub const DATAEND: u32 = 1 << 8;
+    pub const DHOLD: u32 = 1 << 9;
+    pub const DBCKEND: u32 = 1 << 10;
+    pub const DABORT: u32 = 1 << 11;
+    pub const DPSMACT: u32 = 1 << 12;
+    pub const CPSMACT: u32 = 1 << 13;
+    pub const TXFIFOHE: u32 = 1 << 14;
+    pub const RXFIFOHF: u32 = 1 << 15;
+    pub const RXFIFOE: u32 = 1 << 19;
+    pub const BUSYD0: u32 = 1 << 20;
+    pub const BUSYD0END: u32 = 1 << 21;
+    pub const SDIOIT: u32 = 1 << 22;
+    pub const VSWEND: u32 = 1 << 25;
+    pub const CKSTOP: u32 = 1 << 26;
+    pub const IDMATE: u32 = 1 << 27;
+    pub const IDMABTC: u32 = 1 << 28;
+
+    /// Data path errors
+    pub const DATA_ERRORS: u32 = DCRCFAIL | DTIMEOUT | TXUNDERR | RXOVERR;
+}
+
+/// SDMMC_IDMACTRLR fields
+pub(crate) mod idmactrlr {
+    pub const IDMAEN: u32 = 1 << 0;
+    pub const IDMABMODE: u32 = 1 << 1;
+}
+
+/// SDMMC_IDMABSIZER fields
+pub(crate) mod idmabsizer {
+    pub const IDMABNDT_SHIFT: u32 = 5;
+}
+
+/// DLYB_CR fields
+pub(crate) mod dlyb_cr {
+    pub const DEN: u32 = 1 << 0;
+    pub const SEN: u32 = 1 << 1;
+}
+
+/// DLYB_CFGR fields
+pub(crate) mod dlyb_cfgr {
+    pub const UNIT_SHIFT: u32 = 8;
+    pub const LNG_SHIFT: u32 = 16;
+    pub const LNG: u32 = 0xFFF << LNG_SHIFT;
+    pub const LNGF: u32 = 1 << 31;
+}
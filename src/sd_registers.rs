@@ -3,8 +3,10 @@
 use core::fmt;
 use core::str;
 
+/// Physical Layer Specification version of a card
 #[non_exhaustive]
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[allow(missing_docs)]
 pub enum CardVersion {
     V1_0,
     V1_1,
@@ -16,6 +18,11 @@ pub enum CardVersion {
     V7,
     Unknown,
 }
+impl Default for CardVersion {
+    fn default() -> Self {
+        CardVersion::Unknown
+    }
+}
 
 #[derive(Debug, Copy, Clone)]
 pub enum BlockSize {
@@ -145,6 +152,188 @@ impl fmt::Debug for OCR {
             .finish()
     }
 }
+/// State of the card, as reported in its Card Status
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CardState {
+    /// Card is in idle state
+    Idle = 0,
+    /// Card state is ready
+    Ready = 1,
+    /// Card is in identification state
+    Identification = 2,
+    /// Card is in standby state
+    Standby = 3,
+    /// Card is in transfer state
+    Transfer = 4,
+    /// Card is sending an operation
+    Sending = 5,
+    /// Card is receiving operation information
+    Receiving = 6,
+    /// Card is in programming state
+    Programming = 7,
+    /// Card is disconnected
+    Disconnected = 8,
+    /// Reserved
+    Reserved,
+}
+impl From<u8> for CardState {
+    fn from(n: u8) -> Self {
+        match n {
+            0 => Self::Idle,
+            1 => Self::Ready,
+            2 => Self::Identification,
+            3 => Self::Standby,
+            4 => Self::Transfer,
+            5 => Self::Sending,
+            6 => Self::Receiving,
+            7 => Self::Programming,
+            8 => Self::Disconnected,
+            _ => Self::Reserved,
+        }
+    }
+}
+/// Card Status, returned in the R1 response. Ref PLSS_v7_10 Table 4-42
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub struct R1Status(pub u32);
+impl R1Status {
+    /// Error bits. COM_CRC_ERROR and ILLEGAL_COMMAND are not included:
+    /// the card does not respond to such a command, they are reported
+    /// in the response to the next one
+    pub const ERRORS: u32 = 0xFD39_8008;
+    /// OUT_OF_RANGE bit
+    pub const OUT_OF_RANGE: u32 = 1 << 31;
+
+    /// The command's argument was out of the allowed range for this card
+    pub fn out_of_range(&self) -> bool {
+        self.0 & Self::OUT_OF_RANGE != 0
+    }
+    /// A misaligned address which did not match the block length
+    pub fn address_error(&self) -> bool {
+        self.0 & (1 << 30) != 0
+    }
+    /// The transferred block length is not allowed for this card
+    pub fn block_len_error(&self) -> bool {
+        self.0 & (1 << 29) != 0
+    }
+    /// An error in the sequence of erase commands occurred
+    pub fn erase_seq_error(&self) -> bool {
+        self.0 & (1 << 28) != 0
+    }
+    /// An invalid selection of write-blocks for erase occurred
+    pub fn erase_param(&self) -> bool {
+        self.0 & (1 << 27) != 0
+    }
+    /// Attempt to program a write protected block
+    pub fn wp_violation(&self) -> bool {
+        self.0 & (1 << 26) != 0
+    }
+    /// The card is locked by the host
+    pub fn card_is_locked(&self) -> bool {
+        self.0 & (1 << 25) != 0
+    }
+    /// A sequence or password error in the lock/unlock card command
+    pub fn lock_unlock_failed(&self) -> bool {
+        self.0 & (1 << 24) != 0
+    }
+    /// The CRC check of the previous command failed
+    pub fn com_crc_error(&self) -> bool {
+        self.0 & (1 << 23) != 0
+    }
+    /// The previous command was not legal for the card state
+    pub fn illegal_command(&self) -> bool {
+        self.0 & (1 << 22) != 0
+    }
+    /// Card internal ECC was applied but failed to correct the data
+    pub fn card_ecc_failed(&self) -> bool {
+        self.0 & (1 << 21) != 0
+    }
+    /// Internal card controller error
+    pub fn cc_error(&self) -> bool {
+        self.0 & (1 << 20) != 0
+    }
+    /// A general or an unknown error occurred during the operation
+    pub fn error(&self) -> bool {
+        self.0 & (1 << 19) != 0
+    }
+    /// The read only section of the CSD does not match the card
+    /// content, or an attempt to reverse the copy or permanent write
+    /// protect bits was made
+    pub fn csd_overwrite(&self) -> bool {
+        self.0 & (1 << 16) != 0
+    }
+    /// Only partial address space was erased due to existing write
+    /// protected blocks
+    pub fn wp_erase_skip(&self) -> bool {
+        self.0 & (1 << 15) != 0
+    }
+    /// The command has been executed without using the internal ECC
+    pub fn card_ecc_disabled(&self) -> bool {
+        self.0 & (1 << 14) != 0
+    }
+    /// An erase sequence was cleared before executing because an out
+    /// of erase sequence command was received
+    pub fn erase_reset(&self) -> bool {
+        self.0 & (1 << 13) != 0
+    }
+    /// The state of the card when receiving the command
+    pub fn current_state(&self) -> CardState {
+        CardState::from((self.0 >> 9) as u8 & 0xF)
+    }
+    /// Corresponds to buffer empty signaling on the bus
+    pub fn ready_for_data(&self) -> bool {
+        self.0 & (1 << 8) != 0
+    }
+    /// The device did not switch to the expected mode (MMC)
+    pub fn switch_error(&self) -> bool {
+        self.0 & (1 << 7) != 0
+    }
+    /// Extension functions may set this bit to get the host to deal
+    /// with events
+    pub fn fx_event(&self) -> bool {
+        self.0 & (1 << 6) != 0
+    }
+    /// The card will expect ACMD, or the command has been interpreted as
+    /// ACMD
+    pub fn app_cmd(&self) -> bool {
+        self.0 & (1 << 5) != 0
+    }
+    /// Error in the sequence of the authentication process
+    pub fn ake_seq_error(&self) -> bool {
+        self.0 & (1 << 3) != 0
+    }
+    /// Any of the error bits is set
+    pub fn is_error(&self) -> bool {
+        self.0 & Self::ERRORS != 0
+    }
+}
+impl fmt::Debug for R1Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("R1: Card Status")
+            .field("OUT_OF_RANGE", &self.out_of_range())
+            .field("ADDRESS_ERROR", &self.address_error())
+            .field("BLOCK_LEN_ERROR", &self.block_len_error())
+            .field("ERASE_SEQ_ERROR", &self.erase_seq_error())
+            .field("ERASE_PARAM", &self.erase_param())
+            .field("WP_VIOLATION", &self.wp_violation())
+            .field("CARD_IS_LOCKED", &self.card_is_locked())
+            .field("LOCK_UNLOCK_FAILED", &self.lock_unlock_failed())
+            .field("COM_CRC_ERROR", &self.com_crc_error())
+            .field("ILLEGAL_COMMAND", &self.illegal_command())
+            .field("CARD_ECC_FAILED", &self.card_ecc_failed())
+            .field("CC_ERROR", &self.cc_error())
+            .field("ERROR", &self.error())
+            .field("CSD_OVERWRITE", &self.csd_overwrite())
+            .field("WP_ERASE_SKIP", &self.wp_erase_skip())
+            .field("CARD_ECC_DISABLED", &self.card_ecc_disabled())
+            .field("ERASE_RESET", &self.erase_reset())
+            .field("CURRENT_STATE", &self.current_state())
+            .field("READY_FOR_DATA", &self.ready_for_data())
+            .field("FX_EVENT", &self.fx_event())
+            .field("APP_CMD", &self.app_cmd())
+            .field("AKE_SEQ_ERROR", &self.ake_seq_error())
+            .finish()
+    }
+}
 /// Card Identification Register (CID)
 #[derive(Clone, Copy, Default)]
 pub struct CID {
@@ -215,34 +404,42 @@ impl CSD {
     /// always equal to READ_BL_LEN
     pub fn block_length(&self) -> Option<BlockSize> {
         // Read block length
-        match self.0 >> 80 {
+        match (self.0 >> 80) & 0xF {
             9 => Some(BlockSize::B512),
             10 => Some(BlockSize::B1024),
             11 => Some(BlockSize::B2048),
             _ => None,
         }
     }
-    /// Number of blocks in the card
-    pub fn block_count(&self) -> u32 {
+    /// Number of 512-byte blocks in the card
+    pub fn block_count(&self) -> u64 {
         match self.csd_version() {
             0 => {
                 // SDSC
-                let c_size: u16 = ((self.0 >> 62) as u16) & 0xFFF;
-                let c_size_mult: u8 = ((self.0 >> 47) as u8) & 7;
-
-                ((c_size + 1) as u32) * ((1 << (c_size_mult + 2)) as u32)
+                self.c_size_block_count()
             }
             1 => {
-                // SDHC / SDXC
-                ((self.0 >> 48) as u32 & 0x3F_FFFF) + 1
+                // SDHC / SDXC: Capacity = (C_SIZE + 1) * 512KByte
+                (((self.0 >> 48) as u64 & 0x3F_FFFF) + 1) * 1024
             }
             2 => {
-                // SDUC
-                ((self.0 >> 48) as u32 & 0xFFF_FFFF) + 1
+                // SDUC: Capacity = (C_SIZE + 1) * 512KByte
+                (((self.0 >> 48) as u64 & 0xFFF_FFFF) + 1) * 1024
             }
             _ => 0,
         }
     }
+    /// Capacity = (C_SIZE+1) * 2^(C_SIZE_MULT+2) * 2^READ_BL_LEN, in
+    /// 512-byte blocks. Used by SDSC cards and MMC devices <= 2GB. See PLSS
+    /// v7_10 Section 5.3.2
+    pub(crate) fn c_size_block_count(&self) -> u64 {
+        let c_size: u16 = ((self.0 >> 62) as u16) & 0xFFF;
+        let c_size_mult: u8 = ((self.0 >> 47) as u8) & 7;
+        let read_bl_len: u8 = ((self.0 >> 80) as u8) & 0xF;
+
+        let shift = (c_size_mult + 2 + read_bl_len).saturating_sub(9);
+        (u64::from(c_size) + 1) << shift
+    }
     /// Maximum read current at the minimum VDD
     pub fn read_current_minimum_vdd(&self) -> CurrentConsumption {
         CurrentConsumption::from_minimum_reg(self.0 >> 59)
@@ -351,7 +548,19 @@ impl SDStatus {
     }
     /// Allocation Unit (AU) size. Lookup in PLSS v7_10 Table 4-47
     pub fn allocation_unit_size(&self) -> u8 {
-        (self.inner[3] >> 20) as u8 & 0xF
+        (self.inner[2] >> 20) as u8 & 0xF
+    }
+    /// Allocation Unit (AU) size in 512-byte blocks. Zero if not defined
+    pub fn allocation_unit_blocks(&self) -> u32 {
+        match self.allocation_unit_size() {
+            0 => 0,
+            size @ 1..=0xA => 32 << (size - 1), // 16kB to 8MB
+            0xB => 24 * 1024,                   // 12MB
+            0xC => 32 * 1024,                   // 16MB
+            0xD => 48 * 1024,                   // 24MB
+            0xE => 64 * 1024,                   // 32MB
+            _ => 128 * 1024,                    // 64MB
+        }
     }
     /// Indicates N_Erase, in units of AU
     pub fn erase_size(&self) -> u16 {
@@ -362,6 +571,37 @@ impl SDStatus {
     pub fn erase_timeout(&self) -> u8 {
         (self.inner[3] >> 10) as u8 & 0x3F
     }
+    /// Supports Discard
+    pub fn supports_discard(&self) -> bool {
+        (self.inner[6] >> 1) & 1 != 0
+    }
+    /// Supports Full User area Logical Erase (FULE)
+    pub fn supports_fule(&self) -> bool {
+        self.inner[6] & 1 != 0
+    }
+    /// Indicates T_Offset, in seconds
+    pub fn erase_offset(&self) -> u8 {
+        (self.inner[3] >> 8) as u8 & 0x3
+    }
+    /// Timeout for erasing `blocks` 512-byte blocks, in milliseconds. See
+    /// PLSS v7_10 Section 4.10.2.5
+    ///
+    /// Returns None if the card does not support the timeout calculation
+    pub fn erase_timeout_ms(&self, blocks: u32) -> Option<u32> {
+        let au_blocks = self.allocation_unit_blocks();
+        let n_erase = u64::from(self.erase_size());
+        let t_erase = u64::from(self.erase_timeout());
+        if au_blocks == 0 || n_erase == 0 || t_erase == 0 {
+            return None;
+        }
+
+        // Number of AUs to be erased
+        let n_au = u64::from((blocks + au_blocks - 1) / au_blocks);
+        let t_offset = u64::from(self.erase_offset());
+        let ms = (t_erase * 1000 * n_au) / n_erase + t_offset * 1000;
+
+        Some(core::cmp::min(ms, 0xFFFF_FFFF) as u32)
+    }
 }
 impl fmt::Debug for SDStatus {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -372,6 +612,612 @@ impl fmt::Debug for SDStatus {
             .field("AU Size", &self.allocation_unit_size())
             .field("Erase Size (AU)", &self.erase_size())
             .field("Erase Timeout (s)", &self.erase_timeout())
+            .field("Erase Offset (s)", &self.erase_offset())
+            .field("Discard", &self.supports_discard())
+            .field("FULE", &self.supports_fule())
+            .finish()
+    }
+}
+/// Function groups of the Switch Function command (CMD6). See PLSS v7_10
+/// Table 4-11
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FunctionGroup {
+    /// Function Group 1: Access mode (bus speed)
+    AccessMode = 1,
+    /// Function Group 2: Command system
+    CommandSystem = 2,
+    /// Function Group 3: Driver strength
+    DriverStrength = 3,
+    /// Function Group 4: Power limit
+    PowerLimit = 4,
+    /// Function Group 5: Reserved
+    Group5 = 5,
+    /// Function Group 6: Reserved
+    Group6 = 6,
+}
+
+/// Switch Function Status, returned by CMD6. See PLSS v7_10 Table 4-13
+#[derive(Clone, Copy, Default)]
+pub struct SwitchStatus {
+    inner: [u32; 16],
+}
+impl SwitchStatus {
+    /// A new Switch Function Status from a slice (512 bits)
+    pub fn new(inner: [u32; 16]) -> Self {
+        SwitchStatus { inner }
+    }
+    /// Byte `index` of the status, starting from the most significant byte
+    fn byte(&self, index: usize) -> u8 {
+        (self.inner[index / 4] >> (8 * (index % 4))) as u8
+    }
+    /// Big-endian 16-bit field starting at byte `index`
+    fn half_word(&self, index: usize) -> u16 {
+        u16::from_be_bytes([self.byte(index), self.byte(index + 1)])
+    }
+    /// Maximum current consumption of the selected functions, in mA. Zero
+    /// if the selection is in error
+    pub fn max_current_ma(&self) -> u16 {
+        self.half_word(0)
+    }
+    /// Support bits of a function group. Bit `n` is set if function `n` is
+    /// supported
+    pub fn supported(&self, group: FunctionGroup) -> u16 {
+        self.half_word(2 + 2 * (6 - group as usize))
+    }
+    /// Function selected in a function group. In check mode this is the
+    /// function that would be selected. 0xF indicates that the requested
+    /// function cannot be selected
+    pub fn selected(&self, group: FunctionGroup) -> u8 {
+        let group = group as usize;
+        let byte = self.byte(14 + (6 - group) / 2);
+
+        if group % 2 == 0 {
+            byte >> 4
+        } else {
+            byte & 0xF
+        }
+    }
+    /// Data structure version. 0 = busy status bits are not defined, 1 =
+    /// busy status bits are defined
+    pub fn data_structure_version(&self) -> u8 {
+        self.byte(17)
+    }
+    /// Busy status of functions in a function group. Bit `n` is set if
+    /// function `n` is busy. Only valid for data structure version 1
+    pub fn busy(&self, group: FunctionGroup) -> u16 {
+        if self.data_structure_version() == 0 {
+            0
+        } else {
+            self.half_word(18 + 2 * (6 - group as usize))
+        }
+    }
+}
+impl fmt::Debug for SwitchStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let group = |g| (self.supported(g), self.selected(g), self.busy(g));
+
+        f.debug_struct("Switch Function Status")
+            .field("Max Current (mA)", &self.max_current_ma())
+            .field("Data Structure Version", &self.data_structure_version())
+            .field(
+                "Access Mode (Support, Selected, Busy)",
+                &group(FunctionGroup::AccessMode),
+            )
+            .field(
+                "Command System (Support, Selected, Busy)",
+                &group(FunctionGroup::CommandSystem),
+            )
+            .field(
+                "Driver Strength (Support, Selected, Busy)",
+                &group(FunctionGroup::DriverStrength),
+            )
+            .field(
+                "Power Limit (Support, Selected, Busy)",
+                &group(FunctionGroup::PowerLimit),
+            )
+            .field(
+                "Group 5 (Support, Selected, Busy)",
+                &group(FunctionGroup::Group5),
+            )
+            .field(
+                "Group 6 (Support, Selected, Busy)",
+                &group(FunctionGroup::Group6),
+            )
+            .finish()
+    }
+}
+/// Device life time estimate, in steps of 10%. See JESD84-B51 Section
+/// 7.4.41
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LifeTimeEstimate {
+    /// Not defined
+    NotDefined,
+    /// Up to this percentage of the device life time has been used
+    UsedUpTo(u8),
+    /// Maximum estimated device life time exceeded
+    Exceeded,
+}
+impl LifeTimeEstimate {
+    fn from_reg(reg: u8) -> LifeTimeEstimate {
+        match reg {
+            0x01..=0x0A => LifeTimeEstimate::UsedUpTo(reg * 10),
+            0x0B => LifeTimeEstimate::Exceeded,
+            _ => LifeTimeEstimate::NotDefined,
+        }
+    }
+}
+
+/// Pre End of Life information, based on consumed reserved blocks. See
+/// JESD84-B51 Section 7.4.42
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PreEolInfo {
+    /// Not defined
+    NotDefined,
+    /// Normal
+    Normal,
+    /// Warning: 80% of reserved blocks consumed
+    Warning,
+    /// Urgent: 90% of reserved blocks consumed
+    Urgent,
+}
+impl PreEolInfo {
+    fn from_reg(reg: u8) -> PreEolInfo {
+        match reg {
+            1 => PreEolInfo::Normal,
+            2 => PreEolInfo::Warning,
+            3 => PreEolInfo::Urgent,
+            _ => PreEolInfo::NotDefined,
+        }
+    }
+}
+
+/// Partition accessed by read and write commands. See JESD84-B51 Section
+/// 7.4.69
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PartitionAccess {
+    /// User data area
+    User,
+    /// Boot partition 1
+    Boot1,
+    /// Boot partition 2
+    Boot2,
+    /// Replay Protected Memory Block
+    Rpmb,
+    /// General purpose partition 1 - 4
+    GeneralPurpose(u8),
+}
+
+/// Extended CSD (EXT_CSD) of a MMC / eMMC device
+#[derive(Clone, Copy)]
+pub struct ExtCSD {
+    inner: [u32; 128],
+}
+impl Default for ExtCSD {
+    fn default() -> Self {
+        ExtCSD { inner: [0; 128] }
+    }
+}
+impl ExtCSD {
+    /// A new Extended CSD from a slice (512 bytes)
+    pub fn new(inner: [u32; 128]) -> Self {
+        ExtCSD { inner }
+    }
+    /// Byte `index` of the register, as defined in JESD84-B51 Table 135
+    fn byte(&self, index: usize) -> u8 {
+        (self.inner[index / 4] >> (8 * (index % 4))) as u8
+    }
+    /// Extended CSD revision
+    pub fn revision(&self) -> u8 {
+        self.byte(192)
+    }
+    /// Number of 512-byte sectors. Only valid for devices > 2GB
+    pub fn sector_count(&self) -> u32 {
+        self.inner[212 / 4]
+    }
+    /// Supported bus timing modes. See JESD84-B51 Section 7.4.44
+    pub fn device_type(&self) -> u8 {
+        self.byte(196)
+    }
+    /// Supports High Speed SDR at 52MHz
+    pub fn supports_hs52(&self) -> bool {
+        self.device_type() & 0x02 != 0
+    }
+    /// Supports High Speed DDR at 52MHz
+    pub fn supports_ddr52(&self) -> bool {
+        self.device_type() & 0x0C != 0
+    }
+    /// Supports HS200
+    pub fn supports_hs200(&self) -> bool {
+        self.device_type() & 0x30 != 0
+    }
+    /// Supports HS400
+    pub fn supports_hs400(&self) -> bool {
+        self.device_type() & 0xC0 != 0
+    }
+    /// Selected bus timing interface. 0 = Backwards compatible, 1 = High
+    /// Speed, 2 = HS200, 3 = HS400
+    pub fn hs_timing(&self) -> u8 {
+        self.byte(185) & 0xF
+    }
+    /// Bus width mode
+    pub fn bus_width(&self) -> u8 {
+        self.byte(183)
+    }
+    /// Size of each boot partition in bytes
+    pub fn boot_partition_size(&self) -> u32 {
+        u32::from(self.byte(226)) * 128 * 1024
+    }
+    /// Size of the RPMB partition in bytes
+    pub fn rpmb_size(&self) -> u32 {
+        u32::from(self.byte(168)) * 128 * 1024
+    }
+    /// Boot acknowledge sent during the boot operation
+    pub fn boot_ack(&self) -> bool {
+        self.byte(179) & 0x40 != 0
+    }
+    /// Partition enabled for boot. 0 = Not enabled, 1 = Boot partition 1,
+    /// 2 = Boot partition 2, 7 = User area
+    pub fn boot_partition_enable(&self) -> u8 {
+        (self.byte(179) >> 3) & 0x7
+    }
+    /// Partition currently accessed by read and write commands
+    pub fn partition_access(&self) -> PartitionAccess {
+        match self.byte(179) & 0x7 {
+            0 => PartitionAccess::User,
+            1 => PartitionAccess::Boot1,
+            2 => PartitionAccess::Boot2,
+            3 => PartitionAccess::Rpmb,
+            n => PartitionAccess::GeneralPurpose(n - 3),
+        }
+    }
+    /// Size of the volatile cache, in kilobytes. Zero if there is no cache
+    pub fn cache_size_kb(&self) -> u32 {
+        u32::from_le_bytes([
+            self.byte(249),
+            self.byte(250),
+            self.byte(251),
+            self.byte(252),
+        ])
+    }
+    /// Device life time estimation for SLC (Type A) memory
+    pub fn life_time_estimate_a(&self) -> LifeTimeEstimate {
+        LifeTimeEstimate::from_reg(self.byte(268))
+    }
+    /// Device life time estimation for MLC (Type B) memory
+    pub fn life_time_estimate_b(&self) -> LifeTimeEstimate {
+        LifeTimeEstimate::from_reg(self.byte(269))
+    }
+    /// Pre End of Life information
+    pub fn pre_eol_info(&self) -> PreEolInfo {
+        PreEolInfo::from_reg(self.byte(267))
+    }
+    /// Firmware version. Vendor specific
+    pub fn firmware_version(&self) -> [u8; 8] {
+        let mut fw = [0; 8];
+        for (i, b) in fw.iter_mut().enumerate() {
+            *b = self.byte(254 + i);
+        }
+        fw
+    }
+    /// The high-capacity erase group size and timeouts are in use
+    /// (ERASE_GROUP_DEF), instead of those in the CSD
+    pub fn erase_group_def(&self) -> bool {
+        self.byte(175) & 1 != 0
+    }
+    /// Erase timeout for one high-capacity erase group, in milliseconds
+    pub fn erase_timeout_ms(&self) -> u32 {
+        u32::from(self.byte(223)) * 300
+    }
+    /// Size of a high-capacity erase group, in 512-byte blocks
+    pub fn erase_group_blocks(&self) -> u32 {
+        u32::from(self.byte(224)) * 1024
+    }
+    /// Secure Erase timeout, as a multiple of the erase timeout
+    pub fn secure_erase_multiplier(&self) -> u8 {
+        self.byte(230)
+    }
+    /// Supports Secure Erase and Secure Trim
+    pub fn supports_secure_erase(&self) -> bool {
+        self.byte(231) & 1 != 0
+    }
+    /// Supports Trim
+    pub fn supports_trim(&self) -> bool {
+        self.byte(231) & (1 << 4) != 0
+    }
+    /// Supports Sanitize
+    pub fn supports_sanitize(&self) -> bool {
+        self.byte(231) & (1 << 6) != 0
+    }
+    /// Trim timeout for one erase group, in milliseconds
+    pub fn trim_timeout_ms(&self) -> u32 {
+        u32::from(self.byte(232)) * 300
+    }
+    /// Maximum time for a CMD6 SWITCH, in milliseconds. None if it is
+    /// not defined (before eMMC 4.5)
+    pub fn generic_cmd6_time_ms(&self) -> Option<u32> {
+        match self.byte(248) {
+            0 => None,
+            t => Some(u32::from(t) * 10),
+        }
+    }
+    /// Supported command sets. Bit 0 is the standard MMC command set
+    pub fn supported_command_sets(&self) -> u8 {
+        self.byte(504)
+    }
+}
+impl fmt::Debug for ExtCSD {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Extended CSD")
+            .field("Revision", &self.revision())
+            .field("Sector Count", &self.sector_count())
+            .field("Device Type", &self.device_type())
+            .field("HS Timing", &self.hs_timing())
+            .field("Bus Width", &self.bus_width())
+            .field("Boot Partition Size (B)", &self.boot_partition_size())
+            .field("RPMB Size (B)", &self.rpmb_size())
+            .field("Boot ACK", &self.boot_ack())
+            .field("Boot Partition Enable", &self.boot_partition_enable())
+            .field("Partition Access", &self.partition_access())
+            .field("Cache Size (kB)", &self.cache_size_kb())
+            .field("Life Time Estimate A", &self.life_time_estimate_a())
+            .field("Life Time Estimate B", &self.life_time_estimate_b())
+            .field("Pre EOL Info", &self.pre_eol_info())
+            .field("Erase Timeout (ms)", &self.erase_timeout_ms())
+            .field("Erase Group Def", &self.erase_group_def())
+            .field("Erase Group Size (blocks)", &self.erase_group_blocks())
+            .field("Secure Erase Multiplier", &self.secure_erase_multiplier())
+            .field("Secure Erase", &self.supports_secure_erase())
+            .field("Trim", &self.supports_trim())
+            .field("Sanitize", &self.supports_sanitize())
+            .field("Trim Timeout (ms)", &self.trim_timeout_ms())
+            .field("Firmware Version", &self.firmware_version())
+            .field("Supported Command Sets", &self.supported_command_sets())
             .finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// EXT_CSD of an 8GB eMMC 5.1 device, byte 0 first
+    const EXT_CSD_EMMC_5_1: &str = "\
+        0000000000000000000000000000000000000000000000000000000000000000\
+        0000000000000000000000000000000000000000000000000000000000000000\
+        0000000000000000000000000000000000000000000000000000000000000000\
+        0000000000000000000000000000000000000000000000000000000000000000\
+        0000000000000000000000000000000000000000000000000000000000000000\
+        0700000000000000200000000000000000000048000000020001000000000000\
+        08000200570000000000000000000000000000000000e9000000000000100011\
+        0800200001001b55110000000000000000000000000000000000020000003031\
+        3030000000000000000000010102000000000000000000000000000000000000\
+        0000000000000000000000000000000000000000000000000000000000000000\
+        0000000000000000000000000000000000000000000000000000000000000000\
+        0000000000000000000000000000000000000000000000000000000000000000\
+        0000000000000000000000000000000000000000000000000000000000000000\
+        0000000000000000000000000000000000000000000000000000000000000000\
+        0000000000000000000000000000000000000000000000000000000000000000\
+        0000000000000000000000000001010505010300000000000108000000000000";
+
+    /// EXT_CSD of a 2GB eMMC 4.41 device, byte 0 first
+    const EXT_CSD_EMMC_4_41: &str = "\
+        0000000000000000000000000000000000000000000000000000000000000000\
+        0000000000000000000000000000000000000000000000000000000000000000\
+        0000000000000000000000000000000000000000000000000000000000000000\
+        0000000000000000000000000000000000000000000000000000000000000000\
+        0000000000000000000000000000000000000000000000000000000000000000\
+        0300000000000000010000000000000000000000000000000000000000000000\
+        050002000300000000000000000000000000000000343b000000000000080000\
+        0400080000000000000000000000000000000000000000000000000000000000\
+        0000000000000000000000000000000000000000000000000000000000000000\
+        0000000000000000000000000000000000000000000000000000000000000000\
+        0000000000000000000000000000000000000000000000000000000000000000\
+        0000000000000000000000000000000000000000000000000000000000000000\
+        0000000000000000000000000000000000000000000000000000000000000000\
+        0000000000000000000000000000000000000000000000000000000000000000\
+        0000000000000000000000000000000000000000000000000000000000000000\
+        0000000000000000000000000000000000000000000000000100000000000000";
+
+    /// Pack a hex dump into words, as read from the SDMMC FIFO
+    fn words_from_dump(dump: &str, inner: &mut [u32]) {
+        assert_eq!(dump.len(), 8 * inner.len());
+
+        for (i, word) in inner.iter_mut().enumerate() {
+            for j in 0..4 {
+                let k = 2 * (4 * i + j);
+                let byte = u8::from_str_radix(&dump[k..k + 2], 16).unwrap();
+                *word |= u32::from(byte) << (8 * j);
+            }
+        }
+    }
+
+    fn ext_csd_from_dump(dump: &str) -> ExtCSD {
+        let mut inner = [0u32; 128];
+        words_from_dump(dump, &mut inner);
+        ExtCSD::new(inner)
+    }
+
+    #[test]
+    fn ext_csd_emmc_5_1() {
+        let ext_csd = ext_csd_from_dump(EXT_CSD_EMMC_5_1);
+
+        assert_eq!(ext_csd.revision(), 8);
+        assert_eq!(ext_csd.sector_count(), 0x00E9_0000);
+        assert_eq!(ext_csd.device_type(), 0x57);
+        assert!(ext_csd.supports_hs52());
+        assert!(ext_csd.supports_ddr52());
+        assert!(ext_csd.supports_hs200());
+        assert!(ext_csd.supports_hs400());
+        assert_eq!(ext_csd.hs_timing(), 1);
+        assert_eq!(ext_csd.bus_width(), 2);
+        assert_eq!(ext_csd.boot_partition_size(), 4 * 1024 * 1024);
+        assert_eq!(ext_csd.rpmb_size(), 4 * 1024 * 1024);
+        assert!(ext_csd.boot_ack());
+        assert_eq!(ext_csd.boot_partition_enable(), 1);
+        assert_eq!(ext_csd.partition_access(), PartitionAccess::User);
+        assert_eq!(ext_csd.cache_size_kb(), 512);
+        assert_eq!(
+            ext_csd.life_time_estimate_a(),
+            LifeTimeEstimate::UsedUpTo(10)
+        );
+        assert_eq!(
+            ext_csd.life_time_estimate_b(),
+            LifeTimeEstimate::UsedUpTo(20)
+        );
+        assert_eq!(ext_csd.pre_eol_info(), PreEolInfo::Normal);
+        assert_eq!(ext_csd.erase_timeout_ms(), 17 * 300);
+        assert!(!ext_csd.erase_group_def());
+        assert_eq!(ext_csd.erase_group_blocks(), 8 * 1024);
+        assert_eq!(ext_csd.secure_erase_multiplier(), 0x1b);
+        assert!(ext_csd.supports_secure_erase());
+        assert!(ext_csd.supports_trim());
+        assert!(ext_csd.supports_sanitize());
+        assert_eq!(ext_csd.trim_timeout_ms(), 17 * 300);
+        assert_eq!(ext_csd.generic_cmd6_time_ms(), None);
+        assert_eq!(ext_csd.firmware_version(), *b"0100\0\0\0\0");
+        assert_eq!(ext_csd.supported_command_sets(), 1);
+    }
+
+    #[test]
+    fn ext_csd_emmc_4_41() {
+        let ext_csd = ext_csd_from_dump(EXT_CSD_EMMC_4_41);
+
+        assert_eq!(ext_csd.revision(), 5);
+        assert_eq!(ext_csd.sector_count(), 0x003B_3400);
+        assert!(ext_csd.supports_hs52());
+        assert!(!ext_csd.supports_ddr52());
+        assert!(!ext_csd.supports_hs200());
+        assert!(!ext_csd.supports_hs400());
+        assert_eq!(ext_csd.hs_timing(), 0);
+        assert_eq!(ext_csd.bus_width(), 0);
+        assert_eq!(ext_csd.boot_partition_size(), 1024 * 1024);
+        assert_eq!(ext_csd.rpmb_size(), 128 * 1024);
+        assert!(!ext_csd.boot_ack());
+        assert_eq!(ext_csd.boot_partition_enable(), 0);
+        assert_eq!(ext_csd.cache_size_kb(), 0);
+        assert_eq!(
+            ext_csd.life_time_estimate_a(),
+            LifeTimeEstimate::NotDefined
+        );
+        assert_eq!(ext_csd.pre_eol_info(), PreEolInfo::NotDefined);
+        assert!(!ext_csd.supports_trim());
+        assert!(!ext_csd.supports_sanitize());
+        assert_eq!(ext_csd.firmware_version(), [0; 8]);
+    }
+
+    /// CSD Version 1.0 of a 2GB SDSC card, with 1024-byte blocks
+    const CSD_SDSC_2GB: u128 = 0x0026_0032_5f5a_83ae_dbb7_ff80_0a80_002f;
+
+    #[test]
+    fn csd_v1_block_count() {
+        let csd = CSD(CSD_SDSC_2GB);
+
+        // (C_SIZE + 1) * 2^(C_SIZE_MULT + 2) * 2^READ_BL_LEN bytes
+        match csd.block_length() {
+            Some(BlockSize::B1024) => (),
+            b => panic!("Block length {:?}", b),
+        }
+        assert_eq!(csd.block_count(), (3771 + 1) * 512 * 2);
+    }
+
+    /// SD Status of a 4-bit card with a 4MB AU, byte 0 first
+    const SD_STATUS_4MB_AU: &str = "\
+        8000000000000000040090000809100000000000000000000200000000000000\
+        0000000000000000000000000000000000000000000000000000000000000000";
+
+    #[test]
+    fn sd_status_erase_timeout() {
+        let mut inner = [0u32; 16];
+        words_from_dump(SD_STATUS_4MB_AU, &mut inner);
+        let status = SDStatus::new(inner);
+
+        assert_eq!(status.speed_class(), 4);
+        assert_eq!(status.allocation_unit_size(), 9);
+        assert_eq!(status.allocation_unit_blocks(), 8192);
+        assert_eq!(status.erase_size(), 8);
+        assert_eq!(status.erase_timeout(), 2);
+        assert_eq!(status.erase_offset(), 1);
+        assert!(status.supports_discard());
+        assert!(!status.supports_fule());
+
+        // 250ms per AU, plus the offset
+        assert_eq!(status.erase_timeout_ms(1), Some(1250));
+        assert_eq!(status.erase_timeout_ms(8 * 8192), Some(3000));
+        assert_eq!(SDStatus::default().erase_timeout_ms(1), None);
+    }
+
+    /// SD Status of a SDXC card with a 24MB AU, byte 0 first
+    const SD_STATUS_24MB_AU: &str = "\
+        80000000000000000a00d000141a000000000000000000000000000000000000\
+        0000000000000000000000000000000000000000000000000000000000000000";
+
+    #[test]
+    fn sd_status_large_au() {
+        let mut inner = [0u32; 16];
+        words_from_dump(SD_STATUS_24MB_AU, &mut inner);
+        let status = SDStatus::new(inner);
+
+        assert_eq!(status.speed_class(), 0xA);
+        assert_eq!(status.allocation_unit_size(), 0xD);
+        assert_eq!(status.allocation_unit_blocks(), 24 * 2048);
+        assert_eq!(status.erase_size(), 20);
+        assert_eq!(status.erase_timeout(), 6);
+        assert_eq!(status.erase_offset(), 2);
+
+        // 300ms per AU, plus the offset
+        assert_eq!(status.erase_timeout_ms(1), Some(2300));
+        assert_eq!(status.erase_timeout_ms(24 * 2048 + 1), Some(2600));
+        assert_eq!(status.erase_timeout_ms(20 * 24 * 2048), Some(8000));
+    }
+
+    #[test]
+    fn r1_status() {
+        // ADDRESS_ERROR in the Transfer State, ready for data
+        let r1 = R1Status(0x4000_0900);
+        assert!(r1.is_error());
+        assert!(r1.address_error());
+        assert!(!r1.out_of_range());
+        assert_eq!(r1.current_state(), CardState::Transfer);
+        assert!(r1.ready_for_data());
+
+        // CMD55 after an illegal command, in the Standby State
+        let r1 = R1Status(0x0040_0620);
+        assert!(!r1.is_error());
+        assert!(r1.illegal_command());
+        assert!(r1.app_cmd());
+        assert_eq!(r1.current_state(), CardState::Standby);
+
+        // Locked card in the Programming State, with WP_ERASE_SKIP
+        let r1 = R1Status(0x0200_8E00);
+        assert!(r1.is_error());
+        assert!(r1.card_is_locked());
+        assert!(r1.wp_erase_skip());
+        assert_eq!(r1.current_state(), CardState::Programming);
+    }
+
+    /// Switch function status of a UHS-I card, in check mode with SDR25
+    /// selected, byte 0 first
+    const SWITCH_STATUS_UHS_I: &str = "\
+        00c880018001800f800f8003801f000001010000000000000000000000040000\
+        0000000000000000000000000000000000000000000000000000000000000000";
+
+    #[test]
+    fn switch_status_uhs_i() {
+        let mut inner = [0u32; 16];
+        words_from_dump(SWITCH_STATUS_UHS_I, &mut inner);
+        let status = SwitchStatus::new(inner);
+
+        assert_eq!(status.max_current_ma(), 200);
+        assert_eq!(status.data_structure_version(), 1);
+        assert_eq!(status.supported(FunctionGroup::AccessMode), 0x801F);
+        assert_eq!(status.supported(FunctionGroup::CommandSystem), 0x8003);
+        assert_eq!(status.supported(FunctionGroup::DriverStrength), 0x800F);
+        assert_eq!(status.supported(FunctionGroup::PowerLimit), 0x800F);
+        assert_eq!(status.supported(FunctionGroup::Group6), 0x8001);
+        assert_eq!(status.selected(FunctionGroup::AccessMode), 1);
+        assert_eq!(status.selected(FunctionGroup::CommandSystem), 0);
+        assert_eq!(status.selected(FunctionGroup::PowerLimit), 0);
+        assert_eq!(status.busy(FunctionGroup::AccessMode), 1 << 2);
+        assert_eq!(status.busy(FunctionGroup::DriverStrength), 0);
+    }
+}
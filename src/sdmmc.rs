@@ -1,42 +1,82 @@
 //! # SD MultiMediaCard interface (SDMMC)
 //!
-//! For HDHC / SDXC / SDUC cards. SDSC cards are not supported.
+//! For SDSC / SDHC / SDXC / SDUC cards, and MMC / eMMC devices.
 //!
 //! Adapted from stm32f4xx-hal
 //! https://github.com/stm32-rs/stm32f4xx-hal/blob/master/src/sdio.rs
 
+use core::cell::Cell;
 use core::fmt;
+use core::marker::PhantomData;
+use core::sync::atomic::{compiler_fence, Ordering};
 
+use embedded_hal::digital::v2::InputPin;
+
+use crate::registers::*;
 use crate::sd_registers::*;
 
-use stm32h7xx_hal::gpio::gpioa::PA0;
-use stm32h7xx_hal::gpio::gpiob::{PB14, PB15, PB3, PB4, PB8, PB9};
-use stm32h7xx_hal::gpio::gpioc::{PC1, PC10, PC11, PC12, PC6, PC7, PC8, PC9};
-use stm32h7xx_hal::gpio::gpiod::{PD2, PD6, PD7};
-use stm32h7xx_hal::gpio::gpiog::PG11;
+#[cfg(target_arch = "arm")]
+use core::ptr;
+// The async transfers are also built for the tests on the host
+#[cfg(any(target_arch = "arm", test))]
+use core::{
+    cell::RefCell,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+#[cfg(target_arch = "arm")]
+use cortex_m::interrupt::{self, Mutex};
+#[cfg(target_arch = "arm")]
+use stm32h7xx_hal::{
+    gpio::gpioa::PA0,
+    gpio::gpiob::{PB14, PB15, PB3, PB4, PB8, PB9},
+    gpio::gpioc::{PC1, PC10, PC11, PC12, PC6, PC7, PC8, PC9},
+    gpio::gpiod::{PD2, PD6, PD7},
+    gpio::gpiog::PG11,
+    gpio::{Alternate, AF10, AF11, AF12, AF9},
+    //gpio::{AF7, AF8},
+    rcc::rec::{ResetEnable, SdmmcClkSelGetter},
+    rcc::{rec, CoreClocks},
+    stm32::{DELAY_BLOCK_SDMMC1, DELAY_BLOCK_SDMMC2, SDMMC1, SDMMC2},
+};
+
+#[cfg(target_arch = "arm")]
 use stm32h7xx_hal::time::Hertz;
 
-use stm32h7xx_hal::gpio::{Alternate, AF10, AF11, AF12, AF9};
-//use stm32h7xx_hal::gpio:::{AF7, AF8};
-use stm32h7xx_hal::rcc::rec::{ResetEnable, SdmmcClkSelGetter};
-use stm32h7xx_hal::rcc::{rec, CoreClocks};
-use stm32h7xx_hal::stm32::{SDMMC1, SDMMC2};
+/// Frequency in Hertz. Stands in for `stm32h7xx_hal::time::Hertz` when the
+/// driver is built for the host
+#[cfg(not(target_arch = "arm"))]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Hertz(pub u32);
 
+#[cfg(target_arch = "arm")]
 pub trait PinClk<SDMMC> {}
+#[cfg(target_arch = "arm")]
 pub trait PinCmd<SDMMC> {}
+#[cfg(target_arch = "arm")]
 pub trait PinD0<SDMMC> {}
+#[cfg(target_arch = "arm")]
 pub trait PinD1<SDMMC> {}
+#[cfg(target_arch = "arm")]
 pub trait PinD2<SDMMC> {}
+#[cfg(target_arch = "arm")]
 pub trait PinD3<SDMMC> {}
+#[cfg(target_arch = "arm")]
 pub trait PinD4<SDMMC> {}
+#[cfg(target_arch = "arm")]
 pub trait PinD5<SDMMC> {}
+#[cfg(target_arch = "arm")]
 pub trait PinD6<SDMMC> {}
+#[cfg(target_arch = "arm")]
 pub trait PinD7<SDMMC> {}
 
+#[cfg(target_arch = "arm")]
 pub trait Pins<SDMMC> {
     const BUSWIDTH: BusWidth;
 }
 
+#[cfg(target_arch = "arm")]
 impl<SDMMC, CLK, CMD, D0, D1, D2, D3, D4, D5, D6, D7> Pins<SDMMC>
     for (CLK, CMD, D0, D1, D2, D3, D4, D5, D6, D7)
 where
@@ -54,6 +94,7 @@ where
     const BUSWIDTH: BusWidth = BusWidth::Eight;
 }
 
+#[cfg(target_arch = "arm")]
 impl<SDMMC, CLK, CMD, D0, D1, D2, D3> Pins<SDMMC> for (CLK, CMD, D0, D1, D2, D3)
 where
     CLK: PinClk<SDMMC>,
@@ -66,6 +107,7 @@ where
     const BUSWIDTH: BusWidth = BusWidth::Four;
 }
 
+#[cfg(target_arch = "arm")]
 impl<SDMMC, CLK, CMD, D0> Pins<SDMMC> for (CLK, CMD, D0)
 where
     CLK: PinClk<SDMMC>,
@@ -75,6 +117,7 @@ where
     const BUSWIDTH: BusWidth = BusWidth::One;
 }
 
+#[cfg(target_arch = "arm")]
 macro_rules! pins {
     ($($SDMMCX:ty: CLK: [$($CLK:ty),*] CMD: [$($CMD:ty),*]
        D0: [$($D0:ty),*] D1: [$($D1:ty),*] D2: [$($D2:ty),*] D3: [$($D3:ty),*]
@@ -117,6 +160,7 @@ macro_rules! pins {
     }
 }
 
+#[cfg(target_arch = "arm")]
 pins! {
     SDMMC1:
     CLK: [PC12<Alternate<AF12>>]
@@ -151,7 +195,7 @@ pins! {
 }
 
 /// The number of lines used on the SDMMC bus
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 #[allow(missing_docs)]
 pub enum BusWidth {
     One = 1,
@@ -167,6 +211,8 @@ pub enum CardType {
     SDSC,
     /// High capacity (< 32Gb)
     SDHC,
+    /// MultiMediaCard or eMMC device
+    EMMC,
 }
 impl Default for CardType {
     fn default() -> Self {
@@ -190,12 +236,219 @@ impl Default for Signalling {
         Signalling::SDR12
     }
 }
+impl Signalling {
+    /// Maximum SDMMC_CK frequency for this signalling scheme
+    fn max_clock(self) -> u32 {
+        match self {
+            Signalling::SDR12 => 25_000_000,
+            Signalling::SDR25 | Signalling::DDR50 => 50_000_000,
+            Signalling::SDR50 => 100_000_000,
+            Signalling::SDR104 => 208_000_000,
+        }
+    }
+}
+
+/// Erase operation. See PLSS v7_10 Section 4.3.5 and JESD84-B51 Section
+/// 6.6.9
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum EraseMode {
+    /// Erase. The blocks read back as all zeros or all ones
+    Erase,
+    /// SD: Discard. The contents of the blocks become indeterminate
+    Discard,
+    /// SD: Full User area Logical Erase. Erases the whole user area
+    FULE,
+    /// MMC: Trim. Erases write blocks instead of erase groups
+    Trim,
+    /// MMC: Secure Erase. The erased data is physically removed
+    SecureErase,
+    /// MMC: Sanitize. Physically removes all data in the unmapped user
+    /// address space
+    Sanitize,
+}
+
+/// Output driver strength of the card (CMD6 Function Group 3). See PLSS
+/// v7_10 Section 5.1.4
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DriverStrength {
+    /// Type B, 50Ω. Default
+    TypeB = 0,
+    /// Type A, 33Ω
+    TypeA = 1,
+    /// Type C, 66Ω
+    TypeC = 2,
+    /// Type D, 100Ω
+    TypeD = 3,
+}
+impl DriverStrength {
+    fn from_function(function: u8) -> Result<Self, Error> {
+        match function {
+            0 => Ok(DriverStrength::TypeB),
+            1 => Ok(DriverStrength::TypeA),
+            2 => Ok(DriverStrength::TypeC),
+            3 => Ok(DriverStrength::TypeD),
+            _ => Err(ErrorKind::UnsupportedFunction.into()),
+        }
+    }
+}
+
+/// Current limit of the card (CMD6 Function Group 4), also known as the
+/// power limit. See PLSS v7_10 Section 4.3.10.4
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[allow(non_camel_case_types)]
+pub enum CurrentLimit {
+    /// 200mA (0.72W). Default
+    I_200mA = 0,
+    /// 400mA (1.44W)
+    I_400mA = 1,
+    /// 600mA (2.16W)
+    I_600mA = 2,
+    /// 800mA (2.88W)
+    I_800mA = 3,
+}
+impl CurrentLimit {
+    fn from_function(function: u8) -> Result<Self, Error> {
+        match function {
+            0 => Ok(CurrentLimit::I_200mA),
+            1 => Ok(CurrentLimit::I_400mA),
+            2 => Ok(CurrentLimit::I_600mA),
+            3 => Ok(CurrentLimit::I_800mA),
+            _ => Err(ErrorKind::UnsupportedFunction.into()),
+        }
+    }
+}
+
+/// External transceiver that sets the signalling voltage on the SDMMC bus,
+/// for example by driving the VSWITCH / EN pin of a level shifter.
+///
+/// Implemented for any `FnMut(bool)`
+pub trait SignallingVoltage {
+    /// Select 1.8V signalling if `enable` is true, otherwise select 3.3V
+    /// signalling
+    fn set_1v8(&mut self, enable: bool);
+}
+impl<F> SignallingVoltage for F
+where
+    F: FnMut(bool),
+{
+    fn set_1v8(&mut self, enable: bool) {
+        self(enable)
+    }
+}
+
+/// Card detect input, usually a switch in the card slot.
+///
+/// Implemented for any [`InputPin`], and for [`NoCardDetect`]
+pub trait CardDetect {
+    /// Level of the card detect input. `None` if there is no card detect
+    /// input, or it cannot be read
+    fn level(&self) -> Option<bool>;
+}
+impl<P> CardDetect for P
+where
+    P: InputPin,
+{
+    fn level(&self) -> Option<bool> {
+        self.is_high().ok()
+    }
+}
+
+/// No card detect input. A card is assumed to be present
+#[derive(Debug, Copy, Clone)]
+pub struct NoCardDetect;
+impl CardDetect for NoCardDetect {
+    fn level(&self) -> Option<bool> {
+        None
+    }
+}
+
+/// Level of the card detect input when a card is present
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CardDetectPolarity {
+    /// The input is low when a card is present. Usually a switch to
+    /// ground, with a pull-up
+    ActiveLow,
+    /// The input is high when a card is present
+    ActiveHigh,
+}
+
+/// State of the card slot, tracked by
+/// [`poll_card_detect`](struct.Sdmmc.html#method.poll_card_detect)
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SlotState {
+    /// No card in the slot
+    Absent,
+    /// A card is in the slot, but has not been initialized
+    Inserted,
+    /// The card has been initialized by `init_card`
+    Initialized,
+    /// The card has been removed. The card is forgotten and the bus is
+    /// powered down. Becomes `Absent` on the next poll
+    Removed,
+}
+
+/// Retry policy for commands and data transfers. Commands are retried
+/// after a CRC error or a timeout. Data transfers are also retried after a
+/// data CRC error or a FIFO overrun. The commands that start a data
+/// transfer are not retried on their own: the whole transfer is retried
+/// instead. Erase commands are not retried.
+///
+/// The default policy makes a single attempt at everything
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Attempts at each command
+    pub command_attempts: u8,
+    /// Attempts at each single block transfer (`read_block`,
+    /// `write_block`)
+    pub block_attempts: u8,
+    /// Attempts at each multiple block transfer (`read_blocks`,
+    /// `write_blocks`, `read_blocks_dma`, `write_blocks_dma`)
+    pub transfer_attempts: u8,
+    /// Halve the bus clock after this many consecutive data CRC errors.
+    /// The bus clock is never stepped down below 400kHz
+    pub clock_step_down: Option<u8>,
+}
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            command_attempts: 1,
+            block_attempts: 1,
+            transfer_attempts: 1,
+            clock_step_down: None,
+        }
+    }
+}
+
+/// Retries made under the [`RetryPolicy`], for monitoring the quality of
+/// the link to the card
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct RetryStats {
+    /// Commands retried
+    pub command_retries: u32,
+    /// Single block transfers retried
+    pub block_retries: u32,
+    /// Multiple block transfers retried
+    pub transfer_retries: u32,
+    /// Data CRC errors in single and multiple block transfers
+    pub data_crc_errors: u32,
+    /// Times the bus clock was stepped down
+    pub clock_step_downs: u32,
+}
 
-/// Errors
+/// Delay applied to the receive sampling clock by the DLYB delay block
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SampleDelay {
+    /// Delay of each unit delay cell (UNIT)
+    pub unit: u8,
+    /// Output clock phase, in unit delays (SEL)
+    pub sel: u8,
+}
+
+/// Kinds of error
 #[non_exhaustive]
 #[allow(missing_docs)]
-#[derive(Debug, Copy, Clone)]
-pub enum Error {
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ErrorKind {
     Timeout,
     SoftwareTimeout,
     UnsupportedCardVersion,
@@ -206,6 +459,137 @@ pub enum Error {
     NoCard,
     BadClock,
     SignalingSwitchFailed,
+    NoDelayBlock,
+    TuningFailed,
+    UnsupportedEraseMode,
+    BadBuffer,
+    DmaError,
+    OutOfRange,
+    /// The card cannot select the requested function (CMD6)
+    UnsupportedFunction,
+    /// A non-blocking transfer is already in progress
+    Busy,
+    /// The card reported OUT_OF_RANGE, ADDRESS_ERROR or BLOCK_LEN_ERROR
+    AddressError(R1Status),
+    /// The card reported ERASE_SEQ_ERROR or ERASE_PARAM
+    EraseError(R1Status),
+    /// The card reported WP_VIOLATION or WP_ERASE_SKIP
+    WriteProtect(R1Status),
+    /// The card reported LOCK_UNLOCK_FAILED
+    LockUnlockFailed(R1Status),
+    /// The card reported CARD_ECC_FAILED
+    CardEccFailed(R1Status),
+    /// The card reported CC_ERROR, ERROR, CSD_OVERWRITE or AKE_SEQ_ERROR
+    CardError(R1Status),
+}
+
+/// Phase of a command in which an error occurred
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Phase {
+    /// Sending the command, or receiving its response
+    Command,
+    /// Transferring the data
+    Data,
+    /// Waiting for the card to release D0 after a busy (R1b) response
+    Busy,
+}
+
+/// Errors, with the context in which they occurred
+#[derive(Copy, Clone)]
+pub struct Error {
+    kind: ErrorKind,
+    cmd: Option<u8>,
+    app_cmd: bool,
+    phase: Option<Phase>,
+    address: Option<u32>,
+    star: u32,
+}
+impl fmt::Debug for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Error")
+            .field("kind", &self.kind)
+            .field("cmd", &self.cmd)
+            .field("app_cmd", &self.app_cmd)
+            .field("phase", &self.phase)
+            .field("address", &self.address)
+            .field("star", &format_args!("{:#010X}", self.star))
+            .finish()
+    }
+}
+impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Self {
+        Error {
+            kind,
+            cmd: None,
+            app_cmd: false,
+            phase: None,
+            address: None,
+            star: 0,
+        }
+    }
+}
+impl Error {
+    /// The kind of error
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+    /// Index of the command that failed, if the error occurred during a
+    /// command
+    pub fn cmd(&self) -> Option<u8> {
+        self.cmd
+    }
+    /// The command that failed is an application specific command
+    /// (ACMD)
+    pub fn app_cmd(&self) -> bool {
+        self.app_cmd
+    }
+    /// Phase of the command in which the error occurred
+    pub fn phase(&self) -> Option<Phase> {
+        self.phase
+    }
+    /// Block address of the transfer that failed, for errors in a block
+    /// transfer
+    pub fn address(&self) -> Option<u32> {
+        self.address
+    }
+    /// SDMMC_STAR flags when the error was detected. Zero if it was not
+    /// detected by the peripheral
+    pub fn star(&self) -> u32 {
+        self.star
+    }
+    /// The Card Status reported by the card with this error, if any
+    pub fn card_status(&self) -> Option<R1Status> {
+        match self.kind {
+            ErrorKind::AddressError(r1)
+            | ErrorKind::EraseError(r1)
+            | ErrorKind::WriteProtect(r1)
+            | ErrorKind::LockUnlockFailed(r1)
+            | ErrorKind::CardEccFailed(r1)
+            | ErrorKind::CardError(r1) => Some(r1),
+            _ => None,
+        }
+    }
+
+    /// This error in `phase` of command `cmd`, with the flags `star`
+    pub(crate) fn in_cmd(
+        mut self,
+        cmd: u8,
+        app_cmd: bool,
+        phase: Phase,
+        star: u32,
+    ) -> Self {
+        self.cmd = Some(cmd);
+        self.app_cmd = app_cmd;
+        self.phase = Some(phase);
+        self.star = star;
+        self
+    }
+
+    /// This error in a transfer at block `address`
+    pub(crate) fn at(mut self, address: u32) -> Self {
+        self.address = Some(address);
+        self
+    }
 }
 
 /// A SD command
@@ -220,6 +604,8 @@ struct Cmd {
 pub struct Card {
     /// The type of this card
     pub card_type: CardType,
+    /// Physical Layer Specification version
+    pub version: CardVersion,
     /// Operation Conditions Register
     pub ocr: OCR,
     /// Relative Card Address
@@ -232,27 +618,164 @@ pub struct Card {
     pub scr: SCR,
     /// SD Status
     pub status: SDStatus,
+    /// Extended CSD (MMC / eMMC only)
+    pub ext_csd: ExtCSD,
 }
 impl Card {
     /// Size in bytes
     pub fn size(&self) -> u64 {
-        // SDHC / SDXC / SDUC
-        u64::from(self.csd.block_count()) * 512 * 1024
+        match self.card_type {
+            // MMC devices > 2GB report their size in the EXT_CSD
+            CardType::EMMC if self.ocr.ccs() => {
+                u64::from(self.ext_csd.sector_count()) * 512
+            }
+            CardType::EMMC => self.csd.c_size_block_count() * 512,
+            _ => self.csd.block_count() * 512,
+        }
+    }
+
+    /// Convert a block address to the argument for a data transfer
+    /// command. SDSC cards and MMC devices <= 2GB are byte addressed, all
+    /// others are block addressed.
+    ///
+    /// Returns ErrorKind::OutOfRange if the byte address of `block` does
+    /// not fit in the argument
+    fn data_address(&self, block: u32) -> Result<u32, Error> {
+        let byte_addressed = match self.card_type {
+            CardType::SDSC => true,
+            CardType::EMMC => !self.ocr.ccs(),
+            _ => false,
+        };
+
+        if byte_addressed {
+            block
+                .checked_mul(512)
+                .ok_or_else(|| Error::from(ErrorKind::OutOfRange).at(block))
+        } else {
+            Ok(block)
+        }
+    }
+
+    /// Busy timeout for erasing `blocks` blocks with `mode`, in
+    /// milliseconds. Never less than 1 second.
+    ///
+    /// SD cards use the calculation from the SD Status if the card supports
+    /// it, otherwise 250ms per erase unit. MMC devices use the per erase
+    /// group timeouts from the Extended CSD
+    fn erase_timeout_ms(&self, blocks: u32, mode: EraseMode) -> u32 {
+        let ms = match self.card_type {
+            CardType::EMMC => {
+                let ext_csd = &self.ext_csd;
+                let group_ms = match mode {
+                    EraseMode::Trim => ext_csd.trim_timeout_ms(),
+                    EraseMode::SecureErase => {
+                        ext_csd.erase_timeout_ms().saturating_mul(
+                            ext_csd.secure_erase_multiplier().into(),
+                        )
+                    }
+                    _ => ext_csd.erase_timeout_ms(),
+                };
+                let group = core::cmp::max(ext_csd.erase_group_blocks(), 1);
+                let groups = (blocks + group - 1) / group;
+                groups.saturating_mul(group_ms)
+            }
+            _ => self.status.erase_timeout_ms(blocks).unwrap_or_else(|| {
+                let unit = core::cmp::max(self.csd.erase_size_blocks(), 1);
+                let units = (blocks + unit - 1) / unit;
+                units.saturating_mul(250)
+            }),
+        };
+
+        core::cmp::max(ms, 1000)
+    }
+
+    /// Busy timeout for programming the blocks of a write, in
+    /// milliseconds. See PLSS v7_10 Section 4.6.2.2
+    fn write_timeout_ms(&self) -> u32 {
+        match self.card_type {
+            // SDXC cards are > 32GB
+            CardType::SDHC if self.size() > 32 * 1024 * 1024 * 1024 => 500,
+            CardType::SDSC | CardType::SDHC => 250,
+            CardType::EMMC => 500,
+        }
     }
 }
 
+/// The kind of error from the data path flags in `status`, if any
+fn datapath_error_kind(status: u32) -> Option<ErrorKind> {
+    if status & star::DCRCFAIL != 0 {
+        Some(ErrorKind::DataCrcFail)
+    } else if status & star::RXOVERR != 0 {
+        Some(ErrorKind::RxOverFlow)
+    } else if status & star::DTIMEOUT != 0 {
+        Some(ErrorKind::Timeout)
+    } else if status & star::IDMATE != 0 {
+        Some(ErrorKind::DmaError)
+    } else {
+        None
+    }
+}
+
+/// Return the error from the data path flags in `$status`, if any.
+/// The data path and the card are recovered first, so that the driver can
+/// be used again
 macro_rules! err_from_datapath_sm {
-    ($status:ident) => {
-        if $status.dcrcfail().bit() {
-            return Err(Error::DataCrcFail);
-        } else if $status.rxoverr().bit() {
-            return Err(Error::RxOverFlow);
-        } else if $status.dtimeout().bit() {
-            return Err(Error::Timeout);
+    ($self:ident, $status:ident) => {
+        if let Some(kind) = datapath_error_kind($status) {
+            // Before recovery sends more commands
+            let err = $self.error(kind, Phase::Data, $status);
+            $self.recover_datapath();
+            return Err(err);
         }
     };
 }
 
+/// EXT_CSD BUS_WIDTH byte index
+const EXT_CSD_BUS_WIDTH: u8 = 183;
+
+/// EXT_CSD SANITIZE_START byte index
+const EXT_CSD_SANITIZE_START: u8 = 165;
+
+/// EXT_CSD ERASE_GROUP_DEF byte index
+const EXT_CSD_ERASE_GROUP_DEF: u8 = 175;
+
+/// Timeout for a CMD6 SWITCH, for devices that do not define
+/// GENERIC_CMD6_TIME
+const MMC_SWITCH_TIMEOUT_MS: u32 = 500;
+
+/// Timeout for a sanitize operation
+const MMC_SANITIZE_TIMEOUT_MS: u32 = 240_000;
+
+/// Tuning block pattern for a 4-bit bus. See PLSS v7_10 Table 4-2
+const TUNING_BLOCK_4BIT: [u8; 64] = [
+    0xFF, 0x0F, 0xFF, 0x00, 0xFF, 0xCC, 0xC3, 0xCC, 0xC3, 0x3C, 0xCC, 0xFF,
+    0xFE, 0xFF, 0xFE, 0xEF, 0xFF, 0xDF, 0xFF, 0xDD, 0xFF, 0xFB, 0xFF, 0xFB,
+    0xBF, 0xFF, 0x7F, 0xFF, 0x77, 0xF7, 0xBD, 0xEF, 0xFF, 0xF0, 0xFF, 0xF0,
+    0x0F, 0xFC, 0xCC, 0x3C, 0xCC, 0x33, 0xCC, 0xCF, 0xFF, 0xEF, 0xFF, 0xEE,
+    0xFF, 0xFD, 0xFF, 0xFD, 0xDF, 0xFF, 0xBF, 0xFF, 0xBB, 0xFF, 0xF7, 0xFF,
+    0xF7, 0x7F, 0x7B, 0xDE,
+];
+
+#[cfg(target_arch = "arm")]
+/// Memory accessible by the SDMMC1 IDMA, as inclusive address ranges: AXI
+/// SRAM, FMC and QUADSPI. See RM0433 Table 3
+const SDMMC1_IDMA_REGIONS: &[(u32, u32)] = &[
+    (0x2400_0000, 0x2407_FFFF), // AXI SRAM
+    (0x6000_0000, 0x9FFF_FFFF), // FMC, QUADSPI
+    (0xC000_0000, 0xDFFF_FFFF), // FMC SDRAM
+];
+
+#[cfg(target_arch = "arm")]
+/// Memory accessible by the SDMMC2 IDMA, as inclusive address ranges: AXI
+/// SRAM, SRAM1-4, FMC and QUADSPI. See RM0433 Table 3
+const SDMMC2_IDMA_REGIONS: &[(u32, u32)] = &[
+    (0x2400_0000, 0x2407_FFFF), // AXI SRAM
+    (0x3000_0000, 0x3004_7FFF), // SRAM1, SRAM2, SRAM3
+    (0x3800_0000, 0x3800_FFFF), // SRAM4
+    (0x6000_0000, 0x9FFF_FFFF), // FMC, QUADSPI
+    (0xC000_0000, 0xDFFF_FFFF), // FMC SDRAM
+];
+
 /// Indicates transfer direction
 enum Dir {
     CardToHost,
@@ -261,6 +784,7 @@ enum Dir {
 
 enum PowerCtrl {
     Off = 0b00,
+    Cycle = 0b10,
     On = 0b11,
 }
 
@@ -282,71 +806,209 @@ enum Response {
     Long = 3,
 }
 
-#[derive(Eq, PartialEq, Copy, Clone)]
-#[allow(dead_code)]
-enum CardStatus {
-    /// Card state is ready
-    Ready = 1,
-    /// Card is in identification state
-    Identification = 2,
-    /// Card is in standby state
-    Standby = 3,
-    /// Card is in transfer state
-    Transfer = 4,
-    /// Card is sending an operation
-    Sending = 5,
-    /// Card is receiving operation information
-    Receiving = 6,
-    /// Card is in programming state
-    Programming = 7,
-    /// Card is disconnected
-    Disconnected = 8,
-    /// Error
-    Error,
-}
-impl From<u8> for CardStatus {
-    fn from(n: u8) -> Self {
-        match n {
-            1 => Self::Ready,
-            2 => Self::Identification,
-            3 => Self::Standby,
-            4 => Self::Transfer,
-            5 => Self::Sending,
-            6 => Self::Receiving,
-            7 => Self::Programming,
-            8 => Self::Disconnected,
-            _ => Self::Error,
-        }
-    }
-}
-
 /// Sdmmc device
-pub struct Sdmmc<SDMMC> {
+pub struct Sdmmc<SDMMC, CD = NoCardDetect> {
     sdmmc: SDMMC,
     /// SDMMC kernel clock
     ker_ck: Hertz,
     /// AHB clock
     hclk: Hertz,
+    /// Core clock, for software delays
+    c_ck: Hertz,
     /// Data bus width
     bus_width: BusWidth,
     /// Current clock to card
     clock: Hertz,
     /// Current signalling scheme to card
     signalling: Signalling,
+    /// Bus is using 1.8V signalling
+    signalling_1v8: bool,
+    /// Owns the DLYB delay block for this peripheral
+    dlyb: bool,
+    /// Receive clock sampling delay found by tuning
+    sample_delay: Option<SampleDelay>,
     /// Card
     card: Option<Card>,
+    /// Type of the card being initialised or in use. Decides the
+    /// response format of CMD3 and CMD8
+    card_type: CardType,
+    /// Non-blocking transfer
+    transfer: TransferState,
+    /// Card detect input
+    card_detect: CD,
+    /// Card detect input polarity
+    cd_polarity: CardDetectPolarity,
+    /// Time the card detect input must be stable for a change to be
+    /// accepted, in milliseconds
+    cd_debounce_ms: u32,
+    /// Debounced card detect input
+    cd_present: bool,
+    /// Card slot state
+    slot: SlotState,
+    /// Retry policy
+    retry: RetryPolicy,
+    /// Retries made under the retry policy
+    retry_stats: Cell<RetryStats>,
+    /// Consecutive data CRC errors, for stepping down the clock
+    crc_errors: u8,
+    /// Argument of the CMD55 just sent, so that it can be sent
+    /// again when the following ACMD is retried
+    app_cmd: Cell<Option<u32>>,
+    /// Index of the last command sent, and whether it is an ACMD. For
+    /// the context of errors
+    last_cmd: Cell<(u8, bool)>,
 }
-impl<SDMMC> fmt::Debug for Sdmmc<SDMMC> {
+impl<SDMMC, CD> fmt::Debug for Sdmmc<SDMMC, CD> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("SDMMC Peripheral")
             .field("Card detected", &self.card.is_some())
+            .field("Slot", &self.slot)
             .field("Bus Width (bits)", &self.bus_width)
             .field("Signalling", &self.signalling)
+            .field("1.8V Signalling", &self.signalling_1v8)
+            .field("Sample Delay", &self.sample_delay)
             .field("Bus Clock", &self.clock)
+            .field("Transfer", &self.transfer)
+            .field("Retry Stats", &self.retry_stats.get())
+            .finish()
+    }
+}
+
+/// A non-blocking transfer, started by `start_read_blocks` or
+/// `start_write_blocks`. Owns the buffer until the transfer is complete
+pub struct Transfer<SDMMC> {
+    buffer: &'static mut [u8],
+    /// The transfer could not be started. The driver's transfer state
+    /// belongs to another transfer, if any
+    error: Option<Error>,
+    _sdmmc: PhantomData<SDMMC>,
+}
+impl<SDMMC> fmt::Debug for Transfer<SDMMC> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Transfer")
+            .field("Length (bytes)", &self.buffer.len())
             .finish()
     }
 }
 
+#[cfg(any(target_arch = "arm", test))]
+/// Waker of the task waiting on an async transfer, woken from the
+/// interrupt handler
+pub(crate) struct WakerSlot {
+    #[cfg(target_arch = "arm")]
+    waker: Mutex<RefCell<Option<Waker>>>,
+    #[cfg(not(target_arch = "arm"))]
+    waker: RefCell<Option<Waker>>,
+}
+
+#[cfg(any(target_arch = "arm", test))]
+impl WakerSlot {
+    pub(crate) const fn new() -> Self {
+        WakerSlot {
+            #[cfg(target_arch = "arm")]
+            waker: Mutex::new(RefCell::new(None)),
+            #[cfg(not(target_arch = "arm"))]
+            waker: RefCell::new(None),
+        }
+    }
+
+    /// Replace the waker
+    fn register(&self, waker: Option<Waker>) {
+        #[cfg(target_arch = "arm")]
+        interrupt::free(|cs| *self.waker.borrow(cs).borrow_mut() = waker);
+        #[cfg(not(target_arch = "arm"))]
+        let _ = self.waker.replace(waker);
+    }
+
+    /// Wake the task, if any
+    pub(crate) fn wake(&self) {
+        #[cfg(target_arch = "arm")]
+        let waker =
+            interrupt::free(|cs| self.waker.borrow(cs).borrow_mut().take());
+        #[cfg(not(target_arch = "arm"))]
+        let waker = self.waker.borrow_mut().take();
+
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+#[cfg(target_arch = "arm")]
+/// Waker for an async transfer on SDMMC1, woken from the interrupt
+static SDMMC1_WAKER: WakerSlot = WakerSlot::new();
+#[cfg(target_arch = "arm")]
+/// Waker for an async transfer on SDMMC2, woken from the interrupt
+static SDMMC2_WAKER: WakerSlot = WakerSlot::new();
+
+#[cfg(any(target_arch = "arm", test))]
+/// Future that completes with a non-blocking transfer. Aborts the transfer
+/// if it is dropped before completion
+struct TransferFuture<'a, S: SdmmcRegisters, CD: CardDetect> {
+    sdmmc: &'a mut Sdmmc<S, CD>,
+    waker: &'a WakerSlot,
+}
+#[cfg(any(target_arch = "arm", test))]
+impl<'a, S: SdmmcRegisters, CD: CardDetect> Future
+    for TransferFuture<'a, S, CD>
+{
+    type Output = Result<(), Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+
+        // Register the waker before checking the flags, so that no
+        // interrupt is missed
+        this.waker.register(Some(cx.waker().clone()));
+
+        this.sdmmc.detect_removal();
+        this.sdmmc.on_interrupt();
+        match this.sdmmc.transfer_result() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                this.sdmmc.enable_transfer_interrupts();
+                Poll::Pending
+            }
+        }
+    }
+}
+#[cfg(any(target_arch = "arm", test))]
+impl<'a, S: SdmmcRegisters, CD: CardDetect> Drop for TransferFuture<'a, S, CD> {
+    fn drop(&mut self) {
+        // Does nothing if the transfer has completed
+        self.waker.register(None);
+        self.sdmmc.abort_transfer();
+    }
+}
+
+/// State of a non-blocking transfer
+#[derive(Debug, Copy, Clone)]
+enum TransferState {
+    /// No transfer
+    Idle,
+    /// Waiting for the response to command `cmd`, which starts a
+    /// transfer of `blocks` blocks at the card data address `arg`
+    Start {
+        cmd: u8,
+        write: bool,
+        address: u32,
+        arg: u32,
+        blocks: u32,
+    },
+    /// Data transfer in progress
+    Data { write: bool, address: u32 },
+    /// Waiting for the response to the CMD12 that stops the transmission
+    Stop { write: bool, address: u32 },
+    /// Waiting for the card to finish programming
+    Busy { address: u32 },
+    /// Transfer failed. The data path is recovered before the error is
+    /// returned
+    Failed(Error),
+    /// Transfer complete
+    Done(Result<(), Error>),
+}
+
+#[cfg(target_arch = "arm")]
 /// Extension trait for SDMMC peripherals
 pub trait SdmmcExt<SDMMC>: Sized {
     /// The `ResetEnable` singleton for this peripheral
@@ -374,783 +1036,3028 @@ pub trait SdmmcExt<SDMMC>: Sized {
     ) -> Sdmmc<SDMMC>;
 }
 
-impl<S> Sdmmc<S> {
+impl<S, CD> Sdmmc<S, CD> {
     /// Calculate clock divisor. Returns a SDMMC_CK less than or equal to
     /// `sdmmc_ck` in Hertz.
     ///
     /// Returns `(clk_div, clk_f)`, where `clk_div` is the divisor register
     /// value and `clk_f` is the resulting new clock frequency.
-    fn clk_div(ker_ck: Hertz, sdmmc_ck: u32) -> Result<(u16, Hertz), Error> {
+    ///
+    /// In DDR mode the divider cannot be bypassed, so `clk_f` is at most half
+    /// of `ker_ck`.
+    fn clk_div(
+        ker_ck: Hertz,
+        sdmmc_ck: u32,
+        ddr: bool,
+    ) -> Result<(u16, Hertz), Error> {
         match (ker_ck.0 + sdmmc_ck - 1) / sdmmc_ck {
-            0 | 1 => Ok((0, ker_ck)),
+            0 | 1 if !ddr => Ok((0, ker_ck)),
+            0 | 1 => Ok((1, Hertz(ker_ck.0 / 2))),
             x @ 2..=2046 => {
                 let clk_div = ((x + 1) / 2) as u16;
                 let clk = Hertz(ker_ck.0 / (clk_div as u32 * 2));
 
                 Ok((clk_div, clk))
             }
-            _ => Err(Error::BadClock),
+            _ => Err(ErrorKind::BadClock.into()),
         }
     }
 }
 
-macro_rules! sdmmc {
-    ($($SDMMCX:ident: ($sdmmcX:ident, $Rec:ident),)+) => {
-        $(
-            impl SdmmcExt<$SDMMCX> for $SDMMCX {
-                type Rec = rec::$Rec;
+impl<S: SdmmcRegisters> Sdmmc<S> {
+    /// Create the driver for a SDMMC peripheral, clocked by `ker_ck`.
+    /// `hclk` is the AHB clock, and `c_ck` is the core clock used for
+    /// software delays. Initially the bus is clocked at <400kHz and the card
+    /// is powered off.
+    #[cfg_attr(not(any(target_arch = "arm", test)), allow(dead_code))]
+    pub(crate) fn new(
+        sdmmc: S,
+        bus_width: BusWidth,
+        ker_ck: Hertz,
+        hclk: Hertz,
+        c_ck: Hertz,
+    ) -> Self {
+        // While the SD/SDIO card or eMMC is in identification mode,
+        // the SDMMC_CK frequency must be less than 400 kHz.
+        let (clkdiv, clock) = Self::clk_div(ker_ck, 400_000, false)
+            .expect("SDMMC too slow. Cannot be generated from ker_ck");
+
+        // Configure clock. 1-bit wide bus, no power saving, no dephasing,
+        // hardware flow control
+        sdmmc.write(Register::Clkcr, u32::from(clkdiv) | clkcr::HWFC_EN);
+
+        // Power off, writen 00: Clock to the card is stopped;
+        // D[7:0], CMD, and CK are driven high.
+        sdmmc.modify(Register::Power, |r| {
+            (r & !power::PWRCTRL) | PowerCtrl::Off as u32
+        });
+
+        Sdmmc {
+            sdmmc,
+            ker_ck,
+            hclk,
+            c_ck,
+            bus_width,
+            card: None,
+            clock,
+            signalling: Default::default(),
+            signalling_1v8: false,
+            dlyb: false,
+            sample_delay: None,
+            card_type: CardType::default(),
+            transfer: TransferState::Idle,
+            card_detect: NoCardDetect,
+            cd_polarity: CardDetectPolarity::ActiveLow,
+            cd_debounce_ms: 0,
+            cd_present: true,
+            slot: SlotState::Inserted,
+            retry: RetryPolicy::default(),
+            retry_stats: Cell::new(RetryStats::default()),
+            crc_errors: 0,
+            app_cmd: Cell::new(None),
+            last_cmd: Cell::new((0, false)),
+        }
+    }
 
-                fn sdmmc<PINS>(self, _pins: PINS,
-                               prec: rec::$Rec,
-                               clocks: &CoreClocks) -> Sdmmc<$SDMMCX>
-                where
-                    PINS: Pins<$SDMMCX>,
-                {
-                    Sdmmc::$sdmmcX(self, PINS::BUSWIDTH, prec, clocks)
-                }
+    /// Use `pin` as the card detect input. A change on the input is only
+    /// accepted once it has been stable for `debounce_ms` milliseconds.
+    ///
+    /// Without a card detect input, a card is always assumed to be present
+    pub fn with_card_detect<P: InputPin>(
+        self,
+        pin: P,
+        polarity: CardDetectPolarity,
+        debounce_ms: u32,
+    ) -> Sdmmc<S, P> {
+        let mut sdmmc = Sdmmc {
+            sdmmc: self.sdmmc,
+            ker_ck: self.ker_ck,
+            hclk: self.hclk,
+            c_ck: self.c_ck,
+            bus_width: self.bus_width,
+            card: self.card,
+            clock: self.clock,
+            signalling: self.signalling,
+            signalling_1v8: self.signalling_1v8,
+            dlyb: self.dlyb,
+            sample_delay: self.sample_delay,
+            card_type: self.card_type,
+            transfer: self.transfer,
+            card_detect: pin,
+            cd_polarity: polarity,
+            cd_debounce_ms: debounce_ms,
+            cd_present: false,
+            slot: self.slot,
+            retry: self.retry,
+            retry_stats: self.retry_stats,
+            crc_errors: self.crc_errors,
+            app_cmd: self.app_cmd,
+            last_cmd: self.last_cmd,
+        };
+        sdmmc.cd_present = sdmmc.card_detect_input();
+        if !sdmmc.cd_present {
+            sdmmc.slot = SlotState::Absent;
+        }
+        sdmmc
+    }
+}
 
-                fn sdmmc_unchecked(self, bus_width: BusWidth,
-                                   prec: rec::$Rec,
-                                   clocks: &CoreClocks) -> Sdmmc<$SDMMCX>
-                {
-                    Sdmmc::$sdmmcX(self, bus_width, prec, clocks)
+impl<S: SdmmcRegisters, CD: CardDetect> Sdmmc<S, CD> {
+    /// Sets the CLKDIV field in CLKCR. Updates clock field in self
+    fn clkcr_set_clkdiv(
+        &mut self,
+        freq: u32,
+        width: BusWidth,
+    ) -> Result<(), Error> {
+        let ddr = self.signalling == Signalling::DDR50;
+        let (clkdiv, new_clock) = Self::clk_div(self.ker_ck, freq, ddr)?;
+        // Enforce AHB and SDMMC_CK clock relation. See RM0433 Rev 7
+        // Section 55.5.8
+        let data_rate = if ddr { 2 } else { 1 };
+        let sdmmc_bus_bandwidth = new_clock.0 * (width as u32) * data_rate;
+        debug_assert!(self.hclk.0 > 3 * sdmmc_bus_bandwidth / 32);
+        self.clock = new_clock;
+
+        // CPSMACT and DPSMACT must be 0 to set CLKDIV
+        while self.sdmmc.read(Register::Star) & (star::DPSMACT | star::CPSMACT)
+            != 0
+        {}
+
+        self.sdmmc.modify(Register::Clkcr, |r| {
+            (r & !clkcr::CLKDIV) | u32::from(clkdiv)
+        });
+
+        // Re-apply the tuned sampling point
+        if let Some(delay) = self.sample_delay {
+            self.set_sample_delay(delay);
+        }
+
+        Ok(())
+    }
+
+    /// Sets the WIDBUS field in CLKCR
+    fn clkcr_set_widbus(&self, width: BusWidth) {
+        // CPSMACT and DPSMACT must be 0 to set WIDBUS
+        while self.sdmmc.read(Register::Star) & (star::DPSMACT | star::CPSMACT)
+            != 0
+        {}
+        let widbus = match width {
+            BusWidth::One => 0,
+            BusWidth::Four => 1,
+            BusWidth::Eight => 2,
+        };
+        self.sdmmc.modify(Register::Clkcr, |r| {
+            (r & !clkcr::WIDBUS) | widbus << clkcr::WIDBUS_SHIFT
+        });
+    }
+
+    /// Sets the BUSSPEED and DDR fields in CLKCR for a signalling
+    /// mode
+    fn clkcr_set_signalling(&self, signalling: Signalling) {
+        let busspeed = match signalling {
+            Signalling::SDR50 | Signalling::DDR50 | Signalling::SDR104 => true,
+            _ => false,
+        };
+        let ddr = signalling == Signalling::DDR50;
+
+        // CPSMACT and DPSMACT must be 0 to set BUSSPEED and DDR
+        while self.sdmmc.read(Register::Star) & (star::DPSMACT | star::CPSMACT)
+            != 0
+        {}
+        self.sdmmc.modify(Register::Clkcr, |r| {
+            let r = r & !(clkcr::BUSSPEED | clkcr::DDR);
+            r | if busspeed { clkcr::BUSSPEED } else { 0 }
+                | if ddr { clkcr::DDR } else { 0 }
+        });
+    }
+
+    /// Initializes card (if present) and sets the bus at the
+    /// specified frequency.
+    ///
+    /// Returns `ErrorKind::NoCard` if the card detect input shows that
+    /// there is no card in the slot.
+    pub fn init_card(&mut self, freq: impl Into<Hertz>) -> Result<(), Error> {
+        self.init(freq.into(), None)
+    }
+
+    /// Initializes card (if present) and sets the bus at the
+    /// specified frequency. UHS-I cards are switched to 1.8V
+    /// signalling, using `transceiver` to change the voltage of
+    /// an external level shifter.
+    ///
+    /// If the card does not complete the voltage switch, it is
+    /// power cycled and initialized with 3.3V signalling
+    /// instead.
+    pub fn init_card_uhs(
+        &mut self,
+        freq: impl Into<Hertz>,
+        transceiver: &mut dyn SignallingVoltage,
+    ) -> Result<(), Error> {
+        self.init(freq.into(), Some(transceiver))
+    }
+
+    /// Initializes card, if the card detect input shows that a
+    /// card is present
+    fn init(
+        &mut self,
+        freq: Hertz,
+        transceiver: Option<&mut dyn SignallingVoltage>,
+    ) -> Result<(), Error> {
+        self.detect_removal();
+        if !self.is_card_present() {
+            return Err(ErrorKind::NoCard.into());
+        }
+
+        let result = self.init_device(freq, transceiver);
+        self.slot = match result {
+            Ok(()) => SlotState::Initialized,
+            Err(_) => SlotState::Inserted,
+        };
+        result
+    }
+
+    /// Initializes card. Attempts to switch to 1.8V signalling
+    /// if `transceiver` is provided
+    fn init_device(
+        &mut self,
+        freq: Hertz,
+        transceiver: Option<&mut dyn SignallingVoltage>,
+    ) -> Result<(), Error> {
+        // Forget any sampling point tuned for a previous card.
+        // Receive clock is sdmmc_io_in_ck
+        self.sample_delay = None;
+        self.sdmmc.modify(Register::Clkcr, |r| r & !clkcr::SELCLKRX);
+
+        // Return the bus to identification mode
+        self.signalling = Signalling::SDR12;
+        self.clkcr_set_signalling(self.signalling);
+        self.clkcr_set_widbus(BusWidth::One);
+        self.clkcr_set_clkdiv(400_000, BusWidth::One)?;
+
+        // Enable power to card
+        self.sdmmc.modify(Register::Power, |r| {
+            (r & !power::PWRCTRL) | PowerCtrl::On as u32
+        });
+
+        self.card_type = CardType::default();
+        self.cmd(Cmd::idle())?;
+
+        // Check if cards supports CMD8 (with pattern)
+        let mut card = Card::default();
+        let cmd8 = match self.cmd(Cmd::hs_send_ext_csd(0x1AA)) {
+            Ok(_) => {
+                let r7 = self.sdmmc.read(Register::Resp1r);
+                if r7 & 0xFFF != 0x1AA {
+                    // Voltage not accepted, or pattern corrupted
+                    return Err(ErrorKind::UnsupportedCardVersion.into());
                 }
+                // Card echoed back the pattern. Must be at least v2
+                true
             }
-
-            impl Sdmmc<$SDMMCX> {
-                /// Sets the CLKDIV field in CLKCR. Updates clock field in self
-                fn clkcr_set_clkdiv(
-                    &mut self,
-                    freq: u32,
-                    width: BusWidth,
-                ) -> Result<(), Error> {
-                    let (clkdiv, new_clock) = Self::clk_div(self.ker_ck, freq)?;
-                    // Enforce AHB and SDMMC_CK clock relation. See RM0433 Rev 7
-                    // Section 55.5.8
-                    let sdmmc_bus_bandwidth = new_clock.0 * (width as u32);
-                    debug_assert!(self.hclk.0 > 3 * sdmmc_bus_bandwidth / 32);
-                    self.clock = new_clock;
-
-                    // CPSMACT and DPSMACT must be 0 to set CLKDIV
-                    while self.sdmmc.star.read().dpsmact().bit_is_set()
-                        || self.sdmmc.star.read().cpsmact().bit_is_set()
-                    {}
-
-                    self.sdmmc
-                        .clkcr
-                        .modify(|_, w| unsafe { w.clkdiv().bits(clkdiv) });
-
-                    Ok(())
+            Err(e) if e.kind() == ErrorKind::Timeout => {
+                // No response. Card is v1.x, which is always SDSC
+                false
+            }
+            Err(err) => return Err(err),
+        };
+
+        // Power up takes at most 1s. See PLSS v7_10 Section 4.2.3
+        let mut timeout_ms = 1000;
+        let ocr = loop {
+            // Signal that next command is a app command. MMC
+            // devices do not respond to CMD8 or ACMD41
+            match self.cmd(Cmd::app_cmd(0)) {
+                // CMD55
+                Ok(_) => (),
+                Err(e) if e.kind() == ErrorKind::Timeout && !cmd8 => {
+                    return self.init_emmc(freq)
                 }
+                Err(err) => return Err(err),
+            }
 
-                /// Initialise SDMMC peripheral
-                pub fn $sdmmcX(
-                    sdmmc: $SDMMCX,
-                    bus_width: BusWidth,
-                    prec: rec::$Rec,
-                    clocks: &CoreClocks,
-                ) -> Self {
-                    // Enable and reset peripheral
-                    let prec = prec.enable().reset();
+            // v1.x cards do not understand HCS or S18R
+            let arg = if cmd8 {
+                CmdAppOper::VOLTAGE_WINDOW_SD as u32
+                    | CmdAppOper::HIGH_CAPACITY as u32
+                    | CmdAppOper::SD_SWITCH_1_8V_CAPACITY as u32
+            } else {
+                CmdAppOper::VOLTAGE_WINDOW_SD as u32
+            };
+
+            // Initialize card
+            match self.cmd(Cmd::app_op_cmd(arg)) {
+                // ACMD41
+                Ok(_) => (),
+                Err(e) if e.kind() == ErrorKind::Crc => (),
+                Err(e) if e.kind() == ErrorKind::Timeout && !cmd8 => {
+                    return self.init_emmc(freq)
+                }
+                Err(err) => return Err(err),
+            }
+            let ocr = OCR(self.sdmmc.read(Register::Resp1r));
+            if !ocr.is_busy() {
+                // Power up done
+                break ocr;
+            }
+            if timeout_ms == 0 {
+                return Err(ErrorKind::SoftwareTimeout.into());
+            }
+            timeout_ms -= 1;
+            self.delay_ms(1);
+        };
+
+        if let Some(transceiver) = transceiver {
+            if ocr.s18a() {
+                // Card accepted the switch to 1.8V
+                if let Err(e) = self.switch_voltage_1v8(transceiver) {
+                    sdmmc_trace!("Voltage switch failed: {:?}", e);
+
+                    // Return the card to 3.3V signalling
+                    transceiver.set_1v8(false);
+                    self.power_cycle();
+                    return self.init_device(freq, None);
+                }
+            }
+        }
 
-                    let hclk = clocks.hclk();
-                    let ker_ck = match prec.get_kernel_clk_mux() {
-                        rec::SdmmcClkSel::PLL1_Q => clocks.pll1_q_ck(),
-                        rec::SdmmcClkSel::PLL2_R => clocks.pll2_r_ck(),
-                    }
-                    .expect("sdmmc_ker_ck not running!");
+        card.card_type = if cmd8 && ocr.ccs() {
+            // Card is SDHC or SDXC or SDUC
+            CardType::SDHC
+        } else {
+            // Card is SDSC, and is byte addressed
+            CardType::SDSC
+        };
+        card.ocr = ocr;
+
+        // Get CID
+        self.cmd(Cmd::all_send_cid())?; // CMD2
+        card.cid = CID::new(self.long_response());
+
+        // Get RCA
+        self.cmd(Cmd::send_rel_addr())?;
+        card.rca = self.sdmmc.read(Register::Resp1r) >> 16;
+
+        // Get CSD
+        self.cmd(Cmd::send_csd(card.rca << 16))?;
+        card.csd = CSD(self.long_response());
+
+        self.select_card(Some(&card))?;
+
+        self.get_scr(&mut card)?;
+
+        // The version reported in the SCR must agree with the
+        // response to CMD8
+        let scr_version = card.scr.version();
+        card.version = match (cmd8, scr_version) {
+            (false, CardVersion::V1_0) | (false, CardVersion::V1_1) => {
+                scr_version
+            }
+            (true, CardVersion::V1_0)
+            | (true, CardVersion::V1_1)
+            | (false, _) => {
+                return Err(ErrorKind::UnsupportedCardVersion.into())
+            }
+            (true, _) => scr_version,
+        };
 
-                    // For tuning the phase of the receive sampling clock, a
-                    // DLYB block can be connected between sdmmc_io_in_ck and
-                    // sdmmc_fb_ck
-
-                    // While the SD/SDIO card or eMMC is in identification mode,
-                    // the SDMMC_CK frequency must be less than 400 kHz.
-                    let (clkdiv, clock) = Self::clk_div(ker_ck, 400_000)
-                        .expect("SDMMC too slow. Cannot be generated from ker_ck");
-
-                    // Configure clock
-                    sdmmc.clkcr.write(|w| unsafe {
-                        w.widbus()
-                            .bits(0) // 1-bit wide bus
-                            .clkdiv()
-                            .bits(clkdiv)
-                            .pwrsav() // power saving
-                            .clear_bit()
-                            .negedge() // dephasing selection bit
-                            .clear_bit()
-                            .hwfc_en() // hardware flow control
-                            .set_bit()
-                    });
+        // Set bus width. SD cards have at most 4 data lines
+        let (width, acmd_arg) = match self.bus_width {
+            BusWidth::Eight | BusWidth::Four if card.scr.bus_width_four() => {
+                (BusWidth::Four, 2)
+            }
+            _ => (BusWidth::One, 0),
+        };
+        self.cmd(Cmd::app_cmd(card.rca << 16))?;
+        self.cmd(Cmd::cmd6(acmd_arg))?; // ACMD6: Bus Width
+        self.clkcr_set_widbus(width);
+
+        // Set Clock
+        if freq.0 <= 25_000_000 {
+            // Final clock frequency
+            self.clkcr_set_clkdiv(freq.0, width)?;
+        } else {
+            // Switch to max clock for SDR12
+            self.clkcr_set_clkdiv(25_000_000, width)?;
+
+            sdmmc_trace!(
+                "Set intermediate clock frequency of 25MHz (SDR12 signalling"
+            );
+        }
 
-                    // Power off, writen 00: Clock to the card is stopped;
-                    // D[7:0], CMD, and CK are driven high.
-                    sdmmc
-                        .power
-                        .modify(|_, w| unsafe { w.pwrctrl().bits(PowerCtrl::Off as u8) });
-
-                    Sdmmc {
-                        sdmmc,
-                        ker_ck,
-                        hclk,
-                        bus_width,
-                        card: None,
-                        clock,
-                        signalling: Default::default(),
+        // Read status
+        let _old = self.card.replace(card);
+        self.read_sd_status()?;
+
+        if freq.0 > 25_000_000 {
+            // SDR50, SDR104 and DDR50 require 1.8V signalling
+            let ddr50 = if width == BusWidth::Four && self.signalling_1v8 {
+                let status = self.switch_status()?;
+                status.supported(FunctionGroup::AccessMode) & (1 << 4) != 0
+            } else {
+                false
+            };
+            let signalling = match freq.0 {
+                _ if !self.signalling_1v8 => Signalling::SDR25,
+                0..=50_000_000 if ddr50 => Signalling::DDR50,
+                0..=50_000_000 => Signalling::SDR25,
+                50_000_001..=100_000_000 => Signalling::SDR50,
+                _ => Signalling::SDR104,
+            };
+
+            // SDR104 support is optional for UHS-I cards
+            self.signalling = match self.switch_signalling_mode(signalling) {
+                Err(e)
+                    if e.kind() == ErrorKind::UnsupportedCardType
+                        && signalling == Signalling::SDR104 =>
+                {
+                    self.switch_signalling_mode(Signalling::SDR50)?
+                }
+                r => r?,
+            };
+
+            if self.signalling != Signalling::SDR12 {
+                self.clkcr_set_signalling(self.signalling);
+                self.clkcr_set_clkdiv(
+                    freq.0.min(self.signalling.max_clock()),
+                    width,
+                )?;
+
+                // Tune the sampling point, if we can. Required for
+                // SDR104
+                match self.signalling {
+                    Signalling::SDR50 | Signalling::SDR104 if self.dlyb => {
+                        let _delay = self.tune()?;
                     }
+                    _ => {}
+                }
 
-                    // drop prec: ker_ck can no longer be modified
+                if self.send_status()?.current_state() != CardState::Transfer {
+                    return Err(ErrorKind::SignalingSwitchFailed.into());
                 }
+            }
 
-                /// Initializes card (if present) and sets the bus at the
-                /// specified frequency.
-                pub fn init_card(&mut self, freq: impl Into<Hertz>) -> Result<(), Error> {
-                    let freq = freq.into();
+            sdmmc_trace!("Set final clock frequency of {}", self.clock.0);
+        }
 
-                    // Enable power to card
-                    self.sdmmc
-                        .power
-                        .modify(|_, w| unsafe { w.pwrctrl().bits(PowerCtrl::On as u8) });
+        Ok(())
+    }
 
-                    self.cmd(Cmd::idle())?;
+    /// Switch the card and the bus to 1.8V signalling (CMD11).
+    ///
+    /// See PLSS v7_10 Section 4.2.4.2 and RM0433 Rev 7 Section
+    /// 55.6.7
+    fn switch_voltage_1v8(
+        &mut self,
+        transceiver: &mut dyn SignallingVoltage,
+    ) -> Result<(), Error> {
+        // Enable the voltage switch sequence. SDMMC_CK is stopped
+        // after the response to CMD11
+        self.sdmmc.modify(Register::Power, |r| r | power::VSWITCHEN);
+
+        let r = self.cmd(Cmd::voltage_switch()).and_then(|_| {
+            // Wait for SDMMC_CK to stop, which it does right after
+            // the response
+            let mut timeout = self.star_reads(1);
+            while self.sdmmc.read(Register::Star) & star::CKSTOP == 0 {
+                timeout -= 1;
+                if timeout == 0 {
+                    return Err(ErrorKind::SoftwareTimeout.into());
+                }
+            }
 
-                    // Check if cards supports CMD8 (with pattern)
-                    self.cmd(Cmd::hs_send_ext_csd(0x1AA))?;
-                    let r1 = self.sdmmc.resp1r.read().bits();
+            // Card signals that the switch was started by
+            // driving D0 low
+            if self.sdmmc.read(Register::Star) & star::BUSYD0 == 0 {
+                return Err(ErrorKind::SignalingSwitchFailed.into());
+            }
 
-                    let mut card = if r1 == 0x1AA {
-                        // Card echoed back the pattern. Must be at least v2
-                        Card::default()
-                    } else {
-                        return Err(Error::UnsupportedCardVersion);
-                    };
+            // Change the external signalling voltage, then start
+            // the voltage switch timer. SDMMC_CK is restarted
+            // after 5ms
+            transceiver.set_1v8(true);
+            self.sdmmc.modify(Register::Power, |r| r | power::VSWITCH);
+
+            // Card signals that the switch completed by
+            // releasing D0 within 1ms of SDMMC_CK restarting
+            let mut timeout = self.star_reads(10);
+            while self.sdmmc.read(Register::Star) & star::VSWEND == 0 {
+                timeout -= 1;
+                if timeout == 0 {
+                    return Err(ErrorKind::SoftwareTimeout.into());
+                }
+            }
+            if self.sdmmc.read(Register::Star) & star::BUSYD0 != 0 {
+                return Err(ErrorKind::SignalingSwitchFailed.into());
+            }
 
-                    let ocr = loop {
-                        // Signal that next command is a app command
-                        self.cmd(Cmd::app_cmd(0))?; // CMD55
+            Ok(())
+        });
 
-                        let arg = CmdAppOper::VOLTAGE_WINDOW_SD as u32
-                            | CmdAppOper::HIGH_CAPACITY as u32
-                            | CmdAppOper::SD_SWITCH_1_8V_CAPACITY as u32;
+        self.sdmmc.write(Register::Icr, star::CKSTOP | star::VSWEND);
+        self.sdmmc.modify(Register::Power, |r| {
+            r & !(power::VSWITCH | power::VSWITCHEN)
+        });
 
-                        // Initialize card
-                        match self.cmd(Cmd::app_op_cmd(arg)) {
-                            // ACMD41
-                            Ok(_) => (),
-                            Err(Error::Crc) => (),
-                            Err(err) => return Err(err),
-                        }
-                        let ocr = OCR(self.sdmmc.resp1r.read().bits());
-                        if !ocr.is_busy() {
-                            // Power up done
-                            break ocr;
-                        }
-                    };
+        self.signalling_1v8 = r.is_ok();
+        r
+    }
 
-                    if ocr.ccs() {
-                        // Card is SDHC or SDXC or SDUC
-                        card.card_type = CardType::SDHC;
-                    } else {
-                        return Err(Error::UnsupportedCardType);
-                    }
-                    card.ocr = ocr;
-
-                    // Get CID
-                    self.cmd(Cmd::all_send_cid())?; // CMD2
-                    let cid = ((self.sdmmc.resp1r.read().bits() as u128) << 96)
-                        | ((self.sdmmc.resp2r.read().bits() as u128) << 64)
-                        | ((self.sdmmc.resp3r.read().bits() as u128) << 32)
-                        | self.sdmmc.resp4r.read().bits() as u128;
-                    card.cid = CID::new(cid);
-
-                    // Get RCA
-                    self.cmd(Cmd::send_rel_addr())?;
-                    card.rca = self.sdmmc.resp1r.read().bits() >> 16;
-
-                    // Get CSD
-                    self.cmd(Cmd::send_csd(card.rca << 16))?;
-                    let csd = ((self.sdmmc.resp1r.read().bits() as u128) << 96)
-                        | ((self.sdmmc.resp2r.read().bits() as u128) << 64)
-                        | ((self.sdmmc.resp3r.read().bits() as u128) << 32)
-                        | self.sdmmc.resp4r.read().bits() as u128;
-                    card.csd = CSD(csd);
-
-                    self.select_card(Some(&card))?;
-
-                    self.get_scr(&mut card)?;
-
-                    // Set bus width
-                    let (width, acmd_arg) = match self.bus_width {
-                        BusWidth::Eight => unimplemented!(),
-                        BusWidth::Four if card.scr.bus_width_four() => (BusWidth::Four, 2),
-                        _ => (BusWidth::One, 0),
-                    };
-                    self.cmd(Cmd::app_cmd(card.rca << 16))?;
-                    self.cmd(Cmd::cmd6(acmd_arg))?; // ACMD6: Bus Width
-
-                    // CPSMACT and DPSMACT must be 0 to set WIDBUS
-                    while self.sdmmc.star.read().dpsmact().bit_is_set()
-                        || self.sdmmc.star.read().cpsmact().bit_is_set()
-                    {}
-                    self.sdmmc.clkcr.modify(|_, w| unsafe {
-                        w.widbus().bits(match width {
-                            BusWidth::One => 0,
-                            BusWidth::Four => 1,
-                            BusWidth::Eight => 2,
-                        })
-                    });
+    /// Power cycle the card. The card is powered down for at
+    /// least 1ms, as required by PLSS v7_10 Section 6.4.1.2
+    fn power_cycle(&mut self) {
+        self.sdmmc.modify(Register::Power, |r| {
+            (r & !power::PWRCTRL) | PowerCtrl::Cycle as u32
+        });
+        self.delay_ms(2);
+        self.sdmmc.modify(Register::Power, |r| {
+            (r & !power::PWRCTRL) | PowerCtrl::On as u32
+        });
+        self.delay_ms(1);
+
+        self.signalling_1v8 = false;
+        self.signalling = Signalling::SDR12;
+    }
 
-                    // Set Clock
-                    if freq.0 <= 25_000_000 {
-                        // Final clock frequency
-                        self.clkcr_set_clkdiv(freq.0, width)?;
-                    } else {
-                        // Switch to max clock for SDR12
-                        self.clkcr_set_clkdiv(25_000_000, width)?;
+    /// Busy wait for at least `ms` milliseconds
+    fn delay_ms(&self, ms: u32) {
+        let cycles = (self.c_ck.0 / 1000) * ms;
 
-                        sdmmc_trace!("Set intermediate clock frequency of 25MHz (SDR12 signalling");
-                    }
+        #[cfg(target_arch = "arm")]
+        cortex_m::asm::delay(cycles);
+        // No need to wait for a simulated card
+        #[cfg(not(target_arch = "arm"))]
+        let _ = cycles;
+    }
 
-                    // Read status
-                    let _old = self.card.replace(card);
-                    self.read_sd_status()?;
+    /// Number of reads of STAR that take at least `ms`
+    /// milliseconds. Each read takes at least one core clock cycle
+    fn star_reads(&self, ms: u32) -> u32 {
+        (self.c_ck.0 / 1000).saturating_mul(ms)
+    }
 
-                    if freq.0 > 25_000_000 {
-                        // Switch to SDR25
-                        self.signalling = self.switch_signalling_mode(Signalling::SDR25)?;
+    /// Initializes a MMC / eMMC device and sets the bus at the
+    /// specified frequency. Called from `init_card` once the
+    /// device has failed to respond to the SD initialisation
+    /// sequence.
+    fn init_emmc(&mut self, freq: Hertz) -> Result<(), Error> {
+        let mut card = Card {
+            card_type: CardType::EMMC,
+            ..Card::default()
+        };
+
+        self.card_type = CardType::EMMC;
+        self.cmd(Cmd::idle())?;
+
+        // Power up takes at most 1s. See JESD84-B51 Section 6.4.2
+        let mut timeout_ms = 1000;
+        let ocr = loop {
+            // Request sector addressing, 2.7 - 3.6V
+            match self.cmd(Cmd::send_op_cond(0x40FF_8080)) {
+                // CMD1. R3 response has no CRC
+                Ok(_) => (),
+                Err(e) if e.kind() == ErrorKind::Crc => (),
+                Err(err) => return Err(err),
+            }
+            let ocr = OCR(self.sdmmc.read(Register::Resp1r));
+            if !ocr.is_busy() {
+                // Power up done
+                break ocr;
+            }
+            if timeout_ms == 0 {
+                return Err(ErrorKind::SoftwareTimeout.into());
+            }
+            timeout_ms -= 1;
+            self.delay_ms(1);
+        };
+        card.ocr = ocr;
+
+        // Get CID
+        self.cmd(Cmd::all_send_cid())?; // CMD2
+        card.cid = CID::new(self.long_response());
+
+        // Set RCA. For MMC devices this is assigned by the host
+        card.rca = 1;
+        self.cmd(Cmd::set_rel_addr(card.rca << 16))?; // CMD3
+
+        // Get CSD
+        self.cmd(Cmd::send_csd(card.rca << 16))?;
+        card.csd = CSD(self.long_response());
+
+        self.select_card(Some(&card))?;
+
+        self.read_ext_csd(&mut card)?;
+        let timeout_ms = card
+            .ext_csd
+            .generic_cmd6_time_ms()
+            .unwrap_or(MMC_SWITCH_TIMEOUT_MS);
+
+        // Erases are in high-capacity erase groups, with the timeouts
+        // from the Extended CSD. ERASE_GROUP_DEF was added in MMC 4.3
+        // (EXT_CSD_REV 3). See JESD84-B51 Section 7.4.103
+        if card.ext_csd.revision() >= 3 && !card.ext_csd.erase_group_def() {
+            self.mmc_switch(&card, EXT_CSD_ERASE_GROUP_DEF, 1, timeout_ms)?;
+            self.read_ext_csd(&mut card)?;
+        }
 
-                        if self.signalling == Signalling::SDR25 {
-                            self.clkcr_set_clkdiv(freq.0, width)?;
+        // Set bus width. See JESD84-B51 Section 7.4.67
+        let (width, ext_csd_value) = match self.bus_width {
+            BusWidth::Eight => (BusWidth::Eight, 2),
+            BusWidth::Four => (BusWidth::Four, 1),
+            BusWidth::One => (BusWidth::One, 0),
+        };
+        self.mmc_switch(&card, EXT_CSD_BUS_WIDTH, ext_csd_value, timeout_ms)?;
+        self.clkcr_set_widbus(width);
 
-                            if self.send_status()? != CardStatus::Transfer {
-                                return Err(Error::SignalingSwitchFailed);
-                            }
-                        }
+        // Set Clock. Legacy MMC timing is up to 26MHz
+        self.clkcr_set_clkdiv(freq.0.min(26_000_000), width)?;
 
-                        sdmmc_trace!("Set final clock frequency of {}", freq.0);
-                    }
+        let _old = self.card.replace(card);
 
-                    Ok(())
-                }
+        Ok(())
+    }
 
-                /// Get a reference to the initialized card
-                ///
-                /// # Errors
-                ///
-                /// Returns Error::NoCard if [`init_card`](#method.init_card)
-                /// has not previously succeeded
-                pub fn card(&self) -> Result<&Card, Error> {
-                    self.card.as_ref().ok_or(Error::NoCard)
-                }
+    /// Get the receive clock sampling delay found by
+    /// [`tune`](#method.tune), if any
+    pub fn sample_delay(&self) -> Option<SampleDelay> {
+        self.sample_delay
+    }
 
-                /// Get the current SDMMC bus clock
-                ///
-                pub fn clock(&self) -> Hertz {
-                    self.clock
+    /// Tune the sampling point of the receive clock at the current
+    /// bus clock (CMD19).
+    ///
+    /// The DLYB delay line is calibrated to span one SDMMC_CK
+    /// period. Then each output clock phase is tested by reading
+    /// the tuning block, and the center of the widest passing
+    /// window is selected. The selected delay is re-applied after
+    /// any subsequent change to the bus clock.
+    ///
+    /// # Errors
+    ///
+    /// Returns ErrorKind::NoDelayBlock if
+    /// [`set_delay_block`](#method.set_delay_block) has not been
+    /// called, and ErrorKind::TuningFailed if no phase passes
+    pub fn tune(&mut self) -> Result<SampleDelay, Error> {
+        if !self.dlyb {
+            return Err(ErrorKind::NoDelayBlock.into());
+        }
+        let _card = self.card()?;
+
+        let (unit, phases) = self.dlyb_calibrate()?;
+
+        // Find the widest window of passing phases
+        let (mut best_start, mut best_len) = (0, 0);
+        let (mut start, mut len) = (0, 0);
+        for sel in 0..=phases {
+            self.set_sample_delay(SampleDelay { unit, sel });
+
+            if self.tuning_block_passes()? {
+                if len == 0 {
+                    start = sel;
+                }
+                len += 1;
+                if len > best_len {
+                    best_start = start;
+                    best_len = len;
                 }
+            } else {
+                len = 0;
+            }
+        }
 
-                /// Start a transfer
-                fn start_datapath_transfer(
-                    &self,
-                    length_bytes: u32,
-                    block_size: u8,
-                    direction: Dir,
-                ) {
-                    // Block Size up to 2^14 bytes
-                    assert!(block_size <= 14);
-
-                    // Block Size must be greater than 0 ( != 1 byte) in DDR mode
-                    let ddr = self.sdmmc.clkcr.read().ddr().bit_is_set();
-                    assert!(
-                        !ddr || block_size != 0,
-                        "Block size must be >= 1, or >= 2 is DDR mode"
-                    );
-
-                    let dtdir = match direction {
-                        Dir::CardToHost => true,
-                        _ => false,
-                    };
+        if best_len == 0 {
+            self.sample_delay = None;
+            return Err(ErrorKind::TuningFailed.into());
+        }
+
+        let delay = SampleDelay {
+            unit,
+            sel: best_start + best_len / 2,
+        };
+        sdmmc_trace!("Tuned sampling point: {:?}", delay);
+
+        self.set_sample_delay(delay);
+        self.sample_delay = Some(delay);
+        Ok(delay)
+    }
+
+    /// Calibrate the DLYB delay line length to one SDMMC_CK
+    /// period. Returns the UNIT delay, and the number of output
+    /// clock phases that span one period.
+    ///
+    /// See RM0433 Rev 7 Section 25.4.4
+    fn dlyb_calibrate(&self) -> Result<(u8, u8), Error> {
+        // Ownership checked by caller
+        self.sdmmc
+            .write(Register::DlybCr, dlyb_cr::DEN | dlyb_cr::SEN);
+
+        for unit in 0..128 {
+            self.sdmmc.write(
+                Register::DlybCfgr,
+                12 | u32::from(unit) << dlyb_cfgr::UNIT_SHIFT,
+            );
+            while self.sdmmc.read(Register::DlybCfgr) & dlyb_cfgr::LNGF == 0 {}
+
+            // Delay line spans one period when a transition is
+            // found in LNG[10:0], and LNG[11:10] are not both set
+            let lng = (self.sdmmc.read(Register::DlybCfgr) & dlyb_cfgr::LNG)
+                >> dlyb_cfgr::LNG_SHIFT;
+            if lng > 0 && lng < 0xC00 {
+                let mut phases = 10;
+                while phases > 0 && (lng >> phases) == 0 {
+                    phases -= 1;
+                }
+
+                self.sdmmc.write(Register::DlybCr, dlyb_cr::DEN);
+                return if phases > 0 {
+                    Ok((unit, phases))
+                } else {
+                    Err(ErrorKind::TuningFailed.into())
+                };
+            }
+        }
+
+        self.sdmmc.write(Register::DlybCr, 0);
+        Err(ErrorKind::TuningFailed.into())
+    }
+
+    /// Configure the DLYB delay block, and select its output as
+    /// the receive clock
+    fn set_sample_delay(&self, delay: SampleDelay) {
+        // Ownership checked by caller. SEN must be set while the
+        // configuration is changed
+        self.sdmmc
+            .write(Register::DlybCr, dlyb_cr::DEN | dlyb_cr::SEN);
+        self.sdmmc.write(
+            Register::DlybCfgr,
+            u32::from(delay.sel)
+                | u32::from(delay.unit) << dlyb_cfgr::UNIT_SHIFT,
+        );
+        self.sdmmc.write(Register::DlybCr, dlyb_cr::DEN);
+
+        // CPSMACT and DPSMACT must be 0 to set SELCLKRX
+        while self.sdmmc.read(Register::Star) & (star::DPSMACT | star::CPSMACT)
+            != 0
+        {}
+        // Receive clock is sdmmc_fb_ck
+        self.sdmmc.modify(Register::Clkcr, |r| {
+            (r & !clkcr::SELCLKRX) | 0b10 << clkcr::SELCLKRX_SHIFT
+        });
+    }
+
+    /// Read the tuning block (CMD19), and compare it against the
+    /// expected pattern
+    fn tuning_block_passes(&self) -> Result<bool, Error> {
+        // Prepare the transfer
+        self.start_datapath_transfer(64, 6, Dir::CardToHost);
+        match self.cmd(Cmd::send_tuning_block()) {
+            // CMD19
+            Ok(_) => (),
+            Err(e)
+                if e.kind() == ErrorKind::Crc
+                    || e.kind() == ErrorKind::Timeout =>
+            {
+                self.reset_datapath();
+                return Ok(false);
+            }
+            Err(e) => return Err(e),
+        }
+
+        let mut block = [0u32; 16];
+        let mut idx = 0;
+        let mut sta_reg;
+        while {
+            sta_reg = self.sdmmc.read(Register::Star);
+            sta_reg
+                & (star::RXOVERR
+                    | star::DCRCFAIL
+                    | star::DTIMEOUT
+                    | star::DATAEND)
+                == 0
+        } {
+            if sta_reg & star::RXFIFOHF != 0 && idx < block.len() {
+                for _ in 0..8 {
+                    block[idx] = self.sdmmc.read(Register::Fifor);
+                    idx += 1;
+                }
+            }
+        }
+
+        if sta_reg & (star::RXOVERR | star::DCRCFAIL | star::DTIMEOUT) != 0 {
+            self.reset_datapath();
+            return Ok(false);
+        }
+
+        // Drain the words left in the FIFO after DATAEND, then clear the
+        // flags for the next sampling point
+        while idx < block.len()
+            && self.sdmmc.read(Register::Star) & star::RXFIFOE == 0
+        {
+            block[idx] = self.sdmmc.read(Register::Fifor);
+            idx += 1;
+        }
+        self.reset_datapath();
+
+        let passes = block
+            .iter()
+            .zip(TUNING_BLOCK_4BIT.chunks(4))
+            .all(|(word, pattern)| word.to_le_bytes() == pattern);
+        Ok(passes)
+    }
+
+    /// Wait for the data path state machine to become idle, then
+    /// flush the FIFO and clear the data path flags
+    fn reset_datapath(&self) {
+        while self.sdmmc.read(Register::Star) & star::DPSMACT != 0 {}
+
+        self.sdmmc.modify(Register::Dctrl, |r| r | dctrl::FIFORST);
+        self.sdmmc.modify(Register::Dctrl, |r| r & !dctrl::FIFORST);
+        self.clear_static_interrupt_flags();
+    }
+
+    /// Get a reference to the initialized card
+    ///
+    /// # Errors
+    ///
+    /// Returns ErrorKind::NoCard if [`init_card`](#method.init_card)
+    /// has not previously succeeded
+    pub fn card(&self) -> Result<&Card, Error> {
+        self.card.as_ref().ok_or_else(|| ErrorKind::NoCard.into())
+    }
+
+    /// Returns true if a card is in the slot. A change on the card detect
+    /// input is only accepted once it has been stable for the debounce
+    /// time.
+    ///
+    /// Always true if there is no card detect input
+    pub fn is_card_present(&mut self) -> bool {
+        let present = self.card_detect_input();
+        if present != self.cd_present {
+            for _ in 0..self.cd_debounce_ms {
+                self.delay_ms(1);
+                if self.card_detect_input() != present {
+                    return self.cd_present;
+                }
+            }
+            self.cd_present = present;
+        }
+        self.cd_present
+    }
+
+    /// Advance the card slot state from the card detect input, and return
+    /// the new state. Call this periodically to detect when a card is
+    /// inserted or removed.
+    ///
+    /// When the card is removed, it is forgotten and the bus is powered
+    /// down. Any non-blocking transfer in progress fails with
+    /// `ErrorKind::NoCard`
+    pub fn poll_card_detect(&mut self) -> SlotState {
+        let present = self.is_card_present();
+        self.slot = match self.slot {
+            SlotState::Inserted | SlotState::Initialized if !present => {
+                self.card_removed();
+                SlotState::Removed
+            }
+            SlotState::Absent | SlotState::Removed if present => {
+                SlotState::Inserted
+            }
+            SlotState::Removed => SlotState::Absent,
+            state => state,
+        };
+        self.slot
+    }
+
+    /// Get the current card slot state
+    pub fn slot_state(&self) -> SlotState {
+        self.slot
+    }
+
+    /// Card detect input, without debouncing
+    fn card_detect_input(&self) -> bool {
+        match self.card_detect.level() {
+            Some(high) => {
+                high == (self.cd_polarity == CardDetectPolarity::ActiveHigh)
+            }
+            None => true,
+        }
+    }
+
+    /// Handle the removal of the card, if the card detect input
+    /// shows that it has been removed
+    fn detect_removal(&mut self) {
+        if let SlotState::Inserted | SlotState::Initialized = self.slot {
+            if !self.is_card_present() {
+                self.card_removed();
+                self.slot = SlotState::Removed;
+            }
+        }
+    }
+
+    /// The card has been removed. Fail any non-blocking transfer
+    /// in progress, forget the card and power down the bus
+    fn card_removed(&mut self) {
+        if let TransferState::Start { .. }
+        | TransferState::Data { .. }
+        | TransferState::Stop { .. }
+        | TransferState::Busy { .. }
+        | TransferState::Failed(_) = self.transfer
+        {
+            self.abort_transfer();
+            self.transfer = TransferState::Done(Err(ErrorKind::NoCard.into()));
+        }
+        self.card = None;
+
+        self.sdmmc.modify(Register::Power, |r| {
+            (r & !power::PWRCTRL) | PowerCtrl::Off as u32
+        });
+    }
+
+    /// Set the retry policy for commands and data transfers
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.retry = policy;
+    }
+
+    /// Get the retry policy for commands and data transfers
+    pub fn retry_policy(&self) -> RetryPolicy {
+        self.retry
+    }
+
+    /// Get the retries made under the retry policy, since the
+    /// driver was created or the statistics were cleared
+    pub fn retry_stats(&self) -> RetryStats {
+        self.retry_stats.get()
+    }
+
+    /// Clear the retry statistics
+    pub fn clear_retry_stats(&mut self) {
+        self.retry_stats.set(RetryStats::default());
+    }
+
+    /// Get the current SDMMC bus clock
+    ///
+    pub fn clock(&self) -> Hertz {
+        self.clock
+    }
+
+    /// Get the current signalling scheme on the SDMMC bus
+    pub fn signalling(&self) -> Signalling {
+        self.signalling
+    }
+
+    /// Start a transfer
+    fn start_datapath_transfer(
+        &self,
+        length_bytes: u32,
+        block_size: u8,
+        direction: Dir,
+    ) {
+        // Block Size up to 2^14 bytes
+        assert!(block_size <= 14);
+
+        // Block Size must be greater than 0 ( != 1 byte) in DDR mode
+        let ddr = self.sdmmc.read(Register::Clkcr) & clkcr::DDR != 0;
+        assert!(
+            !ddr || block_size != 0,
+            "Block size must be >= 1, or >= 2 is DDR mode"
+        );
+
+        let dtdir = match direction {
+            Dir::CardToHost => dctrl::DTDIR,
+            _ => 0,
+        };
+
+        // Command AND Data state machines must be idle
+        while self.sdmmc.read(Register::Star) & (star::DPSMACT | star::CPSMACT)
+            != 0
+        {}
+
+        // Data timeout, in bus cycles
+        self.sdmmc.write(Register::Dtimer, 5_000_000);
+        // Data length, in bytes
+        self.sdmmc.write(Register::Dlenr, length_bytes);
+        // Transfer. 2^n bytes block size
+        self.sdmmc.write(
+            Register::Dctrl,
+            u32::from(block_size) << dctrl::DBLOCKSIZE_SHIFT
+                | dtdir
+                | dctrl::DTEN, // Enable transfer
+        );
+    }
+
+    /// Read block from card.
+    ///
+    /// `address` is the block address.
+    ///
+    /// Retried according to the `block_attempts` of the retry
+    /// policy. Fails with ErrorKind::NoCard if the card has been
+    /// removed, and with ErrorKind::Busy while a non-blocking
+    /// transfer is in progress.
+    pub fn read_block(
+        &mut self,
+        address: u32,
+        buffer: &mut [u8; 512],
+    ) -> Result<(), Error> {
+        self.retry_data(address, true, |sdmmc| {
+            sdmmc.read_block_attempt(address, buffer)
+        })
+    }
+
+    /// A single attempt at [`read_block`](#method.read_block)
+    fn read_block_attempt(
+        &mut self,
+        address: u32,
+        buffer: &mut [u8; 512],
+    ) -> Result<(), Error> {
+        let address = self.card()?.data_address(address)?;
+
+        self.cmd(Cmd::set_block_length(512))?; // CMD16
+
+        // Setup read command
+        self.start_datapath_transfer(512, 9, Dir::CardToHost);
+        self.data_cmd(Cmd::read_single_block(address))?;
+
+        let mut i = 0;
+        let mut status;
+        while {
+            status = self.sdmmc.read(Register::Star);
+            status
+                & (star::RXOVERR
+                    | star::DCRCFAIL
+                    | star::DTIMEOUT
+                    | star::DATAEND)
+                == 0
+        } {
+            if status & star::RXFIFOHF != 0 {
+                for _ in 0..8 {
+                    let bytes = self.sdmmc.read(Register::Fifor).to_le_bytes();
+                    buffer[i..i + 4].copy_from_slice(&bytes);
+                    i += 4;
+                }
+            }
+
+            if i >= buffer.len() {
+                break;
+            }
+        }
+
+        err_from_datapath_sm!(self, status);
+
+        Ok(())
+    }
+
+    /// Read mutliple blocks from card. The length of the buffer
+    /// must be multiple of 512.
+    ///
+    /// `address` is the block address.
+    ///
+    /// Retried according to the `transfer_attempts` of the retry
+    /// policy. Fails with ErrorKind::NoCard if the card has been
+    /// removed, and with ErrorKind::Busy while a non-blocking
+    /// transfer is in progress.
+    pub fn read_blocks(
+        &mut self,
+        address: u32,
+        buffer: &mut [u8],
+    ) -> Result<(), Error> {
+        self.retry_data(address, false, |sdmmc| {
+            sdmmc.read_blocks_attempt(address, buffer)
+        })
+    }
+
+    /// A single attempt at [`read_blocks`](#method.read_blocks)
+    fn read_blocks_attempt(
+        &mut self,
+        address: u32,
+        buffer: &mut [u8],
+    ) -> Result<(), Error> {
+        let address = self.card()?.data_address(address)?;
+
+        assert!(buffer.len() % 512 == 0);
+        let n_blocks = buffer.len() / 512;
+        self.cmd(Cmd::set_block_length(512))?; // CMD16
+
+        // Setup read command
+        self.start_datapath_transfer(512 * n_blocks as u32, 9, Dir::CardToHost);
+        self.data_cmd(Cmd::read_multiple_blocks(address))?;
+
+        let mut i = 0;
+        let mut status;
+        while {
+            status = self.sdmmc.read(Register::Star);
+            status
+                & (star::RXOVERR
+                    | star::DCRCFAIL
+                    | star::DTIMEOUT
+                    | star::DATAEND)
+                == 0
+        } {
+            if status & star::RXFIFOHF != 0 {
+                for _ in 0..8 {
+                    let bytes = self.sdmmc.read(Register::Fifor).to_le_bytes();
+                    buffer[i..i + 4].copy_from_slice(&bytes);
+                    i += 4;
+                }
+            }
+
+            if i >= buffer.len() {
+                break;
+            }
+        }
+
+        self.stop_data_transfer(status)
+    }
+
+    /// Write block to card. Buffer must be 512 bytes
+    ///
+    /// Retried according to the `block_attempts` of the retry
+    /// policy. Fails with ErrorKind::NoCard if the card has been
+    /// removed, and with ErrorKind::Busy while a non-blocking
+    /// transfer is in progress.
+    pub fn write_block(
+        &mut self,
+        address: u32,
+        buffer: &[u8; 512],
+    ) -> Result<(), Error> {
+        self.retry_data(address, true, |sdmmc| {
+            sdmmc.write_block_attempt(address, buffer)
+        })
+    }
+
+    /// A single attempt at [`write_block`](#method.write_block)
+    fn write_block_attempt(
+        &mut self,
+        address: u32,
+        buffer: &[u8; 512],
+    ) -> Result<(), Error> {
+        let address = self.card()?.data_address(address)?;
+
+        self.cmd(Cmd::set_block_length(512))?; // CMD16
+
+        // Setup write command
+        self.start_datapath_transfer(512, 9, Dir::HostToCard);
+        self.data_cmd(Cmd::write_single_block(address))?; // CMD24
+
+        let mut i = 0;
+        let mut status;
+        while {
+            status = self.sdmmc.read(Register::Star);
+            status
+                & (star::TXUNDERR
+                    | star::DCRCFAIL
+                    | star::DTIMEOUT
+                    | star::DATAEND)
+                == 0
+        } {
+            if i < buffer.len() && status & star::TXFIFOHE != 0 {
+                for _ in 0..8 {
+                    let mut wb = [0u8; 4];
+                    wb.copy_from_slice(&buffer[i..i + 4]);
+                    let word = u32::from_le_bytes(wb);
+                    self.sdmmc.write(Register::Fifor, word);
+                    i += 4;
+                }
+            }
+        }
+
+        err_from_datapath_sm!(self, status);
+        self.clear_static_interrupt_flags();
+
+        let card = self.card()?;
+        let card_type = card.card_type;
+
+        // Each poll takes at least a command and its response, 96 bus
+        // cycles, so this many polls last the whole write timeout
+        let polls = self.busy_cycles(card.write_timeout_ms()) / 96;
+
+        // Try to read card status (ACMD13)
+        for _ in 0..=polls {
+            let r = match card_type {
+                // MMC devices have no SD Status. Wait for the
+                // device to return to the Transfer State instead
+                CardType::EMMC => match self.send_status() {
+                    Ok(r1) if r1.current_state() == CardState::Transfer => {
+                        Ok(())
+                    }
+                    Ok(_) => Err(ErrorKind::Timeout.into()),
+                    Err(e) => Err(e),
+                },
+                _ => self.read_sd_status(),
+            };
+            match r {
+                Ok(_) => return Ok(()),
+                Err(e) if e.kind() == ErrorKind::Timeout => (), // Try again
+                Err(e) => return Err(e),
+            }
+        }
+        Err(self.error(ErrorKind::Timeout, Phase::Busy, 0))
+    }
+
+    /// Write multiple blocks to card. The length of the buffer
+    /// must be multiple of 512.
+    ///
+    /// `address` is the block address.
+    ///
+    /// For SD cards the number of blocks is first sent with
+    /// ACMD23, so that the card can pre-erase them
+    ///
+    /// Retried according to the `transfer_attempts` of the retry
+    /// policy. Fails with ErrorKind::NoCard if the card has been
+    /// removed, and with ErrorKind::Busy while a non-blocking
+    /// transfer is in progress.
+    pub fn write_blocks(
+        &mut self,
+        address: u32,
+        buffer: &[u8],
+    ) -> Result<(), Error> {
+        self.retry_data(address, false, |sdmmc| {
+            sdmmc.write_blocks_attempt(address, buffer)
+        })
+    }
+
+    /// A single attempt at [`write_blocks`](#method.write_blocks)
+    fn write_blocks_attempt(
+        &mut self,
+        address: u32,
+        buffer: &[u8],
+    ) -> Result<(), Error> {
+        let address = self.card()?.data_address(address)?;
+
+        assert!(buffer.len() % 512 == 0);
+        let n_blocks = buffer.len() / 512;
+        self.cmd(Cmd::set_block_length(512))?; // CMD16
+
+        self.pre_erase(n_blocks as u32)?;
+
+        // Setup write command
+        self.start_datapath_transfer(512 * n_blocks as u32, 9, Dir::HostToCard);
+        self.data_cmd(Cmd::write_multiple_blocks(address))?; // CMD25
+
+        let mut i = 0;
+        let mut status;
+        while {
+            status = self.sdmmc.read(Register::Star);
+            status
+                & (star::TXUNDERR
+                    | star::DCRCFAIL
+                    | star::DTIMEOUT
+                    | star::DATAEND)
+                == 0
+        } {
+            if i < buffer.len() && status & star::TXFIFOHE != 0 {
+                for _ in 0..8 {
+                    let mut wb = [0u8; 4];
+                    wb.copy_from_slice(&buffer[i..i + 4]);
+                    let word = u32::from_le_bytes(wb);
+                    self.sdmmc.write(Register::Fifor, word);
+                    i += 4;
+                }
+            }
+        }
+
+        self.stop_data_transfer(status)?;
+        self.clear_static_interrupt_flags();
+
+        // Wait for the card to finish programming
+        self.wait_busy_d0()
+    }
+
+    /// Send the number of blocks about to be written with
+    /// ACMD23, so that the card can pre-erase them. MMC devices
+    /// don't have ACMD23
+    fn pre_erase(&self, n_blocks: u32) -> Result<(), Error> {
+        let card = self.card()?;
+
+        match card.card_type {
+            CardType::EMMC => Ok(()),
+            _ => {
+                self.cmd(Cmd::app_cmd(card.rca << 16))?; // APP
+                self.cmd(Cmd::set_wr_blk_erase_count(n_blocks)) // ACMD23
+            }
+        }
+    }
+
+    /// Read multiple blocks from card using the internal DMA
+    /// (IDMA). The length of the buffer must be multiple of 512.
+    ///
+    /// `address` is the block address.
+    ///
+    /// The buffer must be word aligned, and in memory that can
+    /// be accessed by the IDMA of this SDMMC instance, otherwise
+    /// ErrorKind::BadBuffer is returned. The D-cache is not
+    /// maintained: if the buffer is in cacheable memory, it must
+    /// be invalidated after the read.
+    ///
+    /// Retried according to the `transfer_attempts` of the retry
+    /// policy. Fails with ErrorKind::Busy while a non-blocking
+    /// transfer is in progress.
+    pub fn read_blocks_dma(
+        &mut self,
+        address: u32,
+        buffer: &mut [u8],
+    ) -> Result<(), Error> {
+        self.retry_data(address, false, |sdmmc| {
+            sdmmc.read_blocks_dma_attempt(address, buffer)
+        })
+    }
+
+    /// A single attempt at [`read_blocks_dma`](#method.read_blocks_dma)
+    fn read_blocks_dma_attempt(
+        &mut self,
+        address: u32,
+        buffer: &mut [u8],
+    ) -> Result<(), Error> {
+        let address = self.card()?.data_address(address)?;
+
+        assert!(buffer.len() % 512 == 0);
+        Self::idma_check_buffer(buffer)?;
+        self.cmd(Cmd::set_block_length(512))?; // CMD16
+
+        self.idma_transfer(
+            Cmd::read_multiple_blocks(address), // CMD18
+            buffer.as_mut_ptr() as u32,
+            buffer.len() as u32,
+            Dir::CardToHost,
+        )
+    }
+
+    /// Write multiple blocks to card using the internal DMA
+    /// (IDMA). The length of the buffer must be multiple of 512.
+    ///
+    /// `address` is the block address.
+    ///
+    /// The buffer must be word aligned, and in memory that can
+    /// be accessed by the IDMA of this SDMMC instance, otherwise
+    /// ErrorKind::BadBuffer is returned. The D-cache is not
+    /// maintained: if the buffer is in cacheable memory, it must
+    /// be cleaned before the write.
+    ///
+    /// Retried according to the `transfer_attempts` of the retry
+    /// policy. Fails with ErrorKind::Busy while a non-blocking
+    /// transfer is in progress.
+    pub fn write_blocks_dma(
+        &mut self,
+        address: u32,
+        buffer: &[u8],
+    ) -> Result<(), Error> {
+        self.retry_data(address, false, |sdmmc| {
+            sdmmc.write_blocks_dma_attempt(address, buffer)
+        })
+    }
+
+    /// A single attempt at [`write_blocks_dma`](#method.write_blocks_dma)
+    fn write_blocks_dma_attempt(
+        &mut self,
+        address: u32,
+        buffer: &[u8],
+    ) -> Result<(), Error> {
+        let address = self.card()?.data_address(address)?;
+
+        assert!(buffer.len() % 512 == 0);
+        Self::idma_check_buffer(buffer)?;
+        self.cmd(Cmd::set_block_length(512))?; // CMD16
+        self.pre_erase(buffer.len() as u32 / 512)?;
+
+        self.idma_transfer(
+            Cmd::write_multiple_blocks(address), // CMD25
+            buffer.as_ptr() as u32,
+            buffer.len() as u32,
+            Dir::HostToCard,
+        )?;
+
+        // Wait for the card to finish programming
+        self.wait_busy_d0()
+    }
+
+    /// Read `n_blocks` blocks from card as a continuous stream,
+    /// using the internal DMA (IDMA) in double buffer mode.
+    ///
+    /// `address` is the block address. `buffer0` and `buffer1`
+    /// must be the same length: a multiple of 512 bytes, and at
+    /// most 7680 bytes. `n_blocks * 512` must be a multiple of
+    /// this length, and less than 32MB.
+    ///
+    /// `consume` is called with each buffer once it has been
+    /// filled by the IDMA, while the other buffer is in
+    /// flight. It must return before the other buffer is full.
+    ///
+    /// The buffers must be word aligned, and in memory that can
+    /// be accessed by the IDMA of this SDMMC instance, otherwise
+    /// ErrorKind::BadBuffer is returned. The D-cache is not
+    /// maintained: if the buffers are in cacheable memory,
+    /// `consume` must invalidate the buffer first.
+    ///
+    /// Fails with ErrorKind::Busy while a non-blocking transfer
+    /// is in progress.
+    pub fn read_blocks_stream<F>(
+        &mut self,
+        address: u32,
+        n_blocks: u32,
+        buffer0: &mut [u8],
+        buffer1: &mut [u8],
+        mut consume: F,
+    ) -> Result<(), Error>
+    where
+        F: FnMut(&[u8]),
+    {
+        self.check_no_transfer().map_err(|e| e.at(address))?;
+        let arg = self.card()?.data_address(address)?;
+
+        let n_chunks =
+            Self::idma_check_double_buffer(n_blocks, buffer0, buffer1)?;
+        self.cmd(Cmd::set_block_length(512))?; // CMD16
+
+        self.idma_stream(
+            Cmd::read_multiple_blocks(arg), // CMD18
+            n_chunks,
+            buffer0,
+            buffer1,
+            Dir::CardToHost,
+            &mut |buffer: &mut [u8]| consume(buffer),
+        )
+        .map_err(|e| e.at(address))
+    }
+
+    /// Write `n_blocks` blocks to card as a continuous stream,
+    /// using the internal DMA (IDMA) in double buffer mode.
+    ///
+    /// `address` is the block address. `buffer0` and `buffer1`
+    /// must be the same length: a multiple of 512 bytes, and at
+    /// most 7680 bytes. `n_blocks * 512` must be a multiple of
+    /// this length, and less than 32MB.
+    ///
+    /// `fill` is called to fill each buffer before it is written:
+    /// for both buffers before the transfer starts, and then each
+    /// time the IDMA has finished with a buffer while the other
+    /// buffer is in flight. It must return before the other
+    /// buffer is empty.
+    ///
+    /// The buffers must be word aligned, and in memory that can
+    /// be accessed by the IDMA of this SDMMC instance, otherwise
+    /// ErrorKind::BadBuffer is returned. The D-cache is not
+    /// maintained: if the buffers are in cacheable memory, `fill`
+    /// must clean the buffer before returning.
+    ///
+    /// Fails with ErrorKind::Busy while a non-blocking transfer
+    /// is in progress.
+    pub fn write_blocks_stream<F>(
+        &mut self,
+        address: u32,
+        n_blocks: u32,
+        buffer0: &mut [u8],
+        buffer1: &mut [u8],
+        mut fill: F,
+    ) -> Result<(), Error>
+    where
+        F: FnMut(&mut [u8]),
+    {
+        self.check_no_transfer().map_err(|e| e.at(address))?;
+        let arg = self.card()?.data_address(address)?;
+
+        let n_chunks =
+            Self::idma_check_double_buffer(n_blocks, buffer0, buffer1)?;
+        self.cmd(Cmd::set_block_length(512))?; // CMD16
+        self.pre_erase(n_blocks)?;
+
+        fill(buffer0);
+        if n_chunks > 1 {
+            fill(buffer1);
+        }
+
+        self.idma_stream(
+            Cmd::write_multiple_blocks(arg), // CMD25
+            n_chunks,
+            buffer0,
+            buffer1,
+            Dir::HostToCard,
+            &mut fill,
+        )
+        .and_then(|_| self.wait_busy_d0()) // Wait for programming
+        .map_err(|e| e.at(address))
+    }
+
+    /// Check a pair of buffers for a double buffer IDMA transfer
+    /// of `n_blocks`. Returns the number of buffers to transfer
+    fn idma_check_double_buffer(
+        n_blocks: u32,
+        buffer0: &[u8],
+        buffer1: &[u8],
+    ) -> Result<u32, Error> {
+        let size = buffer0.len() as u32;
+        assert!(size == buffer1.len() as u32);
+        // IDMABNDT is 8 bits, in units of 32 bytes
+        assert!(size > 0 && size % 512 == 0 && size <= 15 * 512);
+        // DATALENGTH is 25 bits
+        assert!(n_blocks > 0 && n_blocks < 0x1_0000);
+        assert!((n_blocks * 512) % size == 0);
+
+        Self::idma_check_buffer(buffer0)?;
+        Self::idma_check_buffer(buffer1)?;
+
+        Ok(n_blocks * 512 / size)
+    }
+
+    /// Send a multiple block command `cmd` and transfer
+    /// `n_chunks` buffers with the IDMA in double buffer mode,
+    /// alternating between `buffer0` and `buffer1`. Then stop
+    /// the transmission (CMD12).
+    ///
+    /// `callback` is called with each buffer when the IDMA has
+    /// finished with it. When writing, it is only called for
+    /// buffers that will be written again
+    fn idma_stream(
+        &mut self,
+        cmd: Cmd,
+        n_chunks: u32,
+        buffer0: &mut [u8],
+        buffer1: &mut [u8],
+        direction: Dir,
+        callback: &mut dyn FnMut(&mut [u8]),
+    ) -> Result<(), Error> {
+        let size = buffer0.len() as u32;
+        let read = match direction {
+            Dir::CardToHost => true,
+            _ => false,
+        };
+
+        self.sdmmc
+            .write(Register::Idmabase0r, buffer0.as_ptr() as u32);
+        self.sdmmc
+            .write(Register::Idmabase1r, buffer1.as_ptr() as u32);
+        self.sdmmc.write(
+            Register::Idmabsizer,
+            (size / 32) << idmabsizer::IDMABNDT_SHIFT,
+        );
+        // Double buffer mode, starting with buffer 0
+        self.sdmmc.write(
+            Register::Idmactrlr,
+            idmactrlr::IDMAEN | idmactrlr::IDMABMODE,
+        );
+
+        // Buffer accesses must not be reordered across the transfer
+        compiler_fence(Ordering::SeqCst);
+
+        // Called when the IDMA has finished with buffer `n`
+        let mut complete = |n: u32| {
+            compiler_fence(Ordering::SeqCst);
+            if read || n + 2 < n_chunks {
+                let buffer = if n % 2 == 0 {
+                    &mut *buffer0
+                } else {
+                    &mut *buffer1
+                };
+                callback(buffer);
+            }
+            compiler_fence(Ordering::SeqCst);
+        };
+
+        self.start_datapath_transfer(n_chunks * size, 9, direction);
+        let result = self.data_cmd(cmd).and_then(|_| {
+            let mut done = 0;
+            let mut status;
+            while {
+                status = self.sdmmc.read(Register::Star);
+                status & (star::DATA_ERRORS | star::IDMATE | star::DATAEND) == 0
+            } {
+                if status & star::IDMABTC != 0 {
+                    self.sdmmc.write(Register::Icr, star::IDMABTC);
+                    complete(done);
+                    done += 1;
+                }
+            }
+
+            self.stop_data_transfer(status)?;
+
+            // The last buffers complete with DATAEND
+            while done < n_chunks {
+                complete(done);
+                done += 1;
+            }
+            Ok(())
+        });
+
+        self.sdmmc.write(Register::Idmactrlr, 0);
+        compiler_fence(Ordering::SeqCst);
+        self.clear_static_interrupt_flags();
+
+        result
+    }
+
+    /// Start reading multiple blocks from card using the
+    /// internal DMA (IDMA), and return without waiting for the
+    /// transfer to complete. The length of the buffer must be
+    /// multiple of 512.
+    ///
+    /// `address` is the block address.
+    ///
+    /// The transfer is advanced by
+    /// [`on_interrupt`](#method.on_interrupt). Use `poll` or
+    /// `wait` on the returned `Transfer` to get the result and
+    /// the buffer back. The data transfer and erase methods
+    /// fail with ErrorKind::Busy while the transfer is in
+    /// progress, and so does another non-blocking transfer.
+    ///
+    /// The buffer has the same requirements as for
+    /// [`read_blocks_dma`](#method.read_blocks_dma).
+    pub fn start_read_blocks(
+        &mut self,
+        address: u32,
+        buffer: &'static mut [u8],
+    ) -> Transfer<S> {
+        let error = self
+            .start_transfer(address, buffer, Dir::CardToHost)
+            .err()
+            .map(|e| e.at(address));
+
+        Transfer {
+            buffer,
+            error,
+            _sdmmc: PhantomData,
+        }
+    }
+
+    /// Start writing multiple blocks to card using the internal
+    /// DMA (IDMA), and return without waiting for the transfer
+    /// to complete. The length of the buffer must be multiple of
+    /// 512.
+    ///
+    /// `address` is the block address.
+    ///
+    /// The transfer is advanced by
+    /// [`on_interrupt`](#method.on_interrupt). Use `poll` or
+    /// `wait` on the returned `Transfer` to get the result and
+    /// the buffer back. The data transfer and erase methods
+    /// fail with ErrorKind::Busy while the transfer is in
+    /// progress, and so does another non-blocking transfer.
+    ///
+    /// The buffer has the same requirements as for
+    /// [`write_blocks_dma`](#method.write_blocks_dma).
+    pub fn start_write_blocks(
+        &mut self,
+        address: u32,
+        buffer: &'static mut [u8],
+    ) -> Transfer<S> {
+        let error = self
+            .start_transfer(address, buffer, Dir::HostToCard)
+            .err()
+            .map(|e| e.at(address));
+
+        Transfer {
+            buffer,
+            error,
+            _sdmmc: PhantomData,
+        }
+    }
+
+    #[cfg(any(target_arch = "arm", test))]
+    /// Read multiple blocks asynchronously, woken by `waker`. See
+    /// `read_blocks_async`
+    pub(crate) async fn read_blocks_waker(
+        &mut self,
+        address: u32,
+        buffer: &'static mut [u8],
+        waker: &WakerSlot,
+    ) -> (&'static mut [u8], Result<(), Error>) {
+        if let Err(e) = self.start_transfer(address, buffer, Dir::CardToHost) {
+            return (buffer, Err(e.at(address)));
+        }
+
+        let result = TransferFuture { sdmmc: self, waker }.await;
+        (buffer, result)
+    }
+
+    #[cfg(any(target_arch = "arm", test))]
+    /// Write multiple blocks asynchronously, woken by `waker`. See
+    /// `write_blocks_async`
+    pub(crate) async fn write_blocks_waker(
+        &mut self,
+        address: u32,
+        buffer: &'static [u8],
+        waker: &WakerSlot,
+    ) -> Result<(), Error> {
+        self.start_transfer(address, buffer, Dir::HostToCard)
+            .map_err(|e| e.at(address))?;
+
+        TransferFuture { sdmmc: self, waker }.await
+    }
+
+    /// Fails with ErrorKind::Busy if a non-blocking transfer is in
+    /// progress
+    fn check_no_transfer(&self) -> Result<(), Error> {
+        match self.transfer {
+            TransferState::Idle | TransferState::Done(_) => Ok(()),
+            _ => Err(ErrorKind::Busy.into()),
+        }
+    }
+
+    /// Start a non-blocking multiple block transfer: start the
+    /// IDMA, send the first command and enable the interrupts.
+    /// The following commands are sent by
+    /// [`on_interrupt`](#method.on_interrupt)
+    fn start_transfer(
+        &mut self,
+        address: u32,
+        buffer: &[u8],
+        direction: Dir,
+    ) -> Result<(), Error> {
+        self.check_no_transfer()?;
+        let arg = self.card()?.data_address(address)?;
+
+        assert!(buffer.len() % 512 == 0);
+        Self::idma_check_buffer(buffer)?;
+
+        // Single buffer mode
+        self.sdmmc
+            .write(Register::Idmabase0r, buffer.as_ptr() as u32);
+        self.sdmmc.write(Register::Idmactrlr, idmactrlr::IDMAEN);
+
+        // Buffer accesses must not be reordered across the transfer
+        compiler_fence(Ordering::SeqCst);
+
+        let write = match direction {
+            Dir::HostToCard => true,
+            Dir::CardToHost => false,
+        };
+        let blocks = buffer.len() as u32 / 512;
+        self.transfer = self.next_start_cmd(0, write, address, arg, blocks);
+        self.enable_transfer_interrupts();
+        Ok(())
+    }
+
+    /// Send the command that follows command `prev` in starting
+    /// a non-blocking transfer, without waiting for the response.
+    /// `prev` is zero before the first command. Returns the next
+    /// state of the transfer
+    fn next_start_cmd(
+        &self,
+        prev: u8,
+        write: bool,
+        address: u32,
+        arg: u32,
+        blocks: u32,
+    ) -> TransferState {
+        let rca = match self.card() {
+            Ok(card) => card.rca,
+            Err(e) => {
+                self.sdmmc.write(Register::Idmactrlr, 0);
+                return TransferState::Done(Err(e.at(address)));
+            }
+        };
+        let emmc = match self.card_type {
+            CardType::EMMC => true,
+            _ => false,
+        };
+
+        let (cmd, app_cmd) = match prev {
+            0 => (Cmd::set_block_length(512), false), // CMD16
+            // Pre-erase the blocks to write. MMC devices don't have ACMD23
+            16 if write && !emmc => (Cmd::app_cmd(rca << 16), false), // APP
+            55 => (Cmd::set_wr_blk_erase_count(blocks), true),        // ACMD23
+            16 | 23 if write => {
+                self.start_datapath_transfer(blocks * 512, 9, Dir::HostToCard);
+                (Cmd::write_multiple_blocks(arg), false) // CMD25
+            }
+            16 => {
+                self.start_datapath_transfer(blocks * 512, 9, Dir::CardToHost);
+                (Cmd::read_multiple_blocks(arg), false) // CMD18
+            }
+            _ => return TransferState::Data { write, address },
+        };
+
+        self.last_cmd.set((cmd.cmd, app_cmd));
+        self.start_cmd(&cmd);
+        TransferState::Start {
+            cmd: cmd.cmd,
+            write,
+            address,
+            arg,
+            blocks,
+        }
+    }
+
+    /// Enable the interrupts for the current state of a
+    /// non-blocking transfer
+    fn enable_transfer_interrupts(&self) {
+        match self.transfer {
+            TransferState::Data { .. } => self.sdmmc.write(
+                Register::Maskr,
+                star::DATAEND | star::DATA_ERRORS | star::IDMATE,
+            ),
+            TransferState::Start { .. } | TransferState::Stop { .. } => {
+                self.sdmmc.write(
+                    Register::Maskr,
+                    star::CMDREND | star::CCRCFAIL | star::CTIMEOUT,
+                )
+            }
+            TransferState::Busy { .. } => self
+                .sdmmc
+                .write(Register::Maskr, star::BUSYD0END | star::DTIMEOUT),
+            _ => self.sdmmc.write(Register::Maskr, 0),
+        }
+    }
+
+    /// Abort a non-blocking transfer that is in progress
+    fn abort_transfer(&mut self) {
+        self.sdmmc.write(Register::Maskr, 0);
+
+        if let TransferState::Start { .. }
+        | TransferState::Data { .. }
+        | TransferState::Stop { .. }
+        | TransferState::Failed(_) = self.transfer
+        {
+            // The transfer is abandoned anyway
+            self.recover_datapath();
+        }
+        self.transfer = TransferState::Idle;
+    }
+
+    /// Take the result of a non-blocking transfer, if it is
+    /// complete. The data path is recovered from a failed
+    /// transfer here rather than in the interrupt handler, as
+    /// recovery waits for the card
+    fn transfer_result(&mut self) -> Option<Result<(), Error>> {
+        if let TransferState::Failed(e) = self.transfer {
+            self.recover_datapath();
+            self.transfer = TransferState::Done(Err(e));
+        }
+
+        match self.transfer {
+            TransferState::Done(result) => {
+                self.transfer = TransferState::Idle;
+                Some(result)
+            }
+            _ => None,
+        }
+    }
+
+    /// Advance a non-blocking transfer. Call this from the
+    /// interrupt handler for this SDMMC peripheral.
+    ///
+    /// Each command that starts the transfer (CMD16, ACMD23 and
+    /// CMD18 or CMD25) is sent when the response to the previous
+    /// one arrives. When the data transfer ends, the transmission
+    /// is stopped (CMD12). For writes the interrupt is then used
+    /// to wait for the card to finish programming. The interrupt
+    /// handler never waits for the card: after an error, the data
+    /// path is recovered when the result is taken with `poll` or
+    /// `wait`.
+    pub fn on_interrupt(&mut self) {
+        let status = self.sdmmc.read(Register::Star);
+
+        match self.transfer {
+            TransferState::Start {
+                cmd,
+                write,
+                address,
+                arg,
+                blocks,
+            } => {
+                if status & (star::CMDREND | star::CCRCFAIL | star::CTIMEOUT)
+                    == 0
+                {
+                    return;
+                }
+                self.sdmmc.write(Register::Maskr, 0);
+
+                // All these commands have a R1 response, which is
+                // checked according to the command index only
+                let r1 = Cmd::new(cmd, arg, Response::Short);
+                self.transfer = match self.cmd_result(&r1, status) {
+                    Ok(()) => {
+                        self.next_start_cmd(cmd, write, address, arg, blocks)
+                    }
+                    // The data path is already waiting for the data
+                    Err(e) if cmd == 18 || cmd == 25 => {
+                        TransferState::Failed(e.at(address))
+                    }
+                    Err(e) => {
+                        self.sdmmc.write(Register::Idmactrlr, 0);
+                        TransferState::Done(Err(e.at(address)))
+                    }
+                };
+                self.enable_transfer_interrupts();
+            }
+            TransferState::Data { write, address } => {
+                if status & (star::DATA_ERRORS | star::IDMATE | star::DATAEND)
+                    == 0
+                {
+                    return;
+                }
+                self.sdmmc.write(Register::Maskr, 0);
+                self.sdmmc.write(Register::Idmactrlr, 0);
+                compiler_fence(Ordering::SeqCst);
+
+                self.transfer = if let Some(kind) = datapath_error_kind(status)
+                {
+                    let err = self.error(kind, Phase::Data, status);
+                    TransferState::Failed(err.at(address))
+                } else {
+                    match self.card().map(Card::write_timeout_ms) {
+                        Ok(timeout_ms) => {
+                            self.clear_static_interrupt_flags();
+
+                            // After a write, the card is busy until it
+                            // has finished programming. The busy timeout
+                            // replaces the data timeout
+                            self.set_busy_timeout(timeout_ms);
+                            let cmd = Cmd::stop_transmission(); // CMD12
+                            self.last_cmd.set((cmd.cmd, false));
+                            self.start_cmd(&cmd);
+                            TransferState::Stop { write, address }
+                        }
+                        Err(e) => TransferState::Failed(e.at(address)),
+                    }
+                };
+                self.enable_transfer_interrupts();
+            }
+            TransferState::Stop { write, address } => {
+                if status & (star::CMDREND | star::CCRCFAIL | star::CTIMEOUT)
+                    == 0
+                {
+                    return;
+                }
+                self.sdmmc.write(Register::Maskr, 0);
+
+                let cmd = Cmd::stop_transmission();
+                self.transfer = match self.cmd_result(&cmd, status) {
+                    // Wait for the card to finish programming
+                    Ok(()) if write => TransferState::Busy { address },
+                    Ok(()) => TransferState::Done(Ok(())),
+                    Err(e) => TransferState::Failed(e.at(address)),
+                };
+                self.enable_transfer_interrupts();
+            }
+            TransferState::Busy { address } => {
+                if status & star::BUSYD0 != 0
+                    && status & (star::BUSYD0END | star::DTIMEOUT) == 0
+                {
+                    return;
+                }
+                self.sdmmc.write(Register::Maskr, 0);
+                self.sdmmc
+                    .write(Register::Icr, star::BUSYD0END | star::DTIMEOUT);
+
+                self.transfer =
+                    TransferState::Done(if status & star::DTIMEOUT != 0 {
+                        Err(self
+                            .error(ErrorKind::Timeout, Phase::Busy, status)
+                            .at(address))
+                    } else {
+                        Ok(())
+                    });
+            }
+            _ => (),
+        }
+    }
+
+    /// Check that `buffer` is word aligned and can be accessed
+    /// by the IDMA
+    fn idma_check_buffer(buffer: &[u8]) -> Result<(), Error> {
+        if buffer.is_empty() {
+            return Err(ErrorKind::BadBuffer.into());
+        }
+
+        let start = buffer.as_ptr() as u32;
+        let end = start.wrapping_add(buffer.len() as u32 - 1);
+
+        let accessible = S::IDMA_REGIONS
+            .iter()
+            .any(|&(lo, hi)| start >= lo && end >= start && end <= hi);
+
+        if start & 3 != 0 || !accessible {
+            return Err(ErrorKind::BadBuffer.into());
+        }
+        Ok(())
+    }
+
+    /// Send a multiple block command `cmd` and transfer `length`
+    /// bytes at `address` with the IDMA in single buffer mode.
+    /// Then stop the transmission (CMD12)
+    fn idma_transfer(
+        &mut self,
+        cmd: Cmd,
+        address: u32,
+        length: u32,
+        direction: Dir,
+    ) -> Result<(), Error> {
+        // Single buffer mode
+        self.sdmmc.write(Register::Idmabase0r, address);
+        self.sdmmc.write(Register::Idmactrlr, idmactrlr::IDMAEN);
+
+        // Buffer accesses must not be reordered across the transfer
+        compiler_fence(Ordering::SeqCst);
+
+        self.start_datapath_transfer(length, 9, direction);
+        let result = self.data_cmd(cmd).and_then(|_| {
+            let mut status;
+            while {
+                status = self.sdmmc.read(Register::Star);
+                status & (star::DATA_ERRORS | star::IDMATE | star::DATAEND) == 0
+            } {}
+
+            self.stop_data_transfer(status)
+        });
+
+        self.sdmmc.write(Register::Idmactrlr, 0);
+        compiler_fence(Ordering::SeqCst);
+        self.clear_static_interrupt_flags();
+
+        result
+    }
+
+    /// Erase blocks `start` to `end` inclusive, using the erase
+    /// operation `mode`. Returns ErrorKind::UnsupportedEraseMode if
+    /// the card does not support `mode`, or ErrorKind::OutOfRange
+    /// if `start` is after `end`.
+    ///
+    /// `start` and `end` are block addresses. They are ignored
+    /// by EraseMode::FULE and EraseMode::Sanitize, which operate
+    /// on the whole card.
+    ///
+    /// Fails with ErrorKind::Busy while a non-blocking transfer
+    /// is in progress.
+    pub fn erase(
+        &mut self,
+        start: u32,
+        end: u32,
+        mode: EraseMode,
+    ) -> Result<(), Error> {
+        self.check_no_transfer()?;
+        let card = self.card()?;
+        let emmc = match card.card_type {
+            CardType::EMMC => true,
+            _ => false,
+        };
+
+        let arg = match mode {
+            EraseMode::Erase => 0,
+            EraseMode::Discard if !emmc && card.status.supports_discard() => 1,
+            EraseMode::FULE if !emmc && card.status.supports_fule() => 2,
+            EraseMode::Trim if emmc && card.ext_csd.supports_trim() => 1,
+            EraseMode::SecureErase
+                if emmc && card.ext_csd.supports_secure_erase() =>
+            {
+                0x8000_0000
+            }
+            EraseMode::Sanitize if emmc && card.ext_csd.supports_sanitize() => {
+                // Sanitize is started by writing SANITIZE_START
+                return self.mmc_switch(
+                    card,
+                    EXT_CSD_SANITIZE_START,
+                    1,
+                    MMC_SANITIZE_TIMEOUT_MS,
+                );
+            }
+            _ => return Err(ErrorKind::UnsupportedEraseMode.into()),
+        };
+
+        let blocks = match mode {
+            EraseMode::FULE => (card.size() / 512) as u32,
+            _ => {
+                if start > end {
+                    return Err(Error::from(ErrorKind::OutOfRange).at(start));
+                }
+                // Both addresses are checked before the erase starts
+                let first = card.data_address(start)?;
+                let last = card.data_address(end)?;
+                if emmc {
+                    self.cmd(Cmd::erase_group_start(first))?; // CMD35
+                    self.cmd(Cmd::erase_group_end(last))?; // CMD36
+                } else {
+                    self.cmd(Cmd::erase_wr_blk_start(first))?; // CMD32
+                    self.cmd(Cmd::erase_wr_blk_end(last))?; // CMD33
+                }
+                end - start + 1
+            }
+        };
+
+        self.set_busy_timeout(card.erase_timeout_ms(blocks, mode));
+        self.cmd(Cmd::erase(arg))?; // CMD38
+
+        self.wait_busy_d0()
+    }
+
+    /// Set the busy timeout for [`wait_busy_d0`](#method.wait_busy_d0),
+    /// in milliseconds at the current bus clock
+    fn set_busy_timeout(&self, timeout_ms: u32) {
+        let cycles = core::cmp::min(self.busy_cycles(timeout_ms), 0xFFFF_FFFF);
+        self.sdmmc.write(Register::Dtimer, cycles as u32);
+    }
+
+    /// The busy timeout is DTIMER, in bus cycles. Timeouts longer than
+    /// 0xFFFF_FFFF cycles do not fit
+    fn busy_cycles(&self, timeout_ms: u32) -> u64 {
+        u64::from(timeout_ms) * u64::from(self.clock.0 / 1000)
+    }
+
+    /// Wait for the card to release D0 after a command with a
+    /// busy (R1b) response. The busy timeout is DTIMER
+    fn wait_busy_d0(&self) -> Result<(), Error> {
+        let mut status;
+        while {
+            status = self.sdmmc.read(Register::Star);
+            status & star::BUSYD0 != 0
+                && status & (star::BUSYD0END | star::DTIMEOUT) == 0
+        } {}
+
+        self.sdmmc
+            .write(Register::Icr, star::BUSYD0END | star::DTIMEOUT);
+
+        if status & star::DTIMEOUT != 0 {
+            return Err(self.error(ErrorKind::Timeout, Phase::Busy, status));
+        }
+        Ok(())
+    }
+
+    /// Make attempts at a single block (`block`) or multiple
+    /// block data transfer, according to the retry policy. Counts
+    /// data CRC errors, and steps down the bus clock if the retry
+    /// policy says so.
+    ///
+    /// The card detect input is checked before the transfer and
+    /// after a failure, so that a transfer to a card that has been
+    /// removed fails with ErrorKind::NoCard. Fails with
+    /// ErrorKind::Busy if a non-blocking transfer is in progress
+    fn retry_data<F>(
+        &mut self,
+        address: u32,
+        block: bool,
+        mut transfer: F,
+    ) -> Result<(), Error>
+    where
+        F: FnMut(&mut Self) -> Result<(), Error>,
+    {
+        let attempts = if block {
+            self.retry.block_attempts
+        } else {
+            self.retry.transfer_attempts
+        };
+
+        self.check_no_transfer().map_err(|e| e.at(address))?;
+        self.detect_removal();
+
+        let mut attempt = 1;
+        loop {
+            let result = transfer(self).map_err(|e| e.at(address));
+
+            if let Err(e) = result {
+                self.detect_removal();
+                if let SlotState::Removed = self.slot {
+                    return Err(Error {
+                        kind: ErrorKind::NoCard,
+                        ..e
+                    });
+                }
+            }
+
+            let kind = result.err().map(|e| e.kind());
+            if kind == Some(ErrorKind::DataCrcFail) {
+                self.count_retries(|stats| stats.data_crc_errors += 1);
+                self.crc_errors = self.crc_errors.saturating_add(1);
+                match self.retry.clock_step_down {
+                    Some(n) if self.crc_errors >= n => self.step_down_clock(),
+                    _ => (),
+                }
+            } else if result.is_ok() {
+                self.crc_errors = 0;
+            }
 
-                    // Command AND Data state machines must be idle
-                    while self.sdmmc.star.read().dpsmact().bit_is_set()
-                        || self.sdmmc.star.read().cpsmact().bit_is_set()
-                    {}
-
-                    // Data timeout, in bus cycles
-                    self.sdmmc
-                        .dtimer
-                        .write(|w| unsafe { w.datatime().bits(5_000_000) });
-                    // Data length, in bytes
-                    self.sdmmc
-                        .dlenr
-                        .write(|w| unsafe { w.datalength().bits(length_bytes) });
-                    // Transfer
-                    self.sdmmc.dctrl.write(|w| unsafe {
-                        w.dblocksize()
-                            .bits(block_size) // 2^n bytes block size
-                            .dtdir()
-                            .bit(dtdir)
-                            .dten()
-                            .set_bit() // Enable transfer
+            match kind {
+                Some(ErrorKind::DataCrcFail)
+                | Some(ErrorKind::RxOverFlow)
+                | Some(ErrorKind::Crc)
+                | Some(ErrorKind::Timeout)
+                    if attempt < attempts =>
+                {
+                    self.count_retries(|stats| {
+                        if block {
+                            stats.block_retries += 1;
+                        } else {
+                            stats.transfer_retries += 1;
+                        }
                     });
+                    attempt += 1;
                 }
+                _ => return result,
+            }
+        }
+    }
 
-                /// Read block from card.
-                ///
-                /// `address` is the block address.
-                pub fn read_block(
-                    &mut self,
-                    address: u32,
-                    buffer: &mut [u8; 512],
-                ) -> Result<(), Error> {
-                    let _card = self.card()?;
-
-                    self.cmd(Cmd::set_block_length(512))?; // CMD16
-
-                    // Setup read command
-                    self.start_datapath_transfer(512, 9, Dir::CardToHost);
-                    self.cmd(Cmd::read_single_block(address))?;
-
-                    let mut i = 0;
-                    let mut status;
-                    while {
-                        status = self.sdmmc.star.read();
-                        !(status.rxoverr().bit()
-                          || status.dcrcfail().bit()
-                          || status.dtimeout().bit()
-                          || status.dataend().bit())
-                    } {
-                        if status.rxfifohf().bit() {
-                            for _ in 0..8 {
-                                let bytes = self.sdmmc.fifor.read().bits().to_le_bytes();
-                                buffer[i..i + 4].copy_from_slice(&bytes);
-                                i += 4;
-                            }
-                        }
+    /// Halve the bus clock, if it stays at or above 400kHz
+    fn step_down_clock(&mut self) {
+        self.crc_errors = 0;
+
+        let width = match (self.sdmmc.read(Register::Clkcr) & clkcr::WIDBUS)
+            >> clkcr::WIDBUS_SHIFT
+        {
+            0 => BusWidth::One,
+            1 => BusWidth::Four,
+            _ => BusWidth::Eight,
+        };
+        let freq = self.clock.0 / 2;
+        if freq >= 400_000 && self.clkcr_set_clkdiv(freq, width).is_ok() {
+            self.count_retries(|stats| stats.clock_step_downs += 1);
+            sdmmc_trace!("Stepped down bus clock to {}Hz", self.clock.0);
+        }
+    }
 
-                        if i >= buffer.len() {
-                            break;
-                        }
-                    }
+    /// Update the retry statistics
+    fn count_retries<F: FnOnce(&mut RetryStats)>(&self, f: F) {
+        let mut stats = self.retry_stats.get();
+        f(&mut stats);
+        self.retry_stats.set(stats);
+    }
 
-                    err_from_datapath_sm!(status);
+    /// Stop the transmission (CMD12) at the end of a multiple
+    /// block transfer that ended with `status`, or recover from
+    /// a data path error
+    fn stop_data_transfer(&self, status: u32) -> Result<(), Error> {
+        err_from_datapath_sm!(self, status);
 
-                    Ok(())
+        // After a write, the card is busy until it has finished
+        // programming. The busy timeout replaces the data timeout
+        self.set_busy_timeout(self.card()?.write_timeout_ms());
+        self.cmd(Cmd::stop_transmission()) // CMD12
+    }
+
+    /// Recover from a data path error, so that the driver can be
+    /// used again. Errors during recovery are ignored.
+    ///
+    /// The data transfer is aborted with CMD12, which also
+    /// stops the DPSM (DABORT). Once the card has released D0 it
+    /// should return to the Transfer State. Then the FIFO is
+    /// flushed and all the static flags are cleared
+    fn recover_datapath(&self) {
+        self.sdmmc.write(Register::Idmactrlr, 0);
+        compiler_fence(Ordering::SeqCst);
+
+        let _ = self.cmd(Cmd::stop_transmission()); // CMD12
+        let _ = self.wait_busy_d0();
+
+        // The card may still be programming. Wait up to 500ms,
+        // the write timeout for SDXC cards
+        for _ in 0..500 {
+            match self.send_status().map(|r1| r1.current_state()) {
+                Ok(CardState::Programming) => self.delay_ms(1),
+                Ok(CardState::Sending) | Ok(CardState::Receiving) => {
+                    let _ = self.cmd(Cmd::stop_transmission()); // CMD12
                 }
+                // Error bits are cleared by reading them, ask again
+                Err(e) if e.card_status().is_some() => (),
+                _ => break,
+            }
+        }
 
-                /// Read mutliple blocks from card. The length of the buffer
-                /// must be multiple of 512.
-                ///
-                /// `address` is the block address.
-                pub fn read_blocks(
-                    &mut self,
-                    address: u32,
-                    buffer: &mut [u8],
-                ) -> Result<(), Error> {
-                    let _card = self.card()?;
-
-                    assert!(buffer.len() % 512 == 0);
-                    let n_blocks = buffer.len() / 512;
-                    self.cmd(Cmd::set_block_length(512))?; // CMD16
-
-                    // Setup read command
-                    self.start_datapath_transfer(512 * n_blocks as u32, 9, Dir::CardToHost);
-                    self.cmd(Cmd::read_multiple_blocks(address))?;
-
-                    let mut i = 0;
-                    let mut status;
-                    while {
-                        status = self.sdmmc.star.read();
-                        !(status.rxoverr().bit()
-                          || status.dcrcfail().bit()
-                          || status.dtimeout().bit()
-                          || status.dataend().bit())
-                    } {
-                        if status.rxfifohf().bit() {
-                            for _ in 0..8 {
-                                let bytes = self.sdmmc.fifor.read().bits().to_le_bytes();
-                                buffer[i..i + 4].copy_from_slice(&bytes);
-                                i += 4;
-                            }
-                        }
+        self.reset_datapath();
+        self.sdmmc.write(
+            Register::Icr,
+            star::CCRCFAIL
+                | star::CTIMEOUT
+                | star::CMDREND
+                | star::CMDSENT
+                | star::BUSYD0END,
+        );
+    }
 
-                        if i >= buffer.len() {
-                            break;
-                        }
-                    }
+    /// Query the card's status register (CMD13).
+    ///
+    /// Fails if the card reports an error in its status
+    fn send_status(&self) -> Result<R1Status, Error> {
+        let card = self.card()?;
 
-                    self.cmd(Cmd::stop_transmission())?; // CMD12
+        // SEND_STATUS
+        self.cmd(Cmd::card_status(card.rca << 16))?; // CMD13
 
-                    err_from_datapath_sm!(status);
+        Ok(R1Status(self.sdmmc.read(Register::Resp1r)))
+    }
 
-                    Ok(())
+    /// Reads the SD Status (ACMD13)
+    ///
+    fn read_sd_status(&mut self) -> Result<(), Error> {
+        let card = self.card()?;
+
+        self.cmd(Cmd::set_block_length(64))?; // CMD16
+        self.cmd(Cmd::app_cmd(card.rca << 16))?; // APP
+
+        // Prepare the transfer
+        self.start_datapath_transfer(64, 6, Dir::CardToHost);
+        self.cmd(Cmd::card_status(0))?; // ACMD13
+
+        let mut status = [0u32; 16];
+        let mut idx = 0;
+        let mut sta_reg;
+        while {
+            sta_reg = self.sdmmc.read(Register::Star);
+            sta_reg
+                & (star::RXOVERR
+                    | star::DCRCFAIL
+                    | star::DTIMEOUT
+                    | star::DBCKEND)
+                == 0
+        } {
+            if sta_reg & star::RXFIFOHF != 0 {
+                for _ in 0..8 {
+                    status[idx] = self.sdmmc.read(Register::Fifor);
+                    idx += 1;
                 }
+            }
 
-                /// Write block to card. Buffer must be 512 bytes
-                pub fn write_block(
-                    &mut self,
-                    address: u32,
-                    buffer: &[u8; 512]
-                ) -> Result<(), Error> {
-                    let _card = self.card()?;
-
-                    self.cmd(Cmd::set_block_length(512))?; // CMD16
-
-                    // Setup write command
-                    self.start_datapath_transfer(512, 9, Dir::HostToCard);
-                    self.cmd(Cmd::write_single_block(address))?; // CMD24
-
-                    let mut i = 0;
-                    let mut status;
-                    while {
-                        status = self.sdmmc.star.read();
-                        !(status.txunderr().bit()
-                          || status.dcrcfail().bit()
-                          || status.dtimeout().bit()
-                          || status.dataend().bit())
-                    } {
-                        if status.txfifohe().bit() {
-                            for _ in 0..8 {
-                                let mut wb = [0u8; 4];
-                                wb.copy_from_slice(&buffer[i..i + 4]);
-                                let word = u32::from_le_bytes(wb);
-                                self.sdmmc.fifor.write(|w| unsafe { w.bits(word) });
-                                i += 4;
-                            }
-                        }
+            if idx == status.len() {
+                break;
+            }
+        }
 
-                        if i >= buffer.len() {
-                            break;
-                        }
-                    }
+        err_from_datapath_sm!(self, sta_reg);
 
-                    err_from_datapath_sm!(status);
-                    self.clear_static_interrupt_flags();
+        let card = self
+            .card
+            .as_mut()
+            .ok_or_else(|| Error::from(ErrorKind::NoCard))?;
+        card.status = SDStatus::new(status);
 
-                    let mut timeout: u32 = 0xFFFF_FFFF;
+        Ok(())
+    }
 
-                    // Try to read card status (ACMD13)
-                    while timeout > 0 {
-                        match self.read_sd_status() {
-                            Ok(_) => return Ok(()),
-                            Err(Error::Timeout) => (), // Try again
-                            Err(e) => return Err(e),
-                        }
+    /// Get SD CARD Configuration Register (SCR)
+    fn get_scr(&self, card: &mut Card) -> Result<(), Error> {
+        // Read the the 64-bit SCR register
+        self.cmd(Cmd::set_block_length(8))?; // CMD16
+        self.cmd(Cmd::app_cmd(card.rca << 16))?;
+
+        self.start_datapath_transfer(8, 3, Dir::CardToHost);
+        self.cmd(Cmd::cmd51())?;
+
+        let mut scr = [0; 2];
+        let mut i = 0;
+        let mut status;
+        while {
+            status = self.sdmmc.read(Register::Star);
+
+            status
+                & (star::RXOVERR
+                    | star::DCRCFAIL
+                    | star::DTIMEOUT
+                    | star::DBCKEND)
+                == 0
+        } {
+            if status & star::RXFIFOE == 0 {
+                // FIFO not empty
+                scr[i] = self.sdmmc.read(Register::Fifor);
+                i += 1;
+            }
 
-                        timeout -= 1;
-                    }
-                    Err(Error::SoftwareTimeout)
-                }
+            if i == 2 {
+                break;
+            }
+        }
 
-                /// Query the card's status register (CMD13).
-                ///
-                /// Returns the 'card state' bits
-                fn send_status(&self) -> Result<CardStatus, Error> {
-                    let card = self.card()?;
+        err_from_datapath_sm!(self, status);
 
-                    // SEND_STATUS
-                    self.cmd(Cmd::card_status(card.rca << 16))?; // CMD13
-                    let r1 = self.sdmmc.resp1r.read().bits();
+        // Bytes from wire are Big Endian
+        let scr = ((scr[1] as u64) << 32) | scr[0] as u64;
+        card.scr = SCR(u64::from_be(scr));
 
-                    let cardstate = (r1 >> 9) as u8 & 0xF;
+        Ok(())
+    }
 
-                    Ok(cardstate.into())
+    /// Get the Extended CSD register of a MMC / eMMC device
+    fn read_ext_csd(&self, card: &mut Card) -> Result<(), Error> {
+        self.cmd(Cmd::set_block_length(512))?; // CMD16
+
+        // Prepare the transfer
+        self.start_datapath_transfer(512, 9, Dir::CardToHost);
+        self.cmd(Cmd::hs_send_ext_csd(0))?; // CMD8
+
+        let mut ext_csd = [0u32; 128];
+        let mut idx = 0;
+        let mut sta_reg;
+        while {
+            sta_reg = self.sdmmc.read(Register::Star);
+            sta_reg
+                & (star::RXOVERR
+                    | star::DCRCFAIL
+                    | star::DTIMEOUT
+                    | star::DBCKEND)
+                == 0
+        } {
+            if sta_reg & star::RXFIFOHF != 0 {
+                for _ in 0..8 {
+                    ext_csd[idx] = self.sdmmc.read(Register::Fifor);
+                    idx += 1;
                 }
+            }
 
-                /// Reads the SD Status (ACMD13)
-                ///
-                fn read_sd_status(&mut self) -> Result<(), Error> {
-                    let card = self.card()?;
-
-                    self.cmd(Cmd::set_block_length(64))?; // CMD16
-                    self.cmd(Cmd::app_cmd(card.rca << 16))?; // APP
-
-                    // Prepare the transfer
-                    self.start_datapath_transfer(64, 6, Dir::CardToHost);
-                    self.cmd(Cmd::card_status(0))?; // ACMD13
-
-                    let mut status = [0u32; 16];
-                    let mut idx = 0;
-                    let mut sta_reg;
-                    while {
-                        sta_reg = self.sdmmc.star.read();
-                        !(sta_reg.rxoverr().bit()
-                          || sta_reg.dcrcfail().bit()
-                          || sta_reg.dtimeout().bit()
-                          || sta_reg.dbckend().bit())
-                    } {
-                        if sta_reg.rxfifohf().bit() {
-                            for _ in 0..8 {
-                                status[idx] = self.sdmmc.fifor.read().bits();
-                                idx += 1;
-                            }
-                        }
+            if idx == ext_csd.len() {
+                break;
+            }
+        }
 
-                        if idx == status.len() {
-                            break;
-                        }
-                    }
+        err_from_datapath_sm!(self, sta_reg);
 
-                    err_from_datapath_sm!(sta_reg);
+        card.ext_csd = ExtCSD::new(ext_csd);
 
-                    let card = self.card.as_mut().ok_or(Error::NoCard)?;
-                    card.status = SDStatus::new(status);
+        Ok(())
+    }
 
-                    Ok(())
-                }
+    /// Write one byte of the Extended CSD register of a MMC /
+    /// eMMC device using CMD6, and wait up to `timeout_ms` for
+    /// the device to return to the _Transfer State_
+    fn mmc_switch(
+        &self,
+        card: &Card,
+        index: u8,
+        value: u8,
+        timeout_ms: u32,
+    ) -> Result<(), Error> {
+        // Access mode: Write Byte
+        let arg =
+            0x0300_0000 | (u32::from(index) << 16) | (u32::from(value) << 8);
+        self.set_busy_timeout(timeout_ms);
+        self.cmd(Cmd::cmd6(arg))?; // CMD6
+        match self.wait_busy_d0() {
+            // DTIMER cannot hold the longest timeouts (Sanitize). The
+            // card status is polled for the remainder
+            Err(e)
+                if e.kind() == ErrorKind::Timeout
+                    && self.busy_cycles(timeout_ms) > 0xFFFF_FFFF => {}
+            result => result?,
+        }
 
-                /// Get SD CARD Configuration Register (SCR)
-                fn get_scr(&self, card: &mut Card) -> Result<(), Error> {
-                    // Read the the 64-bit SCR register
-                    self.cmd(Cmd::set_block_length(8))?; // CMD16
-                    self.cmd(Cmd::app_cmd(card.rca << 16))?;
-
-                    self.start_datapath_transfer(8, 3, Dir::CardToHost);
-                    self.cmd(Cmd::cmd51())?;
-
-                    let mut scr = [0; 2];
-                    let mut i = 0;
-                    let mut status;
-                    while {
-                        status = self.sdmmc.star.read();
-
-                        !(status.rxoverr().bit()
-                          || status.dcrcfail().bit()
-                          || status.dtimeout().bit()
-                          || status.dbckend().bit())
-                    } {
-                        if status.rxfifoe().bit_is_clear() {
-                            // FIFO not empty
-                            scr[i] = self.sdmmc.fifor.read().bits();
-                            i += 1;
-                        }
+        for _ in 0..=timeout_ms {
+            self.cmd(Cmd::card_status(card.rca << 16))?; // CMD13
+            let r1 = R1Status(self.sdmmc.read(Register::Resp1r));
 
-                        if i == 2 {
-                            break;
-                        }
-                    }
+            if r1.switch_error() {
+                return Err(ErrorKind::SignalingSwitchFailed.into());
+            }
+            if r1.current_state() == CardState::Transfer {
+                return Ok(());
+            }
+            self.delay_ms(1);
+        }
+        Err(ErrorKind::SoftwareTimeout.into())
+    }
+
+    /// Query the functions supported by the card using the
+    /// Switch Function command (CMD6) in check mode. No function
+    /// is changed.
+    ///
+    /// # Errors
+    ///
+    /// Returns ErrorKind::NoCard if [`init_card`](#method.init_card)
+    /// has not previously succeeded, or ErrorKind::UnsupportedCardType
+    /// for MMC devices
+    pub fn switch_status(&self) -> Result<SwitchStatus, Error> {
+        if let CardType::EMMC = self.card()?.card_type {
+            return Err(ErrorKind::UnsupportedCardType.into());
+        }
+
+        // Check function, no change to any group
+        self.switch_function(0x00FF_FFFF)
+    }
+
+    /// Get the output driver strength currently selected by the
+    /// card
+    pub fn driver_strength(&self) -> Result<DriverStrength, Error> {
+        let status = self.switch_status()?;
+
+        DriverStrength::from_function(
+            status.selected(FunctionGroup::DriverStrength),
+        )
+    }
+
+    /// Set the output driver strength of the card. Check which
+    /// types are supported with
+    /// [`switch_status`](#method.switch_status).
+    ///
+    /// Returns the driver strength selected by the card, or
+    /// ErrorKind::UnsupportedFunction if the card rejected the
+    /// request
+    pub fn set_driver_strength(
+        &self,
+        strength: DriverStrength,
+    ) -> Result<DriverStrength, Error> {
+        let selected = self.switch_function_group(
+            FunctionGroup::DriverStrength,
+            strength as u8,
+        )?;
+        DriverStrength::from_function(selected)
+    }
 
-                    err_from_datapath_sm!(status);
+    /// Get the current limit currently selected by the card
+    pub fn current_limit(&self) -> Result<CurrentLimit, Error> {
+        let status = self.switch_status()?;
 
-                    // Bytes from wire are Big Endian
-                    let scr = ((scr[1] as u64) << 32) | scr[0] as u64;
-                    card.scr = SCR(u64::from_be(scr));
+        CurrentLimit::from_function(status.selected(FunctionGroup::PowerLimit))
+    }
+
+    /// Set the current limit of the card. Higher limits are
+    /// needed for the card to reach full performance in SDR50
+    /// and SDR104. Check which limits are supported with
+    /// [`switch_status`](#method.switch_status).
+    ///
+    /// Returns the current limit selected by the card, or
+    /// ErrorKind::UnsupportedFunction if the card rejected the
+    /// request
+    pub fn set_current_limit(
+        &self,
+        limit: CurrentLimit,
+    ) -> Result<CurrentLimit, Error> {
+        let selected =
+            self.switch_function_group(FunctionGroup::PowerLimit, limit as u8)?;
+        CurrentLimit::from_function(selected)
+    }
+
+    /// Select `function` in one function group using CMD6, with
+    /// no change to the other groups. Returns the function
+    /// selected by the card
+    fn switch_function_group(
+        &self,
+        group: FunctionGroup,
+        function: u8,
+    ) -> Result<u8, Error> {
+        if let CardType::EMMC = self.card()?.card_type {
+            return Err(ErrorKind::UnsupportedCardType.into());
+        }
+
+        let shift = 4 * (group as u32 - 1);
+        let arg =
+            (0x80FF_FFFF & !(0xF << shift)) | (u32::from(function) << shift);
+
+        let status = self.switch_function(arg)?;
+        Ok(status.selected(group))
+    }
+
+    /// Switch mode using CMD6.
+    ///
+    /// Attempt to set a new signalling mode. The selected
+    /// signalling mode is returned. Expects the current clock
+    /// frequency to be > 12.5MHz.
+    fn switch_signalling_mode(
+        &self,
+        signalling: Signalling,
+    ) -> Result<Signalling, Error> {
+        let set_function = 0x8000_0000
+            | match signalling {
+                // See PLSS v7_10 Table 4-11
+                Signalling::DDR50 => 0xFF_FF04,
+                Signalling::SDR104 => 0xFF_1F03,
+                Signalling::SDR50 => 0xFF_1F02,
+                Signalling::SDR25 => 0xFF_FF01,
+                Signalling::SDR12 => 0xFF_FF00,
+            };
+
+        let status = self.switch_function(set_function)?;
+
+        match status.selected(FunctionGroup::AccessMode) {
+            0 => Ok(Signalling::SDR12),
+            1 => Ok(Signalling::SDR25),
+            2 => Ok(Signalling::SDR50),
+            3 => Ok(Signalling::SDR104),
+            4 => Ok(Signalling::DDR50),
+            _ => Err(ErrorKind::UnsupportedCardType.into()),
+        }
+    }
 
-                    Ok(())
+    /// Send CMD6 with argument `arg`, and read the 512-bit switch
+    /// function status
+    fn switch_function(&self, arg: u32) -> Result<SwitchStatus, Error> {
+        // NB PLSS v7_10 4.3.10.4: "the use of SET_BLK_LEN command is not
+        // necessary"
+
+        // Prepare the transfer
+        self.start_datapath_transfer(64, 6, Dir::CardToHost);
+        self.cmd(Cmd::cmd6(arg))?; // CMD6
+
+        let mut status = [0u32; 16];
+        let mut idx = 0;
+        let mut sta_reg;
+        while {
+            sta_reg = self.sdmmc.read(Register::Star);
+            sta_reg
+                & (star::RXOVERR
+                    | star::DCRCFAIL
+                    | star::DTIMEOUT
+                    | star::DBCKEND)
+                == 0
+        } {
+            if sta_reg & star::RXFIFOHF != 0 {
+                for _ in 0..8 {
+                    status[idx] = self.sdmmc.read(Register::Fifor);
+                    idx += 1;
                 }
+            }
 
-                /// Switch mode using CMD6.
-                ///
-                /// Attempt to set a new signalling mode. The selected
-                /// signalling mode is returned. Expects the current clock
-                /// frequency to be > 12.5MHz.
-                fn switch_signalling_mode(
-                    &self,
-                    signalling: Signalling,
-                ) -> Result<Signalling, Error> {
-                    // NB PLSS v7_10 4.3.10.4: "the use of SET_BLK_LEN command is not
-                    // necessary"
-
-                    let set_function = 0x8000_0000
-                        | match signalling {
-                            // See PLSS v7_10 Table 4-11
-                            Signalling::DDR50 => 0xFF_FF04,
-                            Signalling::SDR104 => 0xFF_1F03,
-                            Signalling::SDR50 => 0xFF_1F02,
-                            Signalling::SDR25 => 0xFF_FF01,
-                            Signalling::SDR12 => 0xFF_FF00,
-                        };
-
-                    // Prepare the transfer
-                    self.start_datapath_transfer(64, 6, Dir::CardToHost);
-                    self.cmd(Cmd::cmd6(set_function))?; // CMD6
-
-                    let mut status = [0u32; 16];
-                    let mut idx = 0;
-                    let mut sta_reg;
-                    while {
-                        sta_reg = self.sdmmc.star.read();
-                        !(sta_reg.rxoverr().bit()
-                          || sta_reg.dcrcfail().bit()
-                          || sta_reg.dtimeout().bit()
-                          || sta_reg.dbckend().bit())
-                    } {
-                        if sta_reg.rxfifohf().bit() {
-                            for _ in 0..8 {
-                                status[idx] = self.sdmmc.fifor.read().bits();
-                                idx += 1;
-                            }
-                        }
+            if idx == status.len() {
+                break;
+            }
+        }
 
-                        if idx == status.len() {
-                            break;
-                        }
-                    }
+        err_from_datapath_sm!(self, sta_reg);
 
-                    err_from_datapath_sm!(sta_reg);
+        // Host is allowed to use the new functions at least 8
+        // clocks after the end of the switch command
+        // transaction. We know the current clock period is < 80ns,
+        // so a total delay of 640ns is required here
+        #[cfg(target_arch = "arm")]
+        for _ in 0..300 {
+            cortex_m::asm::nop();
+        }
 
-                    // Host is allowed to use the new functions at least 8
-                    // clocks after the end of the switch command
-                    // transaction. We know the current clock period is < 80ns,
-                    // so a total delay of 640ns is required here
-                    for _ in 0..300 {
-                        cortex_m::asm::nop();
-                    }
+        Ok(SwitchStatus::new(status))
+    }
+
+    /// Select one card and place it into the _Tranfer State_
+    ///
+    /// If `None` is specifed for `card`, all cards are put back into
+    /// _Stand-by State_
+    fn select_card(&self, card: Option<&Card>) -> Result<(), Error> {
+        // Determine Relative Card Address (RCA) of given card
+        let rca = card.map(|c| c.rca << 16).unwrap_or(0);
+
+        let r = self.cmd(Cmd::sel_desel_card(rca));
+        match (r, rca) {
+            (Err(e), 0) if e.kind() == ErrorKind::Timeout => Ok(()),
+            _ => r,
+        }
+    }
+
+    /// Clear "static flags" in interrupt clear register
+    fn clear_static_interrupt_flags(&self) {
+        self.sdmmc.write(
+            Register::Icr,
+            star::DATA_ERRORS
+                | star::DATAEND
+                | star::DHOLD
+                | star::DBCKEND
+                | star::DABORT
+                | star::IDMATE
+                | star::IDMABTC,
+        );
+    }
+
+    /// Read a long (R2) response
+    fn long_response(&self) -> u128 {
+        ((self.sdmmc.read(Register::Resp1r) as u128) << 96)
+            | ((self.sdmmc.read(Register::Resp2r) as u128) << 64)
+            | ((self.sdmmc.read(Register::Resp3r) as u128) << 32)
+            | self.sdmmc.read(Register::Resp4r) as u128
+    }
+
+    /// Send command to card, retrying it according to the retry
+    /// policy. An application specific command (ACMD) is retried
+    /// together with the CMD55 that precedes it
+    fn cmd(&self, cmd: Cmd) -> Result<(), Error> {
+        let app_cmd = self.app_cmd.replace(None);
+
+        let mut attempt = 1;
+        loop {
+            self.last_cmd.set((cmd.cmd, app_cmd.is_some()));
+            let result = self.send_cmd(&cmd);
+            match result {
+                Err(e)
+                    if attempt < self.retry.command_attempts
+                        && cmd.retryable(
+                            e,
+                            app_cmd.is_some(),
+                            self.card_type,
+                        ) =>
+                {
+                    self.count_retries(|stats| stats.command_retries += 1);
+                    attempt += 1;
 
-                    // Support Bits of Functions in Function Group 1
-                    let _support_bits = u32::from_be(status[3]) >> 16;
-                    // Function Selection of Function Group 1
-                    let selection = (u32::from_be(status[4]) >> 24) & 0xF;
-
-                    match selection {
-                        0 => Ok(Signalling::SDR12),
-                        1 => Ok(Signalling::SDR25),
-                        2 => Ok(Signalling::SDR50),
-                        3 => Ok(Signalling::SDR104),
-                        4 => Ok(Signalling::DDR50),
-                        _ => Err(Error::UnsupportedCardType),
+                    if let Some(arg) = app_cmd {
+                        // Any error is seen again on the ACMD
+                        let _ = self.send_cmd(&Cmd::app_cmd(arg)); // CMD55
                     }
                 }
-
-                /// Select one card and place it into the _Tranfer State_
-                ///
-                /// If `None` is specifed for `card`, all cards are put back into
-                /// _Stand-by State_
-                fn select_card(&self, card: Option<&Card>) -> Result<(), Error> {
-                    // Determine Relative Card Address (RCA) of given card
-                    let rca = card.map(|c| c.rca << 16).unwrap_or(0);
-
-                    let r = self.cmd(Cmd::sel_desel_card(rca));
-                    match (r, rca) {
-                        (Err(Error::Timeout), 0) => Ok(()),
-                        _ => r,
+                _ => {
+                    if cmd.cmd == 55 && result.is_ok() {
+                        self.app_cmd.set(Some(cmd.arg));
                     }
+                    return result;
                 }
+            }
+        }
+    }
 
-                /// Clear "static flags" in interrupt clear register
-                fn clear_static_interrupt_flags(&self) {
-                    self.sdmmc.icr.modify(|_, w| {
-                        w.dcrcfailc()
-                            .set_bit()
-                            .dtimeoutc()
-                            .set_bit()
-                            .txunderrc()
-                            .set_bit()
-                            .rxoverrc()
-                            .set_bit()
-                            .dataendc()
-                            .set_bit()
-                            .dholdc()
-                            .set_bit()
-                            .dbckendc()
-                            .set_bit()
-                            .dabortc()
-                            .set_bit()
-                            .idmatec()
-                            .set_bit()
-                            .idmabtcc()
-                            .set_bit()
-                    });
+    /// Send a command that starts a data transfer. The DPSM is already
+    /// waiting for the data, so the data path is recovered if the
+    /// command fails
+    fn data_cmd(&self, cmd: Cmd) -> Result<(), Error> {
+        let result = self.cmd(cmd);
+        if result.is_err() {
+            self.recover_datapath();
+        }
+        result
+    }
+
+    /// An error in `phase` of the last command sent, with the flags
+    /// `status`
+    fn error(&self, kind: ErrorKind, phase: Phase, status: u32) -> Error {
+        let (cmd, app_cmd) = self.last_cmd.get();
+        Error::from(kind).in_cmd(cmd, app_cmd, phase, status)
+    }
+
+    /// Send command to card, once
+    fn send_cmd(&self, cmd: &Cmd) -> Result<(), Error> {
+        self.start_cmd(cmd);
+
+        // The response takes a few hundred bus cycles even at 400kHz.
+        // CTIMEOUT is set after 64, unless the SDMMC has stopped
+        let mut timeout = self.star_reads(10);
+
+        let mut status;
+        if cmd.resp == Response::None {
+            // Wait for CMDSENT or a timeout
+            while {
+                status = self.sdmmc.read(Register::Star);
+                status & (star::CTIMEOUT | star::CMDSENT) == 0 && timeout > 0
+            } {
+                timeout -= 1;
+            }
+        } else {
+            // Wait for CMDREND or CCRCFAIL or a timeout
+            while {
+                status = self.sdmmc.read(Register::Star);
+                status & (star::CTIMEOUT | star::CMDREND | star::CCRCFAIL) == 0
+                    && timeout > 0
+            } {
+                timeout -= 1;
+            }
+        }
+
+        if timeout == 0 && status & star::CTIMEOUT == 0 {
+            return Err(self.error(
+                ErrorKind::SoftwareTimeout,
+                Phase::Command,
+                status,
+            ));
+        }
+        self.cmd_result(cmd, status)
+    }
+
+    /// Start sending command to card, without waiting for the
+    /// response
+    fn start_cmd(&self, cmd: &Cmd) {
+        // Clear interrupts
+        self.sdmmc.write(
+            Register::Icr,
+            star::CCRCFAIL
+                | star::CTIMEOUT
+                | star::CMDREND
+                | star::CMDSENT
+                | star::DATAEND
+                | star::DBCKEND
+                | star::DATA_ERRORS
+                | star::SDIOIT
+                | star::BUSYD0END,
+        );
+
+        // CP state machine must be idle
+        while self.sdmmc.read(Register::Star) & star::CPSMACT != 0 {}
+
+        // Command arg
+        self.sdmmc.write(Register::Argr, cmd.arg);
+
+        // Special mode in CP State Machine
+        // CMD12: Stop Transmission
+        let cmdstop = if cmd.cmd == 12 { cmdr::CMDSTOP } else { 0 };
+
+        // Command index, response type (No / Short / Long), and start CP
+        // State Machine
+        self.sdmmc.write(
+            Register::Cmdr,
+            u32::from(cmd.cmd)
+                | (cmd.resp as u32) << cmdr::WAITRESP_SHIFT
+                | cmdstop
+                | cmdr::CPSMEN,
+        );
+    }
+
+    /// The result of the command `cmd`, from the flags `status`
+    /// once it has completed
+    fn cmd_result(&self, cmd: &Cmd, status: u32) -> Result<(), Error> {
+        let result = if status & star::CTIMEOUT != 0 {
+            Err(ErrorKind::Timeout)
+        } else if status & star::CCRCFAIL != 0 {
+            Err(ErrorKind::Crc)
+        } else if cmd.r1(self.card_type) {
+            cmd.check_status(R1Status(self.sdmmc.read(Register::Resp1r)))
+        } else {
+            Ok(())
+        };
+
+        result.map_err(|kind| self.error(kind, Phase::Command, status))
+    }
+}
+
+impl<S: SdmmcRegisters> Transfer<S> {
+    /// Returns the buffer and the result of the transfer if it is complete,
+    /// otherwise returns the transfer.
+    ///
+    /// If the card has been removed, the transfer fails with
+    /// `ErrorKind::NoCard`
+    pub fn poll<CD: CardDetect>(
+        self,
+        sdmmc: &mut Sdmmc<S, CD>,
+    ) -> Result<(&'static mut [u8], Result<(), Error>), Self> {
+        if let Some(e) = self.error {
+            return Ok((self.buffer, Err(e)));
+        }
+
+        sdmmc.detect_removal();
+        match sdmmc.transfer_result() {
+            Some(result) => Ok((self.buffer, result)),
+            None => Err(self),
+        }
+    }
+
+    /// Wait for the transfer to complete, and return the buffer and the
+    /// result of the transfer.
+    ///
+    /// The transfer is advanced by calling `on_interrupt`, so this works
+    /// with or without the interrupt enabled
+    pub fn wait<CD: CardDetect>(
+        mut self,
+        sdmmc: &mut Sdmmc<S, CD>,
+    ) -> (&'static mut [u8], Result<(), Error>) {
+        loop {
+            match self.poll(sdmmc) {
+                Ok(done) => return done,
+                Err(transfer) => self = transfer,
+            }
+            sdmmc.on_interrupt();
+        }
+    }
+}
+
+#[cfg(target_arch = "arm")]
+macro_rules! sdmmc {
+    ($($SDMMCX:ident: ($sdmmcX:ident, $Rec:ident, $DLYB:ident, $IDMA:ident, $WAKER:ident),)+) => {
+        $(
+            impl SdmmcExt<$SDMMCX> for $SDMMCX {
+                type Rec = rec::$Rec;
+
+                fn sdmmc<PINS>(self, _pins: PINS,
+                               prec: rec::$Rec,
+                               clocks: &CoreClocks) -> Sdmmc<$SDMMCX>
+                where
+                    PINS: Pins<$SDMMCX>,
+                {
+                    Sdmmc::$sdmmcX(self, PINS::BUSWIDTH, prec, clocks)
                 }
 
-                /// Send command to card
-                fn cmd(&self, cmd: Cmd) -> Result<(), Error> {
-                    // Clear interrupts
-                    self.sdmmc.icr.modify(|_, w| {
-                        w.ccrcfailc() // CRC FAIL
-                            .set_bit()
-                            .ctimeoutc() // TIMEOUT
-                            .set_bit()
-                            .cmdrendc() // CMD R END
-                            .set_bit()
-                            .cmdsentc() // CMD SENT
-                            .set_bit()
-                            .dataendc()
-                            .set_bit()
-                            .dbckendc()
-                            .set_bit()
-                            .dcrcfailc()
-                            .set_bit()
-                            .dtimeoutc()
-                            .set_bit()
-                            .sdioitc() // SDIO IT
-                            .set_bit()
-                            .rxoverrc()
-                            .set_bit()
-                            .txunderrc()
-                            .set_bit()
-                    });
+                fn sdmmc_unchecked(self, bus_width: BusWidth,
+                                   prec: rec::$Rec,
+                                   clocks: &CoreClocks) -> Sdmmc<$SDMMCX>
+                {
+                    Sdmmc::$sdmmcX(self, bus_width, prec, clocks)
+                }
+            }
 
-                    // CP state machine must be idle
-                    while self.sdmmc.star.read().cpsmact().bit_is_set() {}
-
-                    // Command arg
-                    self.sdmmc
-                        .argr
-                        .write(|w| unsafe { w.cmdarg().bits(cmd.arg) });
-
-                    // Special mode in CP State Machine
-                    // CMD12: Stop Transmission
-                    let cpsm_stop_transmission = (cmd.cmd == 12);
-
-                    // Command index and start CP State Machine
-                    self.sdmmc.cmdr.write(|w| unsafe {
-                        w.waitint()
-                            .clear_bit()
-                            .waitresp() // No / Short / Long
-                            .bits(cmd.resp as u8)
-                            .cmdstop() // CPSM Stop Transmission
-                            .bit(cpsm_stop_transmission)
-                            .cmdindex()
-                            .bits(cmd.cmd)
-                            .cpsmen()
-                            .set_bit()
-                    });
+            impl SdmmcRegisters for $SDMMCX {
+                const IDMA_REGIONS: &'static [(u32, u32)] = $IDMA;
 
-                    let mut timeout: u32 = 0xFFFF_FFFF;
-
-                    let mut status;
-                    if cmd.resp == Response::None {
-                        // Wait for CMDSENT or a timeout
-                        while {
-                            status = self.sdmmc.star.read();
-                            !(status.ctimeout().bit() || status.cmdsent().bit())
-                                && timeout > 0
-                        } {
-                            timeout -= 1;
-                        }
-                    } else {
-                        // Wait for CMDREND or CCRCFAIL or a timeout
-                        while {
-                            status = self.sdmmc.star.read();
-                            !(status.ctimeout().bit()
-                              || status.cmdrend().bit()
-                              || status.ccrcfail().bit())
-                                && timeout > 0
-                        } {
-                            timeout -= 1;
-                        }
-                    }
+                fn read(&self, reg: Register) -> u32 {
+                    // The delay block is only accessed once it is owned.
+                    // See `set_delay_block`
+                    let base = match reg {
+                        Register::DlybCr | Register::DlybCfgr => $DLYB::ptr() as usize,
+                        _ => $SDMMCX::ptr() as usize,
+                    };
+                    unsafe { ptr::read_volatile((base + reg.offset()) as *const u32) }
+                }
 
-                    if status.ctimeout().bit_is_set() {
-                        return Err(Error::Timeout);
-                    } else if timeout == 0 {
-                        return Err(Error::SoftwareTimeout);
-                    } else if status.ccrcfail().bit() {
-                        return Err(Error::Crc);
+                fn write(&self, reg: Register, value: u32) {
+                    let base = match reg {
+                        Register::DlybCr | Register::DlybCfgr => $DLYB::ptr() as usize,
+                        _ => $SDMMCX::ptr() as usize,
+                    };
+                    unsafe { ptr::write_volatile((base + reg.offset()) as *mut u32, value) }
+                }
+            }
+
+            impl Sdmmc<$SDMMCX> {
+                /// Initialise SDMMC peripheral
+                pub fn $sdmmcX(
+                    sdmmc: $SDMMCX,
+                    bus_width: BusWidth,
+                    prec: rec::$Rec,
+                    clocks: &CoreClocks,
+                ) -> Self {
+                    // Enable and reset peripheral
+                    let prec = prec.enable().reset();
+
+                    let ker_ck = match prec.get_kernel_clk_mux() {
+                        rec::SdmmcClkSel::PLL1_Q => clocks.pll1_q_ck(),
+                        rec::SdmmcClkSel::PLL2_R => clocks.pll2_r_ck(),
                     }
+                    .expect("sdmmc_ker_ck not running!");
+
+                    // For tuning the phase of the receive sampling clock, a
+                    // DLYB block can be connected between sdmmc_io_in_ck and
+                    // sdmmc_fb_ck. See `set_delay_block`
+
+                    Sdmmc::new(sdmmc, bus_width, ker_ck, clocks.hclk(), clocks.c_ck())
+
+                    // drop prec: ker_ck can no longer be modified
+                }
+            }
+
+            impl<CD: CardDetect> Sdmmc<$SDMMCX, CD> {
+                /// Gives this peripheral ownership of its DLYB delay block. The
+                /// delay block is used to tune the sampling point of the
+                /// receive clock for SDR50 and SDR104 signalling.
+                ///
+                /// If a delay block is set, `init_card_uhs` tunes the sampling
+                /// point automatically.
+                pub fn set_delay_block(&mut self, _dlyb: $DLYB) {
+                    self.dlyb = true;
+                }
+
+                /// Read multiple blocks from card using the internal DMA
+                /// (IDMA), waiting for the transfer to complete
+                /// asynchronously. The length of the buffer must be multiple
+                /// of 512. Returns the buffer and the result of the transfer.
+                ///
+                /// `address` is the block address.
+                ///
+                /// The future is woken by
+                /// [`on_interrupt_async`](#method.on_interrupt_async), which
+                /// must be called from the interrupt handler for this SDMMC
+                /// peripheral. If the future is dropped before it completes,
+                /// the transfer is aborted. The buffer is `'static` because
+                /// the IDMA keeps writing to it if the future is forgotten
+                /// instead.
+                ///
+                /// The buffer has the same requirements as for
+                /// [`read_blocks_dma`](#method.read_blocks_dma).
+                pub async fn read_blocks_async(
+                    &mut self,
+                    address: u32,
+                    buffer: &'static mut [u8],
+                ) -> (&'static mut [u8], Result<(), Error>) {
+                    self.read_blocks_waker(address, buffer, &$WAKER).await
+                }
 
-                    Ok(())
+                /// Write multiple blocks to card using the internal DMA
+                /// (IDMA), waiting for the transfer to complete
+                /// asynchronously. The length of the buffer must be multiple
+                /// of 512.
+                ///
+                /// `address` is the block address.
+                ///
+                /// The future is woken by
+                /// [`on_interrupt_async`](#method.on_interrupt_async), which
+                /// must be called from the interrupt handler for this SDMMC
+                /// peripheral. If the future is dropped before it completes,
+                /// the transfer is aborted. The buffer is `'static` because
+                /// the IDMA keeps reading from it if the future is forgotten
+                /// instead.
+                ///
+                /// The buffer has the same requirements as for
+                /// [`write_blocks_dma`](#method.write_blocks_dma).
+                pub async fn write_blocks_async(
+                    &mut self,
+                    address: u32,
+                    buffer: &'static [u8],
+                ) -> Result<(), Error> {
+                    self.write_blocks_waker(address, buffer, &$WAKER).await
                 }
 
+                /// Interrupt handler for async transfers. Call this from the
+                /// interrupt handler for this SDMMC peripheral.
+                ///
+                /// Masks the interrupt and wakes the task waiting on the
+                /// transfer, which then advances the transfer itself
+                pub fn on_interrupt_async() {
+                    // Only MASKR is accessed, and the task that owns the
+                    // peripheral re-enables the interrupts when it is polled
+                    let sdmmc = unsafe { &*$SDMMCX::ptr() };
+                    sdmmc.maskr.write(|w| unsafe { w.bits(0) });
+
+                    $WAKER.wake();
+                }
             }
         )+
     };
 }
 
+#[cfg(target_arch = "arm")]
 sdmmc! {
-    SDMMC1: (sdmmc1, Sdmmc1),
-    SDMMC2: (sdmmc2, Sdmmc2),
+    SDMMC1: (
+        sdmmc1,
+        Sdmmc1,
+        DELAY_BLOCK_SDMMC1,
+        SDMMC1_IDMA_REGIONS,
+        SDMMC1_WAKER
+    ),
+    SDMMC2: (
+        sdmmc2,
+        Sdmmc2,
+        DELAY_BLOCK_SDMMC2,
+        SDMMC2_IDMA_REGIONS,
+        SDMMC2_WAKER
+    ),
 }
 
 /// SD card Commands
@@ -1159,11 +4066,88 @@ impl Cmd {
         Cmd { cmd, arg, resp }
     }
 
+    /// This command can be retried after `err`. The R3 response
+    /// to CMD1 and ACMD41 has no CRC, and the tuning commands
+    /// are expected to fail at some sampling points. The card may
+    /// already be transferring data or busy after a data or erase
+    /// command, so these are not retried on their own. This
+    /// includes CMD6 (SD), ACMD13, ACMD51 and CMD8 (eMMC), which
+    /// all read a register over the data lines
+    fn retryable(
+        &self,
+        err: Error,
+        app_cmd: bool,
+        card_type: CardType,
+    ) -> bool {
+        let emmc = match card_type {
+            CardType::EMMC => true,
+            _ => false,
+        };
+        match (self.cmd, err.kind()) {
+            (19, _) | (21, _) => false,
+            (17, _) | (18, _) | (24, _) | (25, _) | (38, _) => false,
+            (6, _) if !app_cmd && !emmc => false,
+            (13, _) | (51, _) if app_cmd => false,
+            (8, _) if emmc => false,
+            (1, ErrorKind::Crc) | (41, ErrorKind::Crc) => false,
+            (_, ErrorKind::Crc) | (_, ErrorKind::Timeout) => true,
+            _ => false,
+        }
+    }
+
+    /// The response to this command is a Card Status (R1 or R1b). The
+    /// R3, R6 and R7 responses are also short
+    fn r1(&self, card_type: CardType) -> bool {
+        match (self.cmd, card_type) {
+            (1, _) | (41, _) => false, // R3
+            (3, CardType::EMMC) | (8, CardType::EMMC) => true,
+            (3, _) | (8, _) => false, // R6 and R7 on SD cards
+            _ => self.resp == Response::Short,
+        }
+    }
+
+    /// Check for errors in the Card Status `r1` returned by this
+    /// command.
+    ///
+    /// Some cards report OUT_OF_RANGE in the response to the CMD12 that
+    /// ends a multiple block read up to the last block. This is ignored
+    fn check_status(&self, r1: R1Status) -> Result<(), ErrorKind> {
+        let mut errors = r1.0 & R1Status::ERRORS;
+        if self.cmd == 12 {
+            errors &= !R1Status::OUT_OF_RANGE;
+        }
+        let errors = R1Status(errors);
+
+        if errors.0 == 0 {
+            Ok(())
+        } else if errors.out_of_range()
+            || errors.address_error()
+            || errors.block_len_error()
+        {
+            Err(ErrorKind::AddressError(r1))
+        } else if errors.erase_seq_error() || errors.erase_param() {
+            Err(ErrorKind::EraseError(r1))
+        } else if errors.wp_violation() || errors.wp_erase_skip() {
+            Err(ErrorKind::WriteProtect(r1))
+        } else if errors.lock_unlock_failed() {
+            Err(ErrorKind::LockUnlockFailed(r1))
+        } else if errors.card_ecc_failed() {
+            Err(ErrorKind::CardEccFailed(r1))
+        } else {
+            Err(ErrorKind::CardError(r1))
+        }
+    }
+
     /// CMD0: Idle
     const fn idle() -> Cmd {
         Cmd::new(0, 0, Response::None)
     }
 
+    /// CMD1: Send Operating Conditions (MMC)
+    const fn send_op_cond(arg: u32) -> Cmd {
+        Cmd::new(1, arg, Response::Short)
+    }
+
     /// CMD2: Send CID
     const fn all_send_cid() -> Cmd {
         Cmd::new(2, 0, Response::Long)
@@ -1174,6 +4158,11 @@ impl Cmd {
         Cmd::new(3, 0, Response::Short)
     }
 
+    /// CMD3: Set Relative Address (MMC)
+    const fn set_rel_addr(rca: u32) -> Cmd {
+        Cmd::new(3, rca, Response::Short)
+    }
+
     /// CMD6: Switch Function Command
     /// ACMD6: Bus Width
     const fn cmd6(arg: u32) -> Cmd {
@@ -1185,7 +4174,8 @@ impl Cmd {
         Cmd::new(7, rca, Response::Short)
     }
 
-    /// CMD8:
+    /// CMD8: Send Interface Condition (SD)
+    /// CMD8: Send Extended CSD (MMC)
     const fn hs_send_ext_csd(arg: u32) -> Cmd {
         Cmd::new(8, arg, Response::Short)
     }
@@ -1195,6 +4185,11 @@ impl Cmd {
         Cmd::new(9, rca, Response::Long)
     }
 
+    /// CMD11: Switch to 1.8V signalling
+    const fn voltage_switch() -> Cmd {
+        Cmd::new(11, 0, Response::Short)
+    }
+
     /// CMD12:
     const fn stop_transmission() -> Cmd {
         Cmd::new(12, 0, Response::Short)
@@ -1221,11 +4216,51 @@ impl Cmd {
         Cmd::new(18, addr, Response::Short)
     }
 
+    /// CMD19: Send Tuning Block
+    const fn send_tuning_block() -> Cmd {
+        Cmd::new(19, 0, Response::Short)
+    }
+
     /// CMD24: Block Write
     const fn write_single_block(addr: u32) -> Cmd {
         Cmd::new(24, addr, Response::Short)
     }
 
+    /// CMD25: Multiple Block Write
+    const fn write_multiple_blocks(addr: u32) -> Cmd {
+        Cmd::new(25, addr, Response::Short)
+    }
+
+    /// ACMD23: Set Number of Write Blocks to be Pre-erased
+    const fn set_wr_blk_erase_count(blocks: u32) -> Cmd {
+        Cmd::new(23, blocks, Response::Short)
+    }
+
+    /// CMD32: Set the address of the first block to be erased
+    const fn erase_wr_blk_start(addr: u32) -> Cmd {
+        Cmd::new(32, addr, Response::Short)
+    }
+
+    /// CMD33: Set the address of the last block to be erased
+    const fn erase_wr_blk_end(addr: u32) -> Cmd {
+        Cmd::new(33, addr, Response::Short)
+    }
+
+    /// CMD35: Set the address of the first erase group (MMC)
+    const fn erase_group_start(addr: u32) -> Cmd {
+        Cmd::new(35, addr, Response::Short)
+    }
+
+    /// CMD36: Set the address of the last erase group (MMC)
+    const fn erase_group_end(addr: u32) -> Cmd {
+        Cmd::new(36, addr, Response::Short)
+    }
+
+    /// CMD38: Erase the selected blocks
+    const fn erase(arg: u32) -> Cmd {
+        Cmd::new(38, arg, Response::Short)
+    }
+
     const fn app_op_cmd(arg: u32) -> Cmd {
         Cmd::new(41, arg, Response::Short)
     }
@@ -0,0 +1,2073 @@
+//! Simulated SDMMC peripheral with a SDHC card or an eMMC device attached,
+//! for testing the driver on the host.
+//!
+//! Commands complete as soon as CMDR is written, and the card sends all the
+//! data for a read command straight into the FIFO. After a write the card
+//! holds D0 busy for a few reads of STAR, and the busy phase times out if
+//! the programming time of the card is longer than DTIMER.
+//!
+//! The IDMA can only access buffers allocated by
+//! [`SimCard::dma_buffer`]. Other addresses fail with an IDMA transfer
+//! error. In single buffer mode it moves all the data of a transfer at the
+//! first read of STAR after the command. In double buffer mode it moves one
+//! buffer at each read of STAR, once the driver has cleared IDMABTC. There
+//! is no delay block.
+
+use core::cell::RefCell;
+use core::cmp;
+use std::boxed::Box;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::vec::Vec;
+
+use embedded_hal::digital::v2::InputPin;
+
+use crate::registers::*;
+use crate::sdmmc::{BusWidth, Hertz, Sdmmc};
+
+/// Card state, as reported in the CURRENT_STATE field of the card
+/// status. See PLSS v7_10 Table 4-42
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum State {
+    Idle = 0,
+    Ready = 1,
+    Identification = 2,
+    Standby = 3,
+    Transfer = 4,
+    Sending = 5,
+    Receiving = 6,
+    Programming = 7,
+}
+
+/// Response from the card to a command
+enum Response {
+    /// R1 and R1b, the card status
+    R1(u32),
+    /// R2, CID or CSD
+    R2(u128),
+    /// R3, OCR. Has no CRC
+    R3(u32),
+    /// R6, published RCA
+    R6(u32),
+    /// R7, card interface condition
+    R7(u32),
+}
+
+/// Data phase of a write command
+struct Data {
+    /// Multiple block transfer, stopped by CMD12
+    multiple: bool,
+    /// Byte address of the data on the card
+    address: usize,
+    /// Data length, in bytes
+    length: usize,
+    /// Data received from the host
+    received: Vec<u8>,
+}
+
+/// Data phase of a command that uses the IDMA
+struct Dma {
+    /// Card to host
+    read: bool,
+    /// Byte address of the data on the card
+    address: usize,
+    /// Data length, in bytes
+    length: usize,
+    /// Bytes transferred so far
+    done: usize,
+}
+
+/// RCA published by the card
+const RCA: u32 = 0x4567;
+
+/// Kernel clock of the simulated peripheral, in Hertz
+const KER_CK: u32 = 100_000_000;
+
+/// Reads of STAR for which the card holds D0 busy
+const BUSY_READS: u32 = 3;
+
+/// CARD_STATUS bits. See PLSS v7_10 Table 4-42
+const OUT_OF_RANGE: u32 = 1 << 31;
+const ERASE_SEQ_ERROR: u32 = 1 << 28;
+const ERASE_PARAM: u32 = 1 << 27;
+const SWITCH_ERROR: u32 = 1 << 7;
+const READY_FOR_DATA: u32 = 1 << 8;
+const APP_CMD: u32 = 1 << 5;
+
+struct Sim {
+    power: u32,
+    clkcr: u32,
+    argr: u32,
+    resp: [u32; 4],
+    dtimer: u32,
+    dlenr: u32,
+    dctrl: u32,
+    star: u32,
+    maskr: u32,
+    fifo: VecDeque<u32>,
+    data: Option<Data>,
+    idmactrl: u32,
+    idmabsize: u32,
+    idmabase: [u32; 2],
+    dma: Option<Dma>,
+    /// Buffers that the IDMA can access, as `(address, length)`
+    dma_buffers: Vec<(usize, usize)>,
+
+    /// The card is an eMMC device
+    emmc: bool,
+    /// A version 1.x SDSC card, which does not answer CMD8 and is byte
+    /// addressed
+    sdsc: bool,
+    /// Extended CSD of an eMMC device
+    ext_csd: [u8; 512],
+    /// Relative card address
+    rca: u32,
+    /// The last CMD6 SWITCH failed
+    switch_error: bool,
+    state: State,
+    /// Next command is an application specific command
+    app_cmd: bool,
+    /// ACMD41 calls before the card reports power up done
+    power_up: u32,
+    /// Block length set by CMD16
+    block_length: u32,
+    bus_width: BusWidth,
+    memory: Vec<u8>,
+    present: bool,
+    /// Levels read from the card detect switch before it settles
+    bounce: VecDeque<bool>,
+    /// Data path error flags for the next data transfers
+    data_error: u32,
+    /// Number of data transfers to fail
+    data_errors: u32,
+    /// Corrupt the response to the next command with this index
+    corrupt_cmd: Option<u32>,
+    /// First and last blocks to erase, set by CMD32 and CMD33
+    erase_start: Option<u32>,
+    erase_end: Option<u32>,
+    /// Time taken to program a write or an erase, in milliseconds. The card is not
+    /// busy if zero
+    program_ms: u32,
+    /// Reads of STAR left in the busy phase, and the flag that ends it
+    busy: Option<(u32, u32)>,
+    /// Commands received, with their arguments. ACMDs are logged with
+    /// index `index | ACMD`
+    commands: Vec<(u32, u32)>,
+}
+
+/// Marks an application specific command in the command log
+const ACMD: u32 = 0x100;
+
+impl Sim {
+    fn status(&self) -> u32 {
+        let app = if self.app_cmd { APP_CMD } else { 0 };
+        let switch = if self.switch_error { SWITCH_ERROR } else { 0 };
+        (self.state as u32) << 9 | READY_FOR_DATA | app | switch
+    }
+
+    /// CSD of an eMMC device in sector mode. See JESD84-B51 Section 7.3
+    fn emmc_csd() -> u128 {
+        3 << 126 // CSD_STRUCTURE
+            | 4 << 122 // SPEC_VERS
+            | 0x32 << 96 // TRAN_SPEED
+            | 0x8F5 << 84 // CCC
+            | 9 << 80 // READ_BL_LEN
+            | 0xFFF << 62 // C_SIZE, > 2GB
+            | 9 << 22 // WRITE_BL_LEN
+            | 1
+    }
+
+    /// Extended CSD of an eMMC 5.1 device of `size` bytes. See
+    /// JESD84-B51 Section 7.4
+    fn ext_csd(size: usize) -> [u8; 512] {
+        let mut ext_csd = [0; 512];
+        ext_csd[192] = 8; // EXT_CSD_REV
+        ext_csd[196] = 0x01; // DEVICE_TYPE: HS 26MHz
+        ext_csd[212..216].copy_from_slice(&(size as u32 / 512).to_le_bytes());
+        ext_csd[223] = 3; // ERASE_TIMEOUT_MULT: 900ms
+        ext_csd[224] = 1; // HC_ERASE_GRP_SIZE: 512kB
+        ext_csd[230] = 2; // SEC_ERASE_MULT
+        ext_csd[231] = 0x51; // SEC_FEATURE_SUPPORT: secure erase, trim, sanitize
+        ext_csd[232] = 1; // TRIM_MULT: 300ms
+        ext_csd[248] = 10; // GENERIC_CMD6_TIME: 100ms
+        ext_csd[504] = 1; // S_CMD_SET
+        ext_csd
+    }
+
+    fn cid() -> u128 {
+        u128::from_be_bytes([
+            0x03, b'S', b'D', b'S', b'I', b'M', b'0', b'1', 0x10, 0x12, 0x34,
+            0x56, 0x78, 0x01, 0x46, 0x01,
+        ])
+    }
+
+    /// CSD Version 1.0 of a SDSC card, with 512 block multipliers of 512
+    /// bytes. See PLSS v7_10 Section 5.3.2
+    fn sdsc_csd(&self) -> u128 {
+        let c_size = (self.memory.len() / (512 * 512) - 1) as u128;
+        0x32 << 96 // TRAN_SPEED
+            | 0x5B5 << 84 // CCC
+            | 9 << 80 // READ_BL_LEN
+            | c_size << 62
+            | 7 << 47 // C_SIZE_MULT
+            | 1 << 46 // ERASE_BLK_EN
+            | 0x7F << 39 // SECTOR_SIZE
+            | 9 << 22 // WRITE_BL_LEN
+            | 1
+    }
+
+    /// CSD Version 2.0. See PLSS v7_10 Section 5.3.3
+    fn csd(&self) -> u128 {
+        let c_size = (self.memory.len() / (512 * 1024) - 1) as u128;
+        1 << 126 // CSD_STRUCTURE
+            | 0x32 << 96 // TRAN_SPEED
+            | 0x5B5 << 84 // CCC
+            | 9 << 80 // READ_BL_LEN
+            | c_size << 48
+            | 1 << 46 // ERASE_BLK_EN
+            | 0x7F << 39 // SECTOR_SIZE
+            | 9 << 22 // WRITE_BL_LEN
+            | 1
+    }
+
+    /// SCR of a Version 3.00 card with a 4-bit bus, or of a Version 1.10
+    /// SDSC card. See PLSS v7_10 Section 5.6
+    fn scr(&self) -> u64 {
+        if self.sdsc {
+            1 << 56 // SD_SPEC
+                | 0b0101 << 48 // SD_BUS_WIDTHS
+        } else {
+            2 << 56 // SD_SPEC
+                | 0b0101 << 48 // SD_BUS_WIDTHS
+                | 1 << 47 // SD_SPEC3
+        }
+    }
+
+    /// Byte address of the data address `arg` of a read or a write
+    fn data_address(&self, arg: u32) -> usize {
+        if self.sdsc {
+            arg as usize
+        } else {
+            arg as usize * 512
+        }
+    }
+
+    /// Block of the data address `arg` of an erase command
+    fn erase_block(&self, arg: u32) -> u32 {
+        if self.sdsc {
+            arg / 512
+        } else {
+            arg
+        }
+    }
+
+    /// Start sending `bytes` to the host
+    fn send(&mut self, bytes: &[u8]) {
+        for word in bytes.chunks(4) {
+            let mut w = [0; 4];
+            w[..word.len()].copy_from_slice(word);
+            self.fifo.push_back(u32::from_le_bytes(w));
+        }
+    }
+
+    /// Start the data phase of a read command. Returns false if the data
+    /// path is not ready to receive, or the address is out of range
+    fn read(&mut self, address: usize) -> bool {
+        let length = self.dlenr as usize;
+        if self.dctrl & (dctrl::DTEN | dctrl::DTDIR)
+            != dctrl::DTEN | dctrl::DTDIR
+            || address + length > self.memory.len()
+        {
+            self.star |= star::DTIMEOUT;
+            return false;
+        }
+        if self.data_errors > 0 {
+            // The card is left sending
+            self.star |= self.data_error;
+            self.data_errors -= 1;
+            return true;
+        }
+
+        if self.idmactrl & idmactrlr::IDMAEN != 0 {
+            self.dma = Some(Dma {
+                read: true,
+                address,
+                length,
+                done: 0,
+            });
+            return true;
+        }
+
+        let bytes = self.memory[address..address + length].to_vec();
+        self.send(&bytes);
+        true
+    }
+
+    /// The buffer allocated by [`SimCard::dma_buffer`] at the 32-bit IDMA
+    /// address `base`, if `length` bytes from there are in the buffer
+    fn dma_memory(&self, base: u32, length: usize) -> Option<*mut u8> {
+        self.dma_buffers.iter().find_map(|&(address, len)| {
+            let offset = base.wrapping_sub(address as u32) as usize;
+            if offset + length <= len {
+                Some((address + offset) as *mut u8)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Move the data of an IDMA transfer between the card and the buffer.
+    /// In double buffer mode, only the next buffer is moved
+    fn dma_transfer(&mut self) {
+        let double = self.idmactrl & idmactrlr::IDMABMODE != 0;
+        if double && self.star & star::IDMABTC != 0 {
+            // The driver has not taken the last buffer yet
+            return;
+        }
+        let mut dma = match self.dma.take() {
+            Some(dma) => dma,
+            None => return,
+        };
+
+        let (base, length) = if double {
+            let size = (self.idmabsize >> idmabsizer::IDMABNDT_SHIFT & 0xFF)
+                as usize
+                * 32;
+            let n = dma.done.checked_div(size).unwrap_or(0);
+            (self.idmabase[n % 2], cmp::min(size, dma.length - dma.done))
+        } else {
+            (self.idmabase[0], dma.length)
+        };
+        let ptr = match self.dma_memory(base, length) {
+            Some(ptr) if length > 0 => ptr,
+            _ => {
+                self.star |= star::IDMATE;
+                return;
+            }
+        };
+
+        // The buffer was leaked by SimCard::dma_buffer, and the driver
+        // does not access it while the IDMA does
+        let buffer = unsafe { core::slice::from_raw_parts_mut(ptr, length) };
+        let address = dma.address + dma.done;
+        if dma.read {
+            buffer.copy_from_slice(&self.memory[address..address + length]);
+        } else {
+            for word in buffer.chunks(4) {
+                let mut w = [0; 4];
+                w[..word.len()].copy_from_slice(word);
+                self.fifo_write(u32::from_le_bytes(w));
+            }
+        }
+        dma.done += length;
+
+        if double {
+            self.star |= star::IDMABTC;
+        }
+        if dma.done < dma.length {
+            self.dma = Some(dma);
+        } else if dma.read {
+            self.data_end();
+        }
+    }
+
+    /// Bus clock, in Hertz
+    fn clock(&self) -> u32 {
+        match self.clkcr & clkcr::CLKDIV {
+            0 => KER_CK,
+            div => KER_CK / (2 * div),
+        }
+    }
+
+    /// Start the busy phase after a write. The phase ends with a
+    /// timeout if programming takes longer than DTIMER
+    fn start_busy(&mut self) {
+        if self.program_ms == 0 {
+            self.state = State::Transfer;
+            return;
+        }
+        let timeout_ms =
+            u64::from(self.dtimer) * 1000 / u64::from(self.clock());
+        let end = if u64::from(self.program_ms) > timeout_ms {
+            star::DTIMEOUT
+        } else {
+            star::BUSYD0END
+        };
+        self.state = State::Programming;
+        self.busy = Some((BUSY_READS, end));
+    }
+
+    /// Start the data phase of a write command
+    fn write(&mut self, address: usize, multiple: bool) -> bool {
+        let length = self.dlenr as usize;
+        if self.dctrl & (dctrl::DTEN | dctrl::DTDIR) != dctrl::DTEN
+            || address + length > self.memory.len()
+        {
+            self.star |= star::DTIMEOUT;
+            return false;
+        }
+
+        self.data = Some(Data {
+            multiple,
+            address,
+            length,
+            received: Vec::new(),
+        });
+        if self.idmactrl & idmactrlr::IDMAEN != 0 {
+            self.dma = Some(Dma {
+                read: false,
+                address,
+                length,
+                done: 0,
+            });
+        }
+        true
+    }
+
+    /// Respond to a command. Returns None if the card does not respond
+    fn command(&mut self, index: u32, arg: u32) -> Option<Response> {
+        if !self.present || self.power & power::PWRCTRL != 0b11 {
+            return None;
+        }
+
+        let app_cmd = self.app_cmd;
+        let status = self.status();
+        self.app_cmd = false;
+        let logged = if app_cmd { index | ACMD } else { index };
+        self.commands.push((logged, arg));
+        let selected = arg >> 16 == self.rca;
+        let emmc = self.emmc;
+
+        match (index, self.state) {
+            (0, _) => {
+                self.state = State::Idle;
+                self.power_up = 1;
+                None
+            }
+            (8, State::Idle) if !emmc && !self.sdsc => {
+                Some(Response::R7(arg & 0xFFF))
+            }
+            (1, State::Idle) if emmc => {
+                // Power up is reported done on the second CMD1. Sector
+                // mode
+                let ocr = if self.power_up == 0 {
+                    self.state = State::Ready;
+                    0xC0FF_8080
+                } else {
+                    self.power_up -= 1;
+                    0x00FF_8080
+                };
+                Some(Response::R3(ocr))
+            }
+            (55, _) if !emmc => {
+                self.app_cmd = true;
+                Some(Response::R1(self.status()))
+            }
+            (41, State::Idle) if app_cmd => {
+                // Power up is reported done on the second ACMD41
+                let ocr = if self.power_up == 0 {
+                    self.state = State::Ready;
+                    let ccs = if self.sdsc { 0 } else { arg & 0x4000_0000 };
+                    0x8000_0000 | ccs | 0x00FF_8000
+                } else {
+                    self.power_up -= 1;
+                    0x00FF_8000
+                };
+                Some(Response::R3(ocr))
+            }
+            (2, State::Ready) => {
+                self.state = State::Identification;
+                Some(Response::R2(Self::cid()))
+            }
+            (3, State::Identification) if emmc => {
+                self.rca = arg >> 16;
+                self.state = State::Standby;
+                Some(Response::R1(status))
+            }
+            (3, State::Identification) | (3, State::Standby) => {
+                self.state = State::Standby;
+                Some(Response::R6(RCA << 16 | status & 0x1FFF))
+            }
+            (9, State::Standby) if selected && emmc => {
+                Some(Response::R2(Self::emmc_csd()))
+            }
+            (9, State::Standby) if selected && self.sdsc => {
+                Some(Response::R2(self.sdsc_csd()))
+            }
+            (9, State::Standby) if selected => Some(Response::R2(self.csd())),
+            (7, State::Standby) if selected => {
+                self.state = State::Transfer;
+                Some(Response::R1(status))
+            }
+            (7, _) => {
+                // Deselected cards do not respond
+                self.state = State::Standby;
+                None
+            }
+            (8, State::Transfer) if emmc => {
+                if self.dctrl & dctrl::DTEN != 0 {
+                    let ext_csd = self.ext_csd;
+                    self.send(&ext_csd);
+                }
+                Some(Response::R1(status))
+            }
+            (6, State::Transfer) if emmc => {
+                // Write Byte access mode only. The properties segment is
+                // read only
+                let index = (arg >> 16) as u8 as usize;
+                let value = (arg >> 8) as u8;
+                self.switch_error = arg >> 24 != 3 || index >= 192;
+                if !self.switch_error {
+                    self.ext_csd[index] = value;
+                    if index == 183 {
+                        self.bus_width = match value {
+                            2 => BusWidth::Eight,
+                            1 => BusWidth::Four,
+                            _ => BusWidth::One,
+                        };
+                    }
+                }
+                self.start_busy();
+                Some(Response::R1(status))
+            }
+            (6, State::Transfer) if app_cmd => {
+                self.bus_width = match arg & 3 {
+                    2 => BusWidth::Four,
+                    _ => BusWidth::One,
+                };
+                Some(Response::R1(status))
+            }
+            (51, State::Transfer) if app_cmd => {
+                if self.dctrl & dctrl::DTEN != 0 {
+                    self.send(&self.scr().to_be_bytes());
+                }
+                Some(Response::R1(status))
+            }
+            (13, State::Transfer) if app_cmd => {
+                // SD Status, with all fields zero
+                if self.dctrl & dctrl::DTEN != 0 {
+                    self.send(&[0; 64]);
+                }
+                Some(Response::R1(status))
+            }
+            (13, _) if selected => {
+                // Error bits are cleared by reading them
+                self.switch_error = false;
+                Some(Response::R1(status))
+            }
+            (16, State::Transfer) => {
+                self.block_length = arg;
+                Some(Response::R1(status))
+            }
+            (23, State::Transfer) if app_cmd => Some(Response::R1(status)),
+            (17, State::Transfer) | (18, State::Transfer) => {
+                let address = self.data_address(arg);
+                if !self.read(address) {
+                    return Some(Response::R1(status | OUT_OF_RANGE));
+                }
+                if index == 18 {
+                    self.state = State::Sending;
+                }
+                Some(Response::R1(status))
+            }
+            (24, State::Transfer) | (25, State::Transfer) => {
+                let address = self.data_address(arg);
+                if !self.write(address, index == 25) {
+                    return Some(Response::R1(status | OUT_OF_RANGE));
+                }
+                self.state = State::Receiving;
+                Some(Response::R1(status))
+            }
+            (32, State::Transfer) if !emmc => {
+                self.erase_start = Some(self.erase_block(arg));
+                Some(Response::R1(status))
+            }
+            (33, State::Transfer) if !emmc => {
+                self.erase_end = Some(self.erase_block(arg));
+                Some(Response::R1(status))
+            }
+            (35, State::Transfer) if emmc => {
+                self.erase_start = Some(arg);
+                Some(Response::R1(status))
+            }
+            (36, State::Transfer) if emmc => {
+                self.erase_end = Some(arg);
+                Some(Response::R1(status))
+            }
+            (38, State::Transfer) if emmc && self.ext_csd[175] & 1 == 0 => {
+                // Only high-capacity erase groups (ERASE_GROUP_DEF) are
+                // simulated
+                self.erase_start = None;
+                self.erase_end = None;
+                Some(Response::R1(status | ERASE_PARAM))
+            }
+            (38, State::Transfer) => {
+                let blocks = self.memory.len() / 512;
+                let range =
+                    match (self.erase_start.take(), self.erase_end.take()) {
+                        // FULE
+                        _ if arg == 2 && !emmc => Some((0, blocks)),
+                        (Some(start), Some(end)) => Some((
+                            start as usize,
+                            cmp::min(end as usize + 1, blocks),
+                        )),
+                        _ => None,
+                    };
+                match range {
+                    Some((start, end)) => {
+                        for byte in &mut self.memory[start * 512..end * 512] {
+                            *byte = 0;
+                        }
+                        self.start_busy();
+                        Some(Response::R1(status))
+                    }
+                    None => Some(Response::R1(status | ERASE_SEQ_ERROR)),
+                }
+            }
+            (12, State::Sending) => {
+                self.state = State::Transfer;
+                self.fifo.clear();
+                self.data = None;
+                self.dma = None;
+                Some(Response::R1(status))
+            }
+            (12, State::Receiving) => {
+                self.data = None;
+                self.dma = None;
+                self.start_busy();
+                Some(Response::R1(status))
+            }
+            _ => None,
+        }
+    }
+
+    /// Send a command, and set the response registers and flags
+    fn cmdr(&mut self, cmdr: u32) {
+        if cmdr & cmdr::CPSMEN == 0 {
+            return;
+        }
+        let waitresp = (cmdr >> cmdr::WAITRESP_SHIFT) & 0b11;
+
+        let index = cmdr & 0x3F;
+        if self.corrupt_cmd == Some(index) {
+            // The card executes the command, but the response fails its
+            // CRC check
+            self.corrupt_cmd = None;
+            let _ = self.command(index, self.argr);
+            self.star |= star::CCRCFAIL;
+            return;
+        }
+
+        self.resp = [0; 4];
+        match self.command(index, self.argr) {
+            _ if waitresp == 0 => self.star |= star::CMDSENT,
+            None => self.star |= star::CTIMEOUT,
+            Some(Response::R2(r)) => {
+                self.resp = [
+                    (r >> 96) as u32,
+                    (r >> 64) as u32,
+                    (r >> 32) as u32,
+                    r as u32,
+                ];
+                self.star |= star::CMDREND;
+            }
+            Some(Response::R3(r)) => {
+                self.resp[0] = r;
+                self.star |= star::CCRCFAIL;
+            }
+            Some(Response::R1(r))
+            | Some(Response::R6(r))
+            | Some(Response::R7(r)) => {
+                self.resp[0] = r;
+                self.star |= star::CMDREND;
+            }
+        }
+    }
+
+    /// The host read a word from the FIFO
+    fn fifo_read(&mut self) -> u32 {
+        let word = self.fifo.pop_front().unwrap_or(0);
+        if self.fifo.is_empty() {
+            self.data_end();
+        }
+        word
+    }
+
+    /// The host wrote a word to the FIFO
+    fn fifo_write(&mut self, word: u32) {
+        let done = match self.data {
+            Some(ref mut data) => {
+                data.received.extend_from_slice(&word.to_le_bytes());
+                data.received.len() >= data.length
+            }
+            None => false,
+        };
+
+        if done && self.data_errors > 0 {
+            // The card is left receiving
+            self.data = None;
+            self.star |= self.data_error;
+            self.data_errors -= 1;
+        } else if done {
+            let data = self.data.take().unwrap();
+            self.memory[data.address..data.address + data.length]
+                .copy_from_slice(&data.received[..data.length]);
+            if !data.multiple {
+                self.start_busy();
+            }
+            self.data_end();
+        }
+    }
+
+    /// All the data for the current transfer has been sent or received
+    fn data_end(&mut self) {
+        self.star |= star::DATAEND | star::DBCKEND;
+        self.dctrl &= !dctrl::DTEN;
+    }
+
+    fn read_star(&mut self) -> u32 {
+        self.dma_transfer();
+
+        match self.busy {
+            Some((0, end)) => {
+                self.busy = None;
+                self.state = State::Transfer;
+                self.star |= end;
+            }
+            Some((reads, end)) => {
+                self.busy = Some((reads - 1, end));
+                self.star |= star::BUSYD0;
+            }
+            None => self.star &= !star::BUSYD0,
+        }
+
+        let mut star = self.star;
+        if self.fifo.len() >= 8 {
+            star |= star::RXFIFOHF;
+        }
+        if self.fifo.is_empty() {
+            star |= star::RXFIFOE;
+        }
+        if self.data.is_some() {
+            star |= star::TXFIFOHE;
+        }
+        star
+    }
+}
+
+/// A simulated SDMMC peripheral with a SDHC card. Clones share the same
+/// peripheral and card
+#[derive(Clone)]
+pub struct SimCard {
+    sim: Rc<RefCell<Sim>>,
+}
+
+impl SimCard {
+    /// A card of `size` bytes, which must be a multiple of 512kB
+    pub fn new(size: usize) -> Self {
+        assert!(size > 0 && size % (512 * 1024) == 0);
+
+        SimCard {
+            sim: Rc::new(RefCell::new(Sim {
+                power: 0,
+                clkcr: 0,
+                argr: 0,
+                resp: [0; 4],
+                dtimer: 0,
+                dlenr: 0,
+                dctrl: 0,
+                star: 0,
+                maskr: 0,
+                fifo: VecDeque::new(),
+                data: None,
+                idmactrl: 0,
+                idmabsize: 0,
+                idmabase: [0; 2],
+                dma: None,
+                dma_buffers: Vec::new(),
+                emmc: false,
+                sdsc: false,
+                ext_csd: [0; 512],
+                rca: RCA,
+                switch_error: false,
+                state: State::Idle,
+                app_cmd: false,
+                power_up: 1,
+                block_length: 512,
+                bus_width: BusWidth::One,
+                memory: vec![0; size],
+                present: true,
+                bounce: VecDeque::new(),
+                data_error: 0,
+                data_errors: 0,
+                corrupt_cmd: None,
+                erase_start: None,
+                erase_end: None,
+                program_ms: 0,
+                busy: None,
+                commands: Vec::new(),
+            })),
+        }
+    }
+
+    /// An eMMC device of `size` bytes
+    pub fn emmc(size: usize) -> Self {
+        let card = Self::new(size);
+        {
+            let mut sim = card.sim.borrow_mut();
+            sim.emmc = true;
+            sim.ext_csd = Sim::ext_csd(size);
+        }
+        card
+    }
+
+    /// A version 1.x SDSC card of `size` bytes, which must be a multiple
+    /// of 512kB and at most 1GB
+    pub fn sdsc(size: usize) -> Self {
+        assert!(size <= 1024 * 1024 * 1024);
+        let card = Self::new(size);
+        card.sim.borrow_mut().sdsc = true;
+        card
+    }
+
+    /// Set byte `index` of the Extended CSD of an eMMC device
+    pub fn set_ext_csd(&self, index: usize, value: u8) {
+        self.sim.borrow_mut().ext_csd[index] = value;
+    }
+
+    /// Byte `index` of the Extended CSD of an eMMC device
+    pub fn ext_csd(&self, index: usize) -> u8 {
+        self.sim.borrow().ext_csd[index]
+    }
+
+    /// A zeroed, word aligned buffer of `length` bytes that the IDMA can
+    /// access. The buffer is leaked
+    pub fn dma_buffer(&self, length: usize) -> &'static mut [u8] {
+        let words: &'static mut [u32] =
+            Box::leak(vec![0u32; (length + 3) / 4].into_boxed_slice());
+        let ptr = words.as_mut_ptr() as *mut u8;
+        self.sim
+            .borrow_mut()
+            .dma_buffers
+            .push((ptr as usize, length));
+
+        // The words are never freed, and are only accessed as bytes from
+        // now on
+        unsafe { core::slice::from_raw_parts_mut(ptr, length) }
+    }
+
+    /// No card in the slot
+    pub fn empty() -> Self {
+        let card = Self::new(512 * 1024);
+        card.sim.borrow_mut().present = false;
+        card
+    }
+
+    /// A driver for this peripheral, with a 4-bit bus
+    pub fn sdmmc(&self) -> Sdmmc<SimCard> {
+        Sdmmc::new(
+            self.clone(),
+            BusWidth::Four,
+            Hertz(KER_CK),
+            Hertz(200_000_000),
+            Hertz(400_000_000),
+        )
+    }
+
+    /// Insert or remove the card
+    pub fn set_present(&self, present: bool) {
+        let mut sim = self.sim.borrow_mut();
+        sim.present = present;
+        if !present {
+            sim.state = State::Idle;
+        }
+    }
+
+    /// Make the card detect switch read `levels` before it settles
+    pub fn bounce(&self, levels: &[bool]) {
+        self.sim.borrow_mut().bounce.extend(levels);
+    }
+
+    /// Fail the next `count` data transfers with the data path error
+    /// `flags`
+    pub fn fail_transfers(&self, flags: u32, count: u32) {
+        let mut sim = self.sim.borrow_mut();
+        sim.data_error = flags;
+        sim.data_errors = count;
+    }
+
+    /// Corrupt the response to the next command with index `index`
+    pub fn corrupt_response(&self, index: u32) {
+        self.sim.borrow_mut().corrupt_cmd = Some(index);
+    }
+
+    /// Take `ms` milliseconds to program each write or erase
+    pub fn set_program_time(&self, ms: u32) {
+        self.sim.borrow_mut().program_ms = ms;
+    }
+
+    /// Take the log of the commands received since the last call, as
+    /// `(index, argument)`. ACMDs have index `index | ACMD`
+    pub fn commands(&self) -> Vec<(u32, u32)> {
+        self.sim.borrow_mut().commands.split_off(0)
+    }
+
+    /// Busy timeout last programmed in DTIMER, in milliseconds
+    pub fn busy_timeout_ms(&self) -> u32 {
+        let sim = self.sim.borrow();
+        (u64::from(sim.dtimer) * 1000 / u64::from(sim.clock())) as u32
+    }
+
+    /// The card is in the Transfer State
+    pub fn in_transfer_state(&self) -> bool {
+        self.sim.borrow().state == State::Transfer
+    }
+
+    /// Power to the card is on
+    pub fn powered(&self) -> bool {
+        self.sim.borrow().power & power::PWRCTRL == 0b11
+    }
+
+    /// Card detect switch of the slot. Low when a card is present
+    pub fn card_detect(&self) -> CardDetectSwitch {
+        CardDetectSwitch {
+            sim: self.sim.clone(),
+        }
+    }
+
+    /// Bus width selected with ACMD6, or with CMD6 on eMMC devices
+    pub fn bus_width(&self) -> BusWidth {
+        self.sim.borrow().bus_width
+    }
+
+    /// Contents of the card
+    pub fn memory(&self) -> Vec<u8> {
+        self.sim.borrow().memory.clone()
+    }
+
+    /// Fill block `block` of the card with `value`
+    pub fn fill_block(&self, block: usize, value: u8) {
+        let mut sim = self.sim.borrow_mut();
+        for byte in &mut sim.memory[block * 512..(block + 1) * 512] {
+            *byte = value;
+        }
+    }
+}
+
+/// Card detect switch of a [`SimCard`]
+pub struct CardDetectSwitch {
+    sim: Rc<RefCell<Sim>>,
+}
+
+impl InputPin for CardDetectSwitch {
+    type Error = ();
+
+    fn is_high(&self) -> Result<bool, ()> {
+        let mut sim = self.sim.borrow_mut();
+        let present = !sim.present;
+        Ok(sim.bounce.pop_front().unwrap_or(present))
+    }
+
+    fn is_low(&self) -> Result<bool, ()> {
+        self.is_high().map(|high| !high)
+    }
+}
+
+impl SdmmcRegisters for SimCard {
+    // Buffers not allocated by dma_buffer fail with IDMATE instead
+    const IDMA_REGIONS: &'static [(u32, u32)] = &[(0, 0xFFFF_FFFF)];
+
+    fn read(&self, reg: Register) -> u32 {
+        let mut sim = self.sim.borrow_mut();
+        match reg {
+            Register::Power => sim.power,
+            Register::Clkcr => sim.clkcr,
+            Register::Argr => sim.argr,
+            Register::Resp1r => sim.resp[0],
+            Register::Resp2r => sim.resp[1],
+            Register::Resp3r => sim.resp[2],
+            Register::Resp4r => sim.resp[3],
+            Register::Dtimer => sim.dtimer,
+            Register::Dlenr => sim.dlenr,
+            Register::Dctrl => sim.dctrl,
+            Register::Star => sim.read_star(),
+            Register::Maskr => sim.maskr,
+            Register::Fifor => sim.fifo_read(),
+            Register::Idmactrlr => sim.idmactrl,
+            Register::Idmabsizer => sim.idmabsize,
+            Register::Idmabase0r => sim.idmabase[0],
+            Register::Idmabase1r => sim.idmabase[1],
+            _ => 0,
+        }
+    }
+
+    fn write(&self, reg: Register, value: u32) {
+        let mut sim = self.sim.borrow_mut();
+        match reg {
+            Register::Power => sim.power = value,
+            Register::Clkcr => sim.clkcr = value,
+            Register::Argr => sim.argr = value,
+            Register::Cmdr => sim.cmdr(value),
+            Register::Dtimer => sim.dtimer = value,
+            Register::Dlenr => sim.dlenr = value,
+            Register::Dctrl => {
+                if value & dctrl::FIFORST != 0 {
+                    sim.fifo.clear();
+                    sim.data = None;
+                    sim.dma = None;
+                }
+                sim.dctrl = value;
+            }
+            Register::Icr => sim.star &= !value,
+            Register::Maskr => sim.maskr = value,
+            Register::Fifor => sim.fifo_write(value),
+            Register::Idmactrlr => {
+                if value & idmactrlr::IDMAEN == 0 {
+                    sim.dma = None;
+                }
+                sim.idmactrl = value;
+            }
+            Register::Idmabsizer => sim.idmabsize = value,
+            Register::Idmabase0r => sim.idmabase[0] = value,
+            Register::Idmabase1r => sim.idmabase[1] = value,
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sd_registers::{CardState, CardVersion};
+    use crate::sdmmc::{
+        CardDetectPolarity, CardType, EraseMode, ErrorKind, Phase, RetryPolicy,
+        SlotState, WakerSlot,
+    };
+    use core::future::Future;
+    use core::ptr;
+    use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+    const SIZE: usize = 2 * 1024 * 1024;
+
+    /// A waker that does nothing, for polling futures by hand
+    fn noop_waker() -> Waker {
+        fn clone(_: *const ()) -> RawWaker {
+            RawWaker::new(ptr::null(), &VTABLE)
+        }
+        fn noop(_: *const ()) {}
+        static VTABLE: RawWakerVTable =
+            RawWakerVTable::new(clone, noop, noop, noop);
+
+        // The vtable functions ignore the data pointer
+        unsafe { Waker::from_raw(RawWaker::new(ptr::null(), &VTABLE)) }
+    }
+
+    /// The interrupt handler for async transfers, as
+    /// `on_interrupt_async` for SDMMC1 and SDMMC2
+    fn on_interrupt_async(card: &SimCard, waker: &WakerSlot) {
+        card.write(Register::Maskr, 0);
+        waker.wake();
+    }
+
+    /// Poll `future` until it completes, calling the interrupt handler
+    /// whenever it is pending
+    fn run_async<F: Future>(
+        card: &SimCard,
+        waker: &WakerSlot,
+        future: F,
+    ) -> F::Output {
+        let noop = noop_waker();
+        let mut cx = Context::from_waker(&noop);
+        let mut future = Box::pin(future);
+        for _ in 0..100 {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+            // The transfer interrupts are enabled while it is pending
+            assert_ne!(card.read(Register::Maskr), 0);
+            on_interrupt_async(card, waker);
+        }
+        panic!("The transfer did not complete");
+    }
+
+    #[test]
+    fn init_card() {
+        let card = SimCard::new(SIZE);
+        let mut sdmmc = card.sdmmc();
+        sdmmc.init_card(Hertz(25_000_000)).unwrap();
+
+        let info = sdmmc.card().unwrap();
+        match info.card_type {
+            CardType::SDHC => (),
+            t => panic!("Card type {:?}", t),
+        }
+        assert_eq!(info.rca, RCA);
+        assert_eq!(info.cid.oem_id(), "SD");
+        assert_eq!(info.cid.product_name(), "SIM01");
+        assert_eq!(info.cid.product_serial_number(), 0x1234_5678);
+        assert_eq!(info.size(), SIZE as u64);
+        assert!(info.scr.bus_width_four());
+
+        assert_eq!(card.bus_width(), BusWidth::Four);
+        assert_eq!(sdmmc.clock(), Hertz(25_000_000));
+    }
+
+    #[test]
+    fn sdsc_card() {
+        let card = SimCard::sdsc(SIZE);
+        card.fill_block(3, 0x55);
+        let mut sdmmc = card.sdmmc();
+        sdmmc.init_card(Hertz(25_000_000)).unwrap();
+
+        let info = sdmmc.card().unwrap();
+        match info.card_type {
+            CardType::SDSC => (),
+            t => panic!("Card type {:?}", t),
+        }
+        assert_eq!(info.version, CardVersion::V1_1);
+        assert!(!info.ocr.ccs());
+        assert_eq!(info.size(), SIZE as u64);
+
+        // Data commands take the byte address
+        let _ = card.commands();
+        let mut buffer = [0; 512];
+        sdmmc.read_block(3, &mut buffer).unwrap();
+        assert!(buffer.iter().all(|&b| b == 0x55));
+        sdmmc.write_blocks(5, &[0xAA; 2 * 512]).unwrap();
+        assert_eq!(
+            card.commands(),
+            vec![
+                (16, 512),
+                (17, 3 * 512),
+                (16, 512),
+                (55, RCA << 16),
+                (23 | ACMD, 2),
+                (25, 5 * 512),
+                (12, 0),
+            ]
+        );
+        assert!(card.memory()[5 * 512..7 * 512].iter().all(|&b| b == 0xAA));
+
+        // The byte address of block 0x80_0000 does not fit in 32 bits
+        let err = sdmmc.read_block(0x80_0000, &mut buffer).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::OutOfRange);
+        assert_eq!(err.address(), Some(0x80_0000));
+        let err = sdmmc.write_blocks(0x80_0000, &[0; 512]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::OutOfRange);
+        assert_eq!(card.commands(), vec![]);
+    }
+
+    #[test]
+    fn init_no_card() {
+        let mut sdmmc = SimCard::empty().sdmmc();
+
+        // The last attempt is at an eMMC device
+        let err = sdmmc.init_card(Hertz(25_000_000)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Timeout);
+        assert_eq!(err.cmd(), Some(1));
+        assert_eq!(err.phase(), Some(Phase::Command));
+        assert!(err.star() & star::CTIMEOUT != 0);
+        assert!(sdmmc.card().is_err());
+    }
+
+    #[test]
+    fn read_before_init() {
+        let mut sdmmc = SimCard::new(SIZE).sdmmc();
+        let mut buffer = [0; 512];
+
+        match sdmmc.read_block(0, &mut buffer).map_err(|e| e.kind()) {
+            Err(ErrorKind::NoCard) => (),
+            r => panic!("{:?}", r),
+        }
+    }
+
+    #[test]
+    fn read_block() {
+        let card = SimCard::new(SIZE);
+        card.fill_block(7, 0xA5);
+        let mut sdmmc = card.sdmmc();
+        sdmmc.init_card(Hertz(25_000_000)).unwrap();
+
+        let mut buffer = [0; 512];
+        sdmmc.read_block(7, &mut buffer).unwrap();
+        assert!(buffer.iter().all(|&b| b == 0xA5));
+        sdmmc.read_block(8, &mut buffer).unwrap();
+        assert!(buffer.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn write_block() {
+        let card = SimCard::new(SIZE);
+        let mut sdmmc = card.sdmmc();
+        sdmmc.init_card(Hertz(25_000_000)).unwrap();
+
+        let mut buffer = [0; 512];
+        for (i, b) in buffer.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        sdmmc.write_block(3, &buffer).unwrap();
+        assert_eq!(&card.memory()[3 * 512..4 * 512], &buffer[..]);
+
+        let mut read = [0; 512];
+        sdmmc.read_block(3, &mut read).unwrap();
+        assert_eq!(&read[..], &buffer[..]);
+    }
+
+    #[test]
+    fn read_write_blocks() {
+        let card = SimCard::new(SIZE);
+        let mut sdmmc = card.sdmmc();
+        sdmmc.init_card(Hertz(25_000_000)).unwrap();
+
+        let mut buffer = [0; 4 * 512];
+        for (i, b) in buffer.iter_mut().enumerate() {
+            *b = (i / 512) as u8 + 1;
+        }
+        sdmmc.write_blocks(10, &buffer).unwrap();
+
+        let mut read = [0; 6 * 512];
+        sdmmc.read_blocks(9, &mut read).unwrap();
+        assert!(read[..512].iter().all(|&b| b == 0));
+        assert_eq!(&read[512..5 * 512], &buffer[..]);
+        assert!(read[5 * 512..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn write_blocks_busy() {
+        let card = SimCard::new(SIZE);
+        let mut sdmmc = card.sdmmc();
+        sdmmc.init_card(Hertz(25_000_000)).unwrap();
+        card.set_program_time(220);
+        let _ = card.commands();
+
+        // Longer than the data timeout, but within the write timeout
+        let buffer = [0x4B; 3 * 512];
+        sdmmc.write_blocks(12, &buffer).unwrap();
+        assert_eq!(
+            card.commands(),
+            vec![
+                (16, 512),
+                (55, RCA << 16),
+                (23 | ACMD, 3),
+                (25, 12),
+                (12, 0)
+            ]
+        );
+        assert_eq!(card.busy_timeout_ms(), 250);
+        assert!(card.in_transfer_state());
+        assert_eq!(&card.memory()[12 * 512..15 * 512], &buffer[..]);
+
+        card.set_program_time(300);
+        let err = sdmmc.write_blocks(12, &buffer).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Timeout);
+        assert_eq!(err.cmd(), Some(12));
+        assert_eq!(err.phase(), Some(Phase::Busy));
+        assert_eq!(err.address(), Some(12));
+        assert!(err.star() & star::DTIMEOUT != 0);
+        assert!(card.in_transfer_state());
+    }
+
+    #[test]
+    fn erase() {
+        let card = SimCard::new(SIZE);
+        for block in 0..30 {
+            card.fill_block(block, 0xFF);
+        }
+        let mut sdmmc = card.sdmmc();
+        sdmmc.init_card(Hertz(25_000_000)).unwrap();
+        card.set_program_time(1500);
+        let _ = card.commands();
+
+        sdmmc.erase(10, 17, EraseMode::Erase).unwrap();
+        assert_eq!(card.commands(), vec![(32, 10), (33, 17), (38, 0)]);
+        // ERASE_BLK_EN is set, so each block is an erase unit
+        assert_eq!(card.busy_timeout_ms(), 8 * 250);
+        assert!(card.in_transfer_state());
+        let memory = card.memory();
+        assert!(memory[..10 * 512].iter().all(|&b| b == 0xFF));
+        assert!(memory[10 * 512..18 * 512].iter().all(|&b| b == 0));
+        assert!(memory[18 * 512..30 * 512].iter().all(|&b| b == 0xFF));
+
+        // The card has no DISCARD_SUPPORT in its SD Status
+        match sdmmc.erase(0, 1, EraseMode::Discard).map_err(|e| e.kind()) {
+            Err(ErrorKind::UnsupportedEraseMode) => (),
+            r => panic!("{:?}", r),
+        }
+        assert!(card.commands().is_empty());
+    }
+
+    #[test]
+    fn erase_errors() {
+        let card = SimCard::new(SIZE);
+        let mut sdmmc = card.sdmmc();
+        sdmmc.init_card(Hertz(25_000_000)).unwrap();
+
+        let err = sdmmc.erase(20, 10, EraseMode::Erase).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::OutOfRange);
+        assert_eq!(err.address(), Some(20));
+
+        // The busy timeout is never less than 1s
+        card.set_program_time(1200);
+        let err = sdmmc.erase(0, 1, EraseMode::Erase).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Timeout);
+        assert_eq!(err.cmd(), Some(38));
+        assert_eq!(err.phase(), Some(Phase::Busy));
+        assert!(card.in_transfer_state());
+
+        // The erase is not retried on its own
+        card.set_program_time(0);
+        card.corrupt_response(33);
+        let err = sdmmc.erase(0, 10, EraseMode::Erase).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Crc);
+        assert_eq!(err.cmd(), Some(33));
+        sdmmc.erase(0, 10, EraseMode::Erase).unwrap();
+    }
+
+    #[test]
+    fn init_emmc() {
+        let card = SimCard::emmc(SIZE);
+        let mut sdmmc = card.sdmmc();
+        sdmmc.init_card(Hertz(25_000_000)).unwrap();
+
+        let info = sdmmc.card().unwrap();
+        match info.card_type {
+            CardType::EMMC => (),
+            t => panic!("Card type {:?}", t),
+        }
+        assert_eq!(info.rca, 1);
+        assert_eq!(info.size(), SIZE as u64);
+        assert!(info.ext_csd.supports_sanitize());
+
+        assert!(info.ext_csd.erase_group_def());
+
+        assert_eq!(card.bus_width(), BusWidth::Four);
+        assert_eq!(card.ext_csd(183), 1);
+        assert_eq!(card.ext_csd(175), 1);
+    }
+
+    #[test]
+    fn secure_erase() {
+        let card = SimCard::emmc(SIZE);
+        for block in 0..30 {
+            card.fill_block(block, 0xFF);
+        }
+        let mut sdmmc = card.sdmmc();
+        sdmmc.init_card(Hertz(25_000_000)).unwrap();
+        card.set_program_time(1500);
+        let _ = card.commands();
+
+        sdmmc.erase(10, 17, EraseMode::SecureErase).unwrap();
+        assert_eq!(
+            card.commands(),
+            vec![(35, 10), (36, 17), (38, 0x8000_0000)]
+        );
+        // One erase group, at SEC_ERASE_MULT times the erase timeout
+        assert_eq!(card.busy_timeout_ms(), 2 * 900);
+        assert!(card.in_transfer_state());
+        let memory = card.memory();
+        assert!(memory[..10 * 512].iter().all(|&b| b == 0xFF));
+        assert!(memory[10 * 512..18 * 512].iter().all(|&b| b == 0));
+        assert!(memory[18 * 512..30 * 512].iter().all(|&b| b == 0xFF));
+
+        card.set_program_time(2000);
+        let err = sdmmc.erase(10, 17, EraseMode::SecureErase).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Timeout);
+        assert_eq!(err.cmd(), Some(38));
+        assert_eq!(err.phase(), Some(Phase::Busy));
+        assert!(card.in_transfer_state());
+
+        // SD erase modes are not available on eMMC devices
+        match sdmmc.erase(0, 1, EraseMode::FULE).map_err(|e| e.kind()) {
+            Err(ErrorKind::UnsupportedEraseMode) => (),
+            r => panic!("{:?}", r),
+        }
+    }
+
+    #[test]
+    fn sanitize() {
+        let card = SimCard::emmc(SIZE);
+        let mut sdmmc = card.sdmmc();
+        sdmmc.init_card(Hertz(25_000_000)).unwrap();
+        card.set_program_time(1500);
+        let _ = card.commands();
+
+        sdmmc.erase(0, 0, EraseMode::Sanitize).unwrap();
+        assert_eq!(card.commands(), vec![(6, 0x03A5_0100), (13, 1 << 16)]);
+        assert!(card.in_transfer_state());
+
+        // Longer than DTIMER can hold at this clock
+        card.set_program_time(200_000);
+        sdmmc.erase(0, 0, EraseMode::Sanitize).unwrap();
+        assert!(card.busy_timeout_ms() < 200_000);
+        assert_eq!(card.commands(), vec![(6, 0x03A5_0100), (13, 1 << 16)]);
+
+        card.corrupt_response(6);
+        let err = sdmmc.erase(0, 0, EraseMode::Sanitize).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Crc);
+        assert_eq!(err.cmd(), Some(6));
+    }
+
+    #[test]
+    fn secure_erase_unsupported() {
+        let card = SimCard::emmc(SIZE);
+        card.set_ext_csd(231, 0x10);
+        let mut sdmmc = card.sdmmc();
+        sdmmc.init_card(Hertz(25_000_000)).unwrap();
+        let _ = card.commands();
+
+        for &mode in &[EraseMode::SecureErase, EraseMode::Sanitize] {
+            match sdmmc.erase(0, 1, mode).map_err(|e| e.kind()) {
+                Err(ErrorKind::UnsupportedEraseMode) => (),
+                r => panic!("{:?}", r),
+            }
+        }
+        assert!(card.commands().is_empty());
+        sdmmc.erase(0, 1, EraseMode::Trim).unwrap();
+    }
+
+    #[test]
+    fn read_write_blocks_dma() {
+        let card = SimCard::new(SIZE);
+        let mut sdmmc = card.sdmmc();
+        sdmmc.init_card(Hertz(25_000_000)).unwrap();
+        let _ = card.commands();
+
+        let buffer = card.dma_buffer(3 * 512);
+        for (i, b) in buffer.iter_mut().enumerate() {
+            *b = (i / 512) as u8 + 1;
+        }
+        sdmmc.write_blocks_dma(20, buffer).unwrap();
+        assert_eq!(&card.memory()[20 * 512..23 * 512], &buffer[..]);
+        assert_eq!(
+            card.commands(),
+            vec![
+                (16, 512),
+                (55, RCA << 16),
+                (23 | ACMD, 3),
+                (25, 20),
+                (12, 0)
+            ]
+        );
+
+        let read = card.dma_buffer(4 * 512);
+        sdmmc.read_blocks_dma(19, read).unwrap();
+        assert!(read[..512].iter().all(|&b| b == 0));
+        assert_eq!(&read[512..], &buffer[..]);
+        assert!(card.in_transfer_state());
+    }
+
+    #[test]
+    fn dma_errors() {
+        let card = SimCard::new(SIZE);
+        let mut sdmmc = card.sdmmc();
+        sdmmc.init_card(Hertz(25_000_000)).unwrap();
+
+        // Not word aligned
+        let buffer = card.dma_buffer(513);
+        match sdmmc
+            .read_blocks_dma(0, &mut buffer[1..])
+            .map_err(|e| e.kind())
+        {
+            Err(ErrorKind::BadBuffer) => (),
+            r => panic!("{:?}", r),
+        }
+
+        // The IDMA cannot access this buffer
+        let mut buffer = vec![0u32; 128];
+        let bytes = unsafe {
+            core::slice::from_raw_parts_mut(buffer.as_mut_ptr() as *mut u8, 512)
+        };
+        let err = sdmmc.read_blocks_dma(0, bytes).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::DmaError);
+        assert_eq!(err.cmd(), Some(18));
+        assert_eq!(err.address(), Some(0));
+        assert!(card.in_transfer_state());
+
+        let buffer = card.dma_buffer(2 * 512);
+        card.fail_transfers(star::DCRCFAIL, 1);
+        let err = sdmmc.write_blocks_dma(4, buffer).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::DataCrcFail);
+        assert_eq!(err.cmd(), Some(25));
+        assert_eq!(err.address(), Some(4));
+        sdmmc.write_blocks_dma(4, buffer).unwrap();
+    }
+
+    #[test]
+    fn read_write_blocks_stream() {
+        let card = SimCard::new(SIZE);
+        let mut sdmmc = card.sdmmc();
+        sdmmc.init_card(Hertz(25_000_000)).unwrap();
+        let _ = card.commands();
+
+        // Four buffers of two blocks
+        let buffer0 = card.dma_buffer(2 * 512);
+        let buffer1 = card.dma_buffer(2 * 512);
+        let mut n = 0;
+        sdmmc
+            .write_blocks_stream(40, 8, buffer0, buffer1, |buffer| {
+                n += 1;
+                for b in buffer.iter_mut() {
+                    *b = n;
+                }
+            })
+            .unwrap();
+        assert_eq!(n, 4);
+        assert_eq!(
+            card.commands(),
+            vec![
+                (16, 512),
+                (55, RCA << 16),
+                (23 | ACMD, 8),
+                (25, 40),
+                (12, 0)
+            ]
+        );
+        let memory = card.memory();
+        for (i, block) in memory[40 * 512..48 * 512].chunks(1024).enumerate() {
+            assert!(block.iter().all(|&b| b == i as u8 + 1));
+        }
+
+        let mut read = Vec::new();
+        sdmmc
+            .read_blocks_stream(40, 8, buffer0, buffer1, |buffer| {
+                read.extend_from_slice(buffer)
+            })
+            .unwrap();
+        assert_eq!(&read[..], &memory[40 * 512..48 * 512]);
+        assert!(card.in_transfer_state());
+    }
+
+    #[test]
+    fn stream_errors() {
+        let card = SimCard::new(SIZE);
+        let mut sdmmc = card.sdmmc();
+        sdmmc.init_card(Hertz(25_000_000)).unwrap();
+
+        // The IDMA cannot access the second buffer
+        let buffer0 = card.dma_buffer(512);
+        let mut words = vec![0u32; 128];
+        let buffer1 = unsafe {
+            core::slice::from_raw_parts_mut(words.as_mut_ptr() as *mut u8, 512)
+        };
+        let mut n = 0;
+        let err = sdmmc
+            .read_blocks_stream(8, 4, buffer0, buffer1, |_| n += 1)
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::DmaError);
+        assert_eq!(err.cmd(), Some(18));
+        assert_eq!(err.address(), Some(8));
+        assert_eq!(n, 1);
+        assert!(card.in_transfer_state());
+
+        let buffer1 = card.dma_buffer(512);
+        card.fail_transfers(star::DCRCFAIL, 1);
+        let err = sdmmc
+            .read_blocks_stream(8, 4, buffer0, buffer1, |_| ())
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::DataCrcFail);
+        assert_eq!(err.cmd(), Some(18));
+        sdmmc
+            .read_blocks_stream(8, 4, buffer0, buffer1, |_| ())
+            .unwrap();
+    }
+
+    #[test]
+    fn non_blocking_transfer() {
+        let card = SimCard::new(SIZE);
+        card.fill_block(5, 0x5A);
+        card.fill_block(6, 0x5A);
+        let mut sdmmc = card.sdmmc();
+        sdmmc.init_card(Hertz(25_000_000)).unwrap();
+        let _ = card.commands();
+
+        // Each command is sent by the interrupt handler when the response
+        // to the previous one arrives
+        let transfer = sdmmc.start_read_blocks(5, card.dma_buffer(2 * 512));
+        assert_eq!(card.commands(), vec![(16, 512)]);
+        sdmmc.on_interrupt();
+        assert_eq!(card.commands(), vec![(18, 5)]);
+        sdmmc.on_interrupt();
+        assert!(card.commands().is_empty());
+        sdmmc.on_interrupt();
+        assert_eq!(card.commands(), vec![(12, 0)]);
+        let transfer = transfer.poll(&mut sdmmc).unwrap_err();
+        sdmmc.on_interrupt();
+        let (buffer, result) = transfer.poll(&mut sdmmc).unwrap();
+        result.unwrap();
+        assert!(buffer.iter().all(|&b| b == 0x5A));
+
+        card.set_program_time(100);
+        let transfer = sdmmc.start_write_blocks(30, buffer);
+        let (buffer, result) = transfer.wait(&mut sdmmc);
+        result.unwrap();
+        assert_eq!(&card.memory()[30 * 512..32 * 512], &buffer[..]);
+        assert_eq!(
+            card.commands(),
+            vec![
+                (16, 512),
+                (55, RCA << 16),
+                (23 | ACMD, 2),
+                (25, 30),
+                (12, 0)
+            ]
+        );
+        assert!(card.in_transfer_state());
+    }
+
+    #[test]
+    fn non_blocking_transfer_errors() {
+        let card = SimCard::new(SIZE);
+        let mut sdmmc = card.sdmmc();
+        sdmmc.init_card(Hertz(25_000_000)).unwrap();
+        let _ = card.commands();
+
+        // The data path is recovered when the result is taken, not in the
+        // interrupt handler
+        card.fail_transfers(star::DCRCFAIL, 1);
+        let transfer = sdmmc.start_read_blocks(3, card.dma_buffer(512));
+        for _ in 0..3 {
+            sdmmc.on_interrupt();
+        }
+        assert_eq!(card.commands(), vec![(16, 512), (18, 3)]);
+        let (buffer, result) = transfer.poll(&mut sdmmc).unwrap();
+        let err = result.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::DataCrcFail);
+        assert_eq!(err.cmd(), Some(18));
+        assert_eq!(err.phase(), Some(Phase::Data));
+        assert_eq!(err.address(), Some(3));
+        assert_eq!(card.commands(), vec![(12, 0), (13, RCA << 16)]);
+        assert!(card.in_transfer_state());
+
+        // Only one transfer at a time
+        let transfer = sdmmc.start_read_blocks(3, buffer);
+        let other = sdmmc.start_read_blocks(4, card.dma_buffer(512));
+        let (_, result) = other.poll(&mut sdmmc).unwrap();
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::Busy);
+
+        // The blocking methods do not touch the card either
+        let _ = card.commands();
+        let mut block = [0; 512];
+        let err = sdmmc.read_block(5, &mut block).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Busy);
+        assert_eq!(err.address(), Some(5));
+        let err = sdmmc.erase(5, 6, EraseMode::Erase).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Busy);
+        assert_eq!(card.commands(), vec![]);
+        let (buffer, result) = transfer.wait(&mut sdmmc);
+        result.unwrap();
+
+        // A failed command ends the transfer
+        let _ = card.commands();
+        card.corrupt_response(23);
+        let transfer = sdmmc.start_write_blocks(3, buffer);
+        let (_, result) = transfer.wait(&mut sdmmc);
+        let err = result.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Crc);
+        assert_eq!(err.cmd(), Some(23));
+        assert!(err.app_cmd());
+        assert_eq!(err.address(), Some(3));
+        assert_eq!(
+            card.commands(),
+            vec![(16, 512), (55, RCA << 16), (23 | ACMD, 1)]
+        );
+        assert!(card.in_transfer_state());
+    }
+
+    #[test]
+    fn async_transfer() {
+        let card = SimCard::new(SIZE);
+        card.fill_block(5, 0x5A);
+        let mut sdmmc = card.sdmmc();
+        sdmmc.init_card(Hertz(25_000_000)).unwrap();
+        let waker = WakerSlot::new();
+        let _ = card.commands();
+
+        let (buffer, result) = run_async(
+            &card,
+            &waker,
+            sdmmc.read_blocks_waker(5, card.dma_buffer(512), &waker),
+        );
+        result.unwrap();
+        assert!(buffer.iter().all(|&b| b == 0x5A));
+        assert_eq!(card.commands(), vec![(16, 512), (18, 5), (12, 0)]);
+        assert_eq!(card.read(Register::Maskr), 0);
+
+        let buffer: &'static [u8] = buffer;
+        let result = run_async(
+            &card,
+            &waker,
+            sdmmc.write_blocks_waker(9, buffer, &waker),
+        );
+        result.unwrap();
+        assert!(card.memory()[9 * 512..10 * 512].iter().all(|&b| b == 0x5A));
+        assert!(card.in_transfer_state());
+    }
+
+    #[test]
+    fn async_transfer_errors() {
+        let card = SimCard::new(SIZE);
+        let mut sdmmc = card.sdmmc();
+        sdmmc.init_card(Hertz(25_000_000)).unwrap();
+        let waker = WakerSlot::new();
+
+        card.fail_transfers(star::DCRCFAIL, 1);
+        let (buffer, result) = run_async(
+            &card,
+            &waker,
+            sdmmc.read_blocks_waker(3, card.dma_buffer(512), &waker),
+        );
+        let err = result.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::DataCrcFail);
+        assert_eq!(err.cmd(), Some(18));
+        assert_eq!(err.phase(), Some(Phase::Data));
+        assert_eq!(err.address(), Some(3));
+        assert!(card.in_transfer_state());
+
+        // A transfer that cannot start fails on the first poll
+        let transfer = sdmmc.start_read_blocks(4, card.dma_buffer(512));
+        let (_, result) = run_async(
+            &card,
+            &waker,
+            sdmmc.read_blocks_waker(3, buffer, &waker),
+        );
+        let err = result.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Busy);
+        assert_eq!(err.address(), Some(3));
+        let (_, result) = transfer.wait(&mut sdmmc);
+        result.unwrap();
+    }
+
+    #[test]
+    fn read_out_of_range() {
+        let card = SimCard::new(SIZE);
+        let mut sdmmc = card.sdmmc();
+        sdmmc.init_card(Hertz(25_000_000)).unwrap();
+
+        let mut buffer = [0; 512];
+        let block = (SIZE / 512) as u32;
+        let err = sdmmc.read_block(block, &mut buffer).unwrap_err();
+        match err.kind() {
+            ErrorKind::AddressError(r1) => {
+                assert!(r1.out_of_range());
+                assert_eq!(r1.current_state(), CardState::Transfer);
+            }
+            r => panic!("{:?}", r),
+        }
+        assert_eq!(err.cmd(), Some(17));
+        assert_eq!(err.phase(), Some(Phase::Command));
+        assert_eq!(err.address(), Some(block));
+
+        // The data path was recovered
+        card.fill_block(block as usize - 1, 0x5A);
+        sdmmc.read_block(block - 1, &mut buffer).unwrap();
+        assert!(buffer.iter().all(|&b| b == 0x5A));
+    }
+
+    #[test]
+    fn card_detect_hot_plug() {
+        let card = SimCard::new(SIZE);
+        card.set_present(false);
+        let mut sdmmc = card.sdmmc().with_card_detect(
+            card.card_detect(),
+            CardDetectPolarity::ActiveLow,
+            10,
+        );
+        assert_eq!(sdmmc.slot_state(), SlotState::Absent);
+        match sdmmc.init_card(Hertz(25_000_000)).map_err(|e| e.kind()) {
+            Err(ErrorKind::NoCard) => (),
+            r => panic!("{:?}", r),
+        }
+
+        card.set_present(true);
+        assert_eq!(sdmmc.poll_card_detect(), SlotState::Inserted);
+        sdmmc.init_card(Hertz(25_000_000)).unwrap();
+        assert_eq!(sdmmc.poll_card_detect(), SlotState::Initialized);
+        assert!(card.powered());
+
+        card.set_present(false);
+        assert_eq!(sdmmc.poll_card_detect(), SlotState::Removed);
+        assert!(sdmmc.card().is_err());
+        assert!(!card.powered());
+        assert_eq!(sdmmc.poll_card_detect(), SlotState::Absent);
+
+        let mut buffer = [0; 512];
+        match sdmmc.read_block(0, &mut buffer).map_err(|e| e.kind()) {
+            Err(ErrorKind::NoCard) => (),
+            r => panic!("{:?}", r),
+        }
+    }
+
+    #[test]
+    fn card_detect_debounce() {
+        let card = SimCard::new(SIZE);
+        let mut sdmmc = card.sdmmc().with_card_detect(
+            card.card_detect(),
+            CardDetectPolarity::ActiveLow,
+            3,
+        );
+        assert!(sdmmc.is_card_present());
+
+        // A glitch shorter than the debounce time is ignored
+        card.bounce(&[true, true, false]);
+        assert!(sdmmc.is_card_present());
+
+        card.set_present(false);
+        card.bounce(&[true, false]);
+        assert!(sdmmc.is_card_present());
+        assert!(!sdmmc.is_card_present());
+    }
+
+    #[test]
+    fn card_removed_during_transfer() {
+        let card = SimCard::new(SIZE);
+        let mut sdmmc = card.sdmmc().with_card_detect(
+            card.card_detect(),
+            CardDetectPolarity::ActiveLow,
+            0,
+        );
+        sdmmc.init_card(Hertz(25_000_000)).unwrap();
+        let mut buffer = [0; 512];
+
+        // Removed before the transfer
+        card.set_present(false);
+        match sdmmc.read_block(0, &mut buffer).map_err(|e| e.kind()) {
+            Err(ErrorKind::NoCard) => (),
+            r => panic!("{:?}", r),
+        }
+        assert_eq!(sdmmc.slot_state(), SlotState::Removed);
+
+        card.set_present(true);
+        assert_eq!(sdmmc.poll_card_detect(), SlotState::Inserted);
+        sdmmc.init_card(Hertz(25_000_000)).unwrap();
+
+        // Removed after the card detect input was checked
+        card.set_present(false);
+        card.bounce(&[false]);
+        let err = sdmmc.write_block(0, &buffer).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NoCard);
+        assert_eq!(err.cmd(), Some(16));
+        assert_eq!(err.address(), Some(0));
+        assert_eq!(sdmmc.slot_state(), SlotState::Removed);
+    }
+
+    #[test]
+    fn no_card_detect() {
+        let card = SimCard::new(SIZE);
+        let mut sdmmc = card.sdmmc();
+        assert!(sdmmc.is_card_present());
+        assert_eq!(sdmmc.poll_card_detect(), SlotState::Inserted);
+
+        sdmmc.init_card(Hertz(25_000_000)).unwrap();
+        assert_eq!(sdmmc.poll_card_detect(), SlotState::Initialized);
+    }
+
+    #[test]
+    fn recover_read_error() {
+        let card = SimCard::new(SIZE);
+        card.fill_block(1, 0x5A);
+        let mut sdmmc = card.sdmmc();
+        sdmmc.init_card(Hertz(25_000_000)).unwrap();
+
+        let mut buffer = [0; 4 * 512];
+        card.fail_transfers(star::DCRCFAIL, 1);
+        let err = sdmmc.read_blocks(0, &mut buffer).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::DataCrcFail);
+        assert_eq!(err.cmd(), Some(18));
+        assert_eq!(err.phase(), Some(Phase::Data));
+        assert_eq!(err.address(), Some(0));
+        assert!(err.star() & star::DCRCFAIL != 0);
+        assert!(card.in_transfer_state());
+
+        card.fail_transfers(star::DTIMEOUT, 1);
+        let mut block = [0; 512];
+        match sdmmc.read_block(1, &mut block).map_err(|e| e.kind()) {
+            Err(ErrorKind::Timeout) => (),
+            r => panic!("{:?}", r),
+        }
+
+        sdmmc.read_blocks(0, &mut buffer).unwrap();
+        assert!(buffer[512..1024].iter().all(|&b| b == 0x5A));
+    }
+
+    #[test]
+    fn recover_write_error() {
+        let card = SimCard::new(SIZE);
+        let mut sdmmc = card.sdmmc();
+        sdmmc.init_card(Hertz(25_000_000)).unwrap();
+
+        let buffer = [0xC3; 2 * 512];
+        card.fail_transfers(star::DCRCFAIL, 1);
+        match sdmmc.write_blocks(4, &buffer).map_err(|e| e.kind()) {
+            Err(ErrorKind::DataCrcFail) => (),
+            r => panic!("{:?}", r),
+        }
+        assert!(card.in_transfer_state());
+        assert!(card.memory()[4 * 512..6 * 512].iter().all(|&b| b == 0));
+
+        sdmmc.write_blocks(4, &buffer).unwrap();
+        assert_eq!(&card.memory()[4 * 512..6 * 512], &buffer[..]);
+    }
+
+    #[test]
+    fn no_retries() {
+        let card = SimCard::new(SIZE);
+        let mut sdmmc = card.sdmmc();
+
+        // ACMD6
+        card.corrupt_response(6);
+        let err = sdmmc.init_card(Hertz(25_000_000)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Crc);
+        assert_eq!(err.cmd(), Some(6));
+        assert!(err.app_cmd());
+        assert!(err.star() & star::CCRCFAIL != 0);
+    }
+
+    #[test]
+    fn retry_app_command() {
+        let card = SimCard::new(SIZE);
+        let mut sdmmc = card.sdmmc();
+        sdmmc.set_retry_policy(RetryPolicy {
+            command_attempts: 2,
+            ..RetryPolicy::default()
+        });
+
+        // ACMD6 is retried after CMD55
+        card.corrupt_response(6);
+        sdmmc.init_card(Hertz(25_000_000)).unwrap();
+        assert_eq!(card.bus_width(), BusWidth::Four);
+        assert_eq!(sdmmc.retry_stats().command_retries, 1);
+    }
+
+    #[test]
+    fn retry_transfers() {
+        let card = SimCard::new(SIZE);
+        card.fill_block(2, 0x3C);
+        let mut sdmmc = card.sdmmc();
+        sdmmc.init_card(Hertz(25_000_000)).unwrap();
+        sdmmc.set_retry_policy(RetryPolicy {
+            block_attempts: 2,
+            transfer_attempts: 3,
+            ..RetryPolicy::default()
+        });
+
+        let mut block = [0; 512];
+        card.fail_transfers(star::DCRCFAIL, 1);
+        sdmmc.read_block(2, &mut block).unwrap();
+        assert!(block.iter().all(|&b| b == 0x3C));
+
+        let buffer = [0x77; 2 * 512];
+        card.fail_transfers(star::DTIMEOUT, 1);
+        sdmmc.write_blocks(6, &buffer).unwrap();
+        assert_eq!(&card.memory()[6 * 512..8 * 512], &buffer[..]);
+
+        let stats = sdmmc.retry_stats();
+        assert_eq!(stats.block_retries, 1);
+        assert_eq!(stats.transfer_retries, 1);
+        assert_eq!(stats.data_crc_errors, 1);
+        assert_eq!(stats.clock_step_downs, 0);
+
+        sdmmc.clear_retry_stats();
+        assert_eq!(sdmmc.retry_stats().block_retries, 0);
+    }
+
+    #[test]
+    fn retry_data_command() {
+        let card = SimCard::new(SIZE);
+        card.fill_block(1, 0x99);
+        let mut sdmmc = card.sdmmc();
+        sdmmc.init_card(Hertz(25_000_000)).unwrap();
+        sdmmc.set_retry_policy(RetryPolicy {
+            command_attempts: 3,
+            ..RetryPolicy::default()
+        });
+
+        // The card is already sending when the response fails its CRC
+        // check, so CMD18 is not retried on its own
+        let mut buffer = [0; 2 * 512];
+        card.corrupt_response(18);
+        let err = sdmmc.read_blocks(0, &mut buffer).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Crc);
+        assert_eq!(err.cmd(), Some(18));
+        assert_eq!(err.phase(), Some(Phase::Command));
+        assert!(card.in_transfer_state());
+        assert_eq!(sdmmc.retry_stats().command_retries, 0);
+
+        // The whole transfer is retried instead
+        sdmmc.set_retry_policy(RetryPolicy {
+            command_attempts: 3,
+            transfer_attempts: 2,
+            ..RetryPolicy::default()
+        });
+        card.corrupt_response(18);
+        sdmmc.read_blocks(0, &mut buffer).unwrap();
+        assert!(buffer[512..].iter().all(|&b| b == 0x99));
+
+        let stats = sdmmc.retry_stats();
+        assert_eq!(stats.command_retries, 0);
+        assert_eq!(stats.transfer_retries, 1);
+    }
+
+    #[test]
+    fn retry_register_read() {
+        let card = SimCard::new(SIZE);
+        let mut sdmmc = card.sdmmc();
+        sdmmc.init_card(Hertz(25_000_000)).unwrap();
+        sdmmc.set_retry_policy(RetryPolicy {
+            command_attempts: 3,
+            ..RetryPolicy::default()
+        });
+
+        // CMD6 reads the switch status over the data lines, so it is not
+        // retried on its own either
+        card.corrupt_response(6);
+        let err = sdmmc.switch_status().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Crc);
+        assert_eq!(err.cmd(), Some(6));
+        assert!(!err.app_cmd());
+        assert_eq!(err.phase(), Some(Phase::Command));
+        assert_eq!(sdmmc.retry_stats().command_retries, 0);
+
+        // The same applies to ACMD51 during initialisation
+        let card = SimCard::new(SIZE);
+        let mut sdmmc = card.sdmmc();
+        sdmmc.set_retry_policy(RetryPolicy {
+            command_attempts: 3,
+            ..RetryPolicy::default()
+        });
+        card.corrupt_response(51);
+        let err = sdmmc.init_card(Hertz(25_000_000)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Crc);
+        assert_eq!(err.cmd(), Some(51));
+        assert!(err.app_cmd());
+        assert_eq!(sdmmc.retry_stats().command_retries, 0);
+    }
+
+    #[test]
+    fn clock_step_down() {
+        let card = SimCard::new(SIZE);
+        let mut sdmmc = card.sdmmc();
+        sdmmc.init_card(Hertz(25_000_000)).unwrap();
+        sdmmc.set_retry_policy(RetryPolicy {
+            transfer_attempts: 3,
+            clock_step_down: Some(2),
+            ..RetryPolicy::default()
+        });
+
+        // Errors that are not consecutive do not step down the clock
+        let mut buffer = [0; 2 * 512];
+        for _ in 0..2 {
+            card.fail_transfers(star::DCRCFAIL, 1);
+            sdmmc.read_blocks(0, &mut buffer).unwrap();
+        }
+        assert_eq!(sdmmc.clock(), Hertz(25_000_000));
+
+        card.fail_transfers(star::DCRCFAIL, 2);
+        sdmmc.read_blocks(0, &mut buffer).unwrap();
+        assert_eq!(sdmmc.clock(), Hertz(12_500_000));
+
+        let stats = sdmmc.retry_stats();
+        assert_eq!(stats.transfer_retries, 4);
+        assert_eq!(stats.data_crc_errors, 4);
+        assert_eq!(stats.clock_step_downs, 1);
+    }
+}
@@ -0,0 +1,236 @@
+//! [`ReadStorage`] and [`Storage`] implementations for
+//! [embedded-storage](https://crates.io/crates/embedded-storage)
+//!
+//! [`ReadStorage`]: https://docs.rs/embedded-storage/0.3.0/embedded_storage/trait.ReadStorage.html
+//! [`Storage`]: https://docs.rs/embedded-storage/0.3.0/embedded_storage/trait.Storage.html
+
+use core::cmp;
+use core::convert::TryFrom;
+use core::fmt;
+
+use embedded_storage::{ReadStorage, Storage};
+
+use crate::registers::SdmmcRegisters;
+use crate::sdmmc::{CardDetect, Error, ErrorKind, NoCardDetect, Sdmmc};
+
+/// An SDMMC peripheral used as embedded-storage `ReadStorage` and
+/// `Storage`, with byte offsets and lengths.
+///
+/// Parts of 512-byte blocks are written by reading the block, modifying
+/// it and writing it back. The card must already be initialised.
+pub struct SdmmcStorage<SDMMC, CD = NoCardDetect> {
+    sdmmc: Sdmmc<SDMMC, CD>,
+    /// Block buffer for partial reads and writes
+    block: [u8; 512],
+}
+
+impl<SDMMC, CD> SdmmcStorage<SDMMC, CD> {
+    /// Create a new storage adapter from an SDMMC peripheral
+    pub fn new(sdmmc: Sdmmc<SDMMC, CD>) -> Self {
+        SdmmcStorage {
+            sdmmc,
+            block: [0; 512],
+        }
+    }
+
+    /// Get a mutable reference to the SDMMC peripheral
+    pub fn sdmmc(&mut self) -> &mut Sdmmc<SDMMC, CD> {
+        &mut self.sdmmc
+    }
+
+    /// Releases the SDMMC peripheral
+    pub fn free(self) -> Sdmmc<SDMMC, CD> {
+        self.sdmmc
+    }
+}
+
+impl<SDMMC, CD> fmt::Debug for SdmmcStorage<SDMMC, CD> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SDMMC Storage")
+            .field("SDMMC", &self.sdmmc)
+            .finish()
+    }
+}
+
+impl<S: SdmmcRegisters, CD: CardDetect> SdmmcStorage<S, CD> {
+    /// Check that `length` bytes at `offset` are on the card
+    fn check_range(&self, offset: u32, length: usize) -> Result<(), Error> {
+        let size = self.sdmmc.card()?.size();
+
+        if u64::from(offset) + length as u64 > size {
+            return Err(ErrorKind::OutOfRange.into());
+        }
+        Ok(())
+    }
+
+    /// Block address and the offset within that block of byte
+    /// `position`
+    fn locate(position: u64) -> Result<(u32, usize), Error> {
+        let address = u32::try_from(position / 512)
+            .map_err(|_| Error::from(ErrorKind::OutOfRange))?;
+
+        Ok((address, (position % 512) as usize))
+    }
+}
+
+impl<S: SdmmcRegisters, CD: CardDetect> ReadStorage for SdmmcStorage<S, CD> {
+    type Error = Error;
+
+    fn read(
+        &mut self,
+        offset: u32,
+        bytes: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        self.check_range(offset, bytes.len())?;
+
+        let mut done = 0;
+        while done < bytes.len() {
+            let (address, start) =
+                Self::locate(u64::from(offset) + done as u64)?;
+            let remaining = bytes.len() - done;
+
+            if start == 0 && remaining >= 512 {
+                // Whole blocks
+                let length = remaining - (remaining % 512);
+                self.sdmmc
+                    .read_blocks(address, &mut bytes[done..done + length])?;
+                done += length;
+            } else {
+                // Part of a block
+                let length = cmp::min(512 - start, remaining);
+                self.sdmmc.read_block(address, &mut self.block)?;
+                bytes[done..done + length]
+                    .copy_from_slice(&self.block[start..start + length]);
+                done += length;
+            }
+        }
+        Ok(())
+    }
+
+    /// Capacity of the card, limited to the range of the `u32`
+    /// offsets
+    fn capacity(&self) -> usize {
+        let size = self.sdmmc.card().map(|card| card.size()).unwrap_or(0);
+        let size = cmp::min(size, u64::from(core::u32::MAX));
+
+        cmp::min(size, core::usize::MAX as u64) as usize
+    }
+}
+
+impl<S: SdmmcRegisters, CD: CardDetect> Storage for SdmmcStorage<S, CD> {
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.check_range(offset, bytes.len())?;
+
+        let mut done = 0;
+        while done < bytes.len() {
+            let (address, start) =
+                Self::locate(u64::from(offset) + done as u64)?;
+            let remaining = bytes.len() - done;
+
+            if start == 0 && remaining >= 512 {
+                // Whole blocks
+                let length = remaining - (remaining % 512);
+                self.sdmmc
+                    .write_blocks(address, &bytes[done..done + length])?;
+                done += length;
+            } else {
+                // Part of a block: read-modify-write
+                let length = cmp::min(512 - start, remaining);
+                self.sdmmc.read_block(address, &mut self.block)?;
+                self.block[start..start + length]
+                    .copy_from_slice(&bytes[done..done + length]);
+                self.sdmmc.write_block(address, &self.block)?;
+                done += length;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sdmmc::Hertz;
+    use crate::sim::SimCard;
+
+    const SIZE: usize = 2 * 1024 * 1024;
+
+    fn storage(card: &SimCard) -> SdmmcStorage<SimCard> {
+        let mut sdmmc = card.sdmmc();
+        sdmmc.init_card(Hertz(25_000_000)).unwrap();
+        SdmmcStorage::new(sdmmc)
+    }
+
+    #[test]
+    fn read_unaligned() {
+        let card = SimCard::new(SIZE);
+        card.fill_block(2, 0x11);
+        card.fill_block(3, 0x22);
+        card.fill_block(4, 0x33);
+        let mut storage = storage(&card);
+        assert_eq!(storage.capacity(), SIZE);
+
+        let mut bytes = [0; 700];
+        storage.read(2 * 512 + 500, &mut bytes).unwrap();
+        assert!(bytes[..12].iter().all(|&b| b == 0x11));
+        assert!(bytes[12..524].iter().all(|&b| b == 0x22));
+        assert!(bytes[524..].iter().all(|&b| b == 0x33));
+
+        let mut bytes = [0; 3];
+        storage.read(3 * 512 + 7, &mut bytes).unwrap();
+        assert_eq!(bytes, [0x22; 3]);
+    }
+
+    #[test]
+    fn write_unaligned() {
+        let card = SimCard::new(SIZE);
+        card.fill_block(5, 0xEE);
+        let mut storage = storage(&card);
+
+        // Inside one block
+        storage.write(5 * 512 + 100, &[1, 2, 3, 4]).unwrap();
+        let memory = card.memory();
+        assert_eq!(&memory[5 * 512 + 100..5 * 512 + 104], &[1, 2, 3, 4]);
+        assert!(memory[5 * 512..5 * 512 + 100].iter().all(|&b| b == 0xEE));
+        assert!(memory[5 * 512 + 104..6 * 512].iter().all(|&b| b == 0xEE));
+    }
+
+    #[test]
+    fn write_straddles_blocks() {
+        let card = SimCard::new(SIZE);
+        card.fill_block(8, 0xAA);
+        card.fill_block(10, 0xBB);
+        let mut storage = storage(&card);
+
+        // The end of block 8, all of block 9 and the start of block 10
+        let bytes = [0x5C; 600];
+        storage.write(8 * 512 + 480, &bytes).unwrap();
+        let memory = card.memory();
+        assert!(memory[8 * 512..8 * 512 + 480].iter().all(|&b| b == 0xAA));
+        assert_eq!(&memory[8 * 512 + 480..8 * 512 + 1080], &bytes[..]);
+        assert!(memory[8 * 512 + 1080..11 * 512].iter().all(|&b| b == 0xBB));
+
+        let mut read = [0; 600];
+        storage.read(8 * 512 + 480, &mut read).unwrap();
+        assert_eq!(&read[..], &bytes[..]);
+    }
+
+    #[test]
+    fn out_of_range() {
+        let card = SimCard::new(SIZE);
+        let mut storage = storage(&card);
+
+        let mut bytes = [0; 4];
+        match storage
+            .read(SIZE as u32 - 2, &mut bytes)
+            .map_err(|e| e.kind())
+        {
+            Err(ErrorKind::OutOfRange) => (),
+            r => panic!("{:?}", r),
+        }
+        match storage.write(SIZE as u32, &bytes).map_err(|e| e.kind()) {
+            Err(ErrorKind::OutOfRange) => (),
+            r => panic!("{:?}", r),
+        }
+    }
+}
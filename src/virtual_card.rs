@@ -0,0 +1,455 @@
+//! Block level virtual SD card, backed by a disk image file or a `Vec<u8>`.
+//!
+//! Has the same block API as [`Sdmmc`](crate::Sdmmc), so that code built on
+//! top of the driver can be tested on the host. Only available with the
+//! `std` feature. With the `embedded-sdmmc` feature, a `VirtualCard` can be
+//! used as a `BlockDevice` through
+//! [`VirtualBlockDevice`](crate::VirtualBlockDevice).
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::vec::Vec;
+
+use crate::registers::star;
+use crate::sd_registers::{CardState, R1Status, SDStatus, CID, CSD, OCR, SCR};
+use crate::sdmmc::{Card, CardType, Error, ErrorKind, Phase};
+
+/// A fault injected into a [`VirtualCard`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Fault {
+    /// The data CRC check fails. Returns `ErrorKind::DataCrcFail`
+    Crc,
+    /// The card does not respond. Returns `ErrorKind::Timeout`
+    Timeout,
+    /// The card is removed from the slot. Returns `ErrorKind::NoCard`, until
+    /// the card is inserted and initialised again
+    Removal,
+}
+
+/// A virtual SDHC card.
+///
+/// The capacity of the card is the size of its image, rounded down to a
+/// multiple of 512kB. An access beyond the end of the card fails with
+/// `ErrorKind::AddressError`, as if the card had reported OUT_OF_RANGE. An
+/// I/O error on the image is reported as `ErrorKind::Timeout`, as if the
+/// card had stopped responding. Errors carry the same context as those of
+/// [`Sdmmc`](crate::Sdmmc).
+///
+/// ```
+/// use stm32h7_sdmmc::VirtualCard;
+///
+/// let mut sdmmc = VirtualCard::from_vec(vec![0; 1024 * 1024]).unwrap();
+/// sdmmc.write_block(1, &[0xA5; 512]).unwrap();
+///
+/// let mut buffer = [0; 512];
+/// sdmmc.read_block(1, &mut buffer).unwrap();
+/// assert_eq!(buffer[..], [0xA5; 512][..]);
+/// ```
+#[derive(Debug)]
+pub struct VirtualCard<B> {
+    image: B,
+    blocks: u32,
+    card: Option<Card>,
+    present: bool,
+    faults: Vec<(u32, Fault)>,
+}
+
+impl VirtualCard<Cursor<Vec<u8>>> {
+    /// A card backed by `image`
+    pub fn from_vec(image: Vec<u8>) -> io::Result<Self> {
+        Self::new(Cursor::new(image))
+    }
+}
+
+impl VirtualCard<File> {
+    /// A card backed by the disk image at `path`. The image is opened for
+    /// reading and writing
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::new(OpenOptions::new().read(true).write(true).open(path)?)
+    }
+}
+
+impl<B: Read + Write + Seek> VirtualCard<B> {
+    /// A card backed by `image`. The card is inserted and initialised.
+    ///
+    /// Fails if the image is smaller than 512kB
+    pub fn new(mut image: B) -> io::Result<Self> {
+        let size = image.seek(SeekFrom::End(0))?;
+        let units = size / (512 * 1024);
+        if units == 0 || units >= 0x40_0000 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "image must be between 512kB and 2TB",
+            ));
+        }
+
+        let mut sdmmc = VirtualCard {
+            image,
+            blocks: units as u32 * 1024,
+            card: None,
+            present: true,
+            faults: Vec::new(),
+        };
+        sdmmc.card = Some(sdmmc.registers());
+        Ok(sdmmc)
+    }
+
+    /// The disk image backing this card
+    pub fn into_inner(self) -> B {
+        self.image
+    }
+
+    /// Initialise the card.
+    ///
+    /// Returns `ErrorKind::Timeout` if there is no card in the slot
+    pub fn init_card(&mut self) -> Result<(), Error> {
+        if !self.present {
+            // CMD8 and ACMD41 are not answered, nor is CMD1
+            return Err(Error::from(ErrorKind::Timeout).in_cmd(
+                1,
+                false,
+                Phase::Command,
+                star::CTIMEOUT,
+            ));
+        }
+
+        self.card = Some(self.registers());
+        Ok(())
+    }
+
+    /// Card information, as read from the card registers
+    fn registers(&self) -> Card {
+        let c_size = u128::from(self.blocks / 1024 - 1);
+        let scr = SCR(Self::scr());
+        Card {
+            card_type: CardType::SDHC,
+            version: scr.version(),
+            ocr: OCR(0xC0FF_8000),
+            rca: 0x4567,
+            cid: CID::new(Self::cid()),
+            csd: CSD(Self::csd(c_size)),
+            scr,
+            status: SDStatus::new(Self::sd_status()),
+            ..Card::default()
+        }
+    }
+
+    /// Get a reference to the initialized card
+    ///
+    /// # Errors
+    ///
+    /// Returns ErrorKind::NoCard if [`init_card`](#method.init_card)
+    /// has not previously succeeded
+    pub fn card(&self) -> Result<&Card, Error> {
+        self.card
+            .as_ref()
+            .ok_or_else(|| Error::from(ErrorKind::NoCard))
+    }
+
+    /// Remove the card from the slot
+    pub fn remove(&mut self) {
+        self.present = false;
+        self.card = None;
+    }
+
+    /// Insert the card into the slot. It must be initialised with
+    /// [`init_card`](#method.init_card) before it can be used
+    pub fn insert(&mut self) {
+        self.present = true;
+    }
+
+    /// Inject `fault` when a transfer reaches block `address`. The
+    /// transfer stops with the fault, after transferring the blocks before
+    /// `address`. Each fault is injected once
+    pub fn inject_fault(&mut self, address: u32, fault: Fault) {
+        self.faults.push((address, fault));
+    }
+
+    /// Read block from card.
+    ///
+    /// `address` is the block address.
+    pub fn read_block(
+        &mut self,
+        address: u32,
+        buffer: &mut [u8; 512],
+    ) -> Result<(), Error> {
+        self.read_blocks(address, buffer)
+    }
+
+    /// Read multiple blocks from card.
+    ///
+    /// `address` is the block address.
+    pub fn read_blocks(
+        &mut self,
+        address: u32,
+        buffer: &mut [u8],
+    ) -> Result<(), Error> {
+        assert!(buffer.len() % 512 == 0);
+        let cmd = if buffer.len() == 512 { 17 } else { 18 };
+
+        for (i, block) in buffer.chunks_mut(512).enumerate() {
+            self.seek(cmd, address, i)?;
+            if self.image.read_exact(block).is_err() {
+                return Err(Self::data_timeout(cmd, Phase::Data, address));
+            }
+        }
+        Ok(())
+    }
+
+    /// Write block to card.
+    ///
+    /// `address` is the block address.
+    pub fn write_block(
+        &mut self,
+        address: u32,
+        buffer: &[u8; 512],
+    ) -> Result<(), Error> {
+        self.write_blocks(address, buffer)
+    }
+
+    /// Write multiple blocks to card.
+    ///
+    /// `address` is the block address.
+    pub fn write_blocks(
+        &mut self,
+        address: u32,
+        buffer: &[u8],
+    ) -> Result<(), Error> {
+        assert!(buffer.len() % 512 == 0);
+        let cmd = if buffer.len() == 512 { 24 } else { 25 };
+
+        for (i, block) in buffer.chunks(512).enumerate() {
+            self.seek(cmd, address, i)?;
+            if self.image.write_all(block).is_err() {
+                return Err(Self::data_timeout(cmd, Phase::Data, address));
+            }
+        }
+        self.image
+            .flush()
+            .map_err(|_| Self::data_timeout(cmd, Phase::Busy, address))
+    }
+
+    /// A timeout in `phase` of command `cmd`, in a transfer at block
+    /// `address`
+    fn data_timeout(cmd: u8, phase: Phase, address: u32) -> Error {
+        Error::from(ErrorKind::Timeout)
+            .in_cmd(cmd, false, phase, star::DTIMEOUT)
+            .at(address)
+    }
+
+    /// Seek to the `i`th block of a transfer by command `cmd` that
+    /// starts at block `address`, injecting any fault at that block
+    fn seek(&mut self, cmd: u8, address: u32, i: usize) -> Result<(), Error> {
+        let _ = self.card()?;
+
+        let block = u64::from(address) + i as u64;
+        if block >= u64::from(self.blocks) {
+            // As reported by a card in the Transfer State
+            let r1 = R1Status(
+                R1Status::OUT_OF_RANGE
+                    | (CardState::Transfer as u32) << 9
+                    | 1 << 8, // READY_FOR_DATA
+            );
+            return Err(Error::from(ErrorKind::AddressError(r1))
+                .in_cmd(cmd, false, Phase::Command, star::CMDREND)
+                .at(address));
+        }
+
+        let fault = self
+            .faults
+            .iter()
+            .position(|&(a, _)| u64::from(a) == block)
+            .map(|i| self.faults.remove(i).1);
+        match fault {
+            Some(Fault::Crc) => {
+                return Err(Error::from(ErrorKind::DataCrcFail)
+                    .in_cmd(cmd, false, Phase::Data, star::DCRCFAIL)
+                    .at(address));
+            }
+            Some(Fault::Timeout) => {
+                return Err(Self::data_timeout(cmd, Phase::Data, address));
+            }
+            Some(Fault::Removal) => {
+                // The card stops responding part way through the data
+                self.remove();
+                return Err(Error::from(ErrorKind::NoCard)
+                    .in_cmd(cmd, false, Phase::Data, star::DTIMEOUT)
+                    .at(address));
+            }
+            None => (),
+        }
+
+        match self.image.seek(SeekFrom::Start(block * 512)) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(Self::data_timeout(cmd, Phase::Data, address)),
+        }
+    }
+
+    /// CID. See PLSS v7_10 Section 5.2
+    fn cid() -> u128 {
+        u128::from_be_bytes([
+            0x03, b'S', b'D', b'V', b'C', b'A', b'R', b'D', 0x10, 0x12, 0x34,
+            0x56, 0x78, 0x01, 0x46, 0x01,
+        ])
+    }
+
+    /// CSD Version 2.0. See PLSS v7_10 Section 5.3.3
+    fn csd(c_size: u128) -> u128 {
+        1 << 126 // CSD_STRUCTURE
+            | 0x0E << 112 // TAAC
+            | 0x32 << 96 // TRAN_SPEED
+            | 0x5B5 << 84 // CCC
+            | 9 << 80 // READ_BL_LEN
+            | c_size << 48
+            | 1 << 46 // ERASE_BLK_EN
+            | 0x7F << 39 // SECTOR_SIZE
+            | 2 << 26 // R2W_FACTOR
+            | 9 << 22 // WRITE_BL_LEN
+            | 1
+    }
+
+    /// SCR of a Version 3.00 card with 1-bit and 4-bit buses. See PLSS
+    /// v7_10 Section 5.6
+    fn scr() -> u64 {
+        2 << 56 // SD_SPEC
+            | 0b0101 << 48 // SD_BUS_WIDTHS
+            | 1 << 47 // SD_SPEC3
+    }
+
+    /// SD Status of a Class 10 card with a 4MB AU, as read from the FIFO.
+    /// See PLSS v7_10 Table 4-44
+    fn sd_status() -> [u32; 16] {
+        let mut bytes = [0; 64];
+        bytes[0] = 0b10 << 6; // DAT_BUS_WIDTH: 4-bit
+        bytes[8] = 4; // SPEED_CLASS: Class 10
+        bytes[10] = 9 << 4; // AU_SIZE: 4MB
+        bytes[12] = 1; // ERASE_SIZE: 1 AU
+        bytes[13] = 1 << 2 | 1; // ERASE_TIMEOUT: 1s, ERASE_OFFSET: 1s
+        bytes[24] = 1 << 1; // DISCARD_SUPPORT
+
+        let mut status = [0; 16];
+        for (word, b) in status.iter_mut().zip(bytes.chunks(4)) {
+            *word = u32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+        }
+        status
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sd_registers::CardVersion;
+
+    const SIZE: usize = 2 * 1024 * 1024;
+
+    fn card() -> VirtualCard<Cursor<Vec<u8>>> {
+        VirtualCard::from_vec(vec![0; SIZE]).unwrap()
+    }
+
+    #[test]
+    fn card_registers() {
+        let sdmmc = card();
+        let card = sdmmc.card().unwrap();
+
+        assert_eq!(card.size(), SIZE as u64);
+        assert_eq!(card.version, CardVersion::V3);
+        assert!(card.ocr.ccs());
+        assert_eq!(card.cid.product_name(), "VCARD");
+        assert!(card.scr.bus_width_four());
+        assert_eq!(card.status.speed_class(), 4);
+        assert_eq!(card.status.allocation_unit_blocks(), 8192);
+        assert_eq!(card.status.erase_timeout_ms(8192), Some(2000));
+    }
+
+    #[test]
+    fn image_too_small() {
+        assert!(VirtualCard::from_vec(vec![0; 1024]).is_err());
+    }
+
+    #[test]
+    fn read_write_blocks() {
+        let mut sdmmc = card();
+
+        let mut buffer = [0; 3 * 512];
+        for (i, b) in buffer.iter_mut().enumerate() {
+            *b = (i / 512) as u8 + 1;
+        }
+        sdmmc.write_blocks(5, &buffer).unwrap();
+
+        let mut block = [0; 512];
+        sdmmc.read_block(6, &mut block).unwrap();
+        assert!(block.iter().all(|&b| b == 2));
+
+        let image = sdmmc.into_inner().into_inner();
+        assert_eq!(&image[5 * 512..8 * 512], &buffer[..]);
+        assert!(image[8 * 512..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn out_of_range() {
+        let mut sdmmc = card();
+        let mut buffer = [0; 1024];
+
+        let last = (SIZE / 512) as u32 - 1;
+        match sdmmc.read_blocks(last, &mut buffer).map_err(|e| e.kind()) {
+            Err(ErrorKind::AddressError(r1)) => assert!(r1.out_of_range()),
+            r => panic!("{:?}", r),
+        }
+    }
+
+    #[test]
+    fn crc_fault() {
+        let mut sdmmc = card();
+        sdmmc.inject_fault(2, Fault::Crc);
+
+        let buffer = [0xFF; 4 * 512];
+        let err = sdmmc.write_blocks(0, &buffer).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::DataCrcFail);
+        assert_eq!(err.cmd(), Some(25));
+        assert_eq!(err.phase(), Some(Phase::Data));
+        assert_eq!(err.address(), Some(0));
+        assert_eq!(err.star(), star::DCRCFAIL);
+
+        // Blocks before the fault were written
+        let mut read = [0; 4 * 512];
+        sdmmc.read_blocks(0, &mut read).unwrap();
+        assert!(read[..2 * 512].iter().all(|&b| b == 0xFF));
+        assert!(read[2 * 512..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn timeout_fault() {
+        let mut sdmmc = card();
+        sdmmc.inject_fault(0, Fault::Timeout);
+
+        let mut buffer = [0; 512];
+        match sdmmc.read_block(0, &mut buffer).map_err(|e| e.kind()) {
+            Err(ErrorKind::Timeout) => (),
+            r => panic!("{:?}", r),
+        }
+        sdmmc.read_block(0, &mut buffer).unwrap();
+    }
+
+    #[test]
+    fn removal_fault() {
+        let mut sdmmc = card();
+        sdmmc.inject_fault(1, Fault::Removal);
+
+        let mut buffer = [0; 2 * 512];
+        let err = sdmmc.read_blocks(0, &mut buffer).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NoCard);
+        assert_eq!(err.cmd(), Some(18));
+        assert_eq!(err.phase(), Some(Phase::Data));
+        assert_eq!(err.address(), Some(0));
+        assert!(sdmmc.card().is_err());
+        match sdmmc.init_card().map_err(|e| e.kind()) {
+            Err(ErrorKind::Timeout) => (),
+            r => panic!("{:?}", r),
+        }
+
+        sdmmc.insert();
+        sdmmc.init_card().unwrap();
+        sdmmc.read_blocks(0, &mut buffer).unwrap();
+    }
+}